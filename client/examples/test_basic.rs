@@ -1,10 +1,10 @@
 use std::error::Error;
 use std::sync::Arc;
+use std::thread::sleep;
 use std::time::Duration;
 
-use last_mile_client::client::LastMileClient;
+use last_mile_client::blocking::LastMileClient;
 use tokio::runtime::Builder;
-use tokio::time::sleep;
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 pub fn main() -> Result<(), Box<dyn Error>> {
@@ -21,17 +21,13 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     // Test 1: Connect to private listener (publisher)
     println!("1. Connecting publisher to private listener (localhost:8081)...");
     let publisher_url = String::from("ws://localhost:8081/");
-    let publisher_rt = Arc::clone(&runtime);
-    let publisher =
-        runtime.block_on(async move { LastMileClient::connect(publisher_rt, publisher_url) })?;
+    let publisher = LastMileClient::connect(Arc::clone(&runtime), publisher_url)?;
     println!("✓ Publisher connected\n");
 
     // Test 2: Connect to public listener (subscriber)
     println!("2. Connecting subscriber to public listener (localhost:8080)...");
     let subscriber_url = String::from("ws://localhost:8080/");
-    let subscriber_rt = Arc::clone(&runtime);
-    let subscriber =
-        runtime.block_on(async move { LastMileClient::connect(subscriber_rt, subscriber_url) })?;
+    let subscriber = LastMileClient::connect(Arc::clone(&runtime), subscriber_url)?;
     println!("✓ Subscriber connected\n");
 
     // Test 3: Create channel on private listener
@@ -47,17 +43,15 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 
     // Test 5: Publish messages
     println!("5. Publishing 5 messages...");
-    runtime.block_on(async {
-        for i in 1..=5 {
-            sleep(Duration::from_millis(500)).await;
-            let msg = format!("Test message #{}", i);
-            println!("  → Publishing: {}", msg);
-            publisher.notify_channel(&channel_id, msg).ok();
-        }
+    for i in 1..=5 {
+        sleep(Duration::from_millis(500));
+        let msg = format!("Test message #{}", i);
+        println!("  → Publishing: {}", msg);
+        publisher.notify_channel(&channel_id, msg)?;
+    }
 
-        // Wait a bit for messages to be received
-        sleep(Duration::from_secs(1)).await;
-    });
+    // Wait a bit for messages to be received
+    sleep(Duration::from_secs(1));
 
     println!("\n✓ Test completed successfully!");
     println!("\nNote: Messages are received asynchronously in the background.");
@@ -1,11 +1,14 @@
 use std::error::Error;
-use std::sync::Arc;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use clap::{Parser, Subcommand};
-use last_mile_client::client::LastMileClient;
-use tokio::runtime::Builder;
-use tokio::time::sleep;
+use common::error::AppError;
+use common::message::{ChannelMessage, ClientCommand};
+use last_mile_client::client::ChannelMessages;
+use last_mile_client::client::{LastMileClient, LastMileClientBuilder};
+use last_mile_client::tls::TlsVerification;
+use tokio::time::{sleep, timeout};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[derive(Parser)]
@@ -16,12 +19,32 @@ struct Cli {
     #[arg(short, long, default_value = "ws://localhost:8080")]
     url: String,
 
+    /// Authentication token for listeners configured with `auth_tokens`
+    #[arg(short, long)]
+    token: Option<String>,
+
+    /// PEM CA bundle trusted instead of the system roots, for wss:// URLs
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate, for listeners with mutual TLS
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM PKCS#8 private key of --client-cert
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Accept any server certificate, for test environments only
+    #[arg(long)]
+    insecure: bool,
+
     /// Subcommand to execute
     #[command(subcommand)]
     command: Commands,
 }
 
-#[derive(Subcommand)]
+#[derive(Subcommand, Clone)]
 enum Commands {
     /// Subscribe to a channel and listen for messages
     Subscribe {
@@ -36,6 +59,11 @@ enum Commands {
         /// Channel ID to create
         channel: String,
     },
+    /// Delete a channel
+    DeleteChannel {
+        /// Channel ID to delete
+        channel: String,
+    },
     /// Publish a message to a channel
     Publish {
         /// Channel ID to publish to
@@ -57,7 +85,51 @@ enum Commands {
     },
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+async fn connect(cli: &Cli) -> Result<LastMileClient, AppError> {
+    let mut builder = LastMileClientBuilder::new(cli.url.clone());
+    if let Some(ref token) = cli.token {
+        builder = builder.token(token.clone());
+    }
+    if let Some(ref ca_cert) = cli.ca_cert {
+        builder = builder
+            .root_certificate(std::fs::read(ca_cert)?)
+            .only_custom_roots(true);
+    }
+    if let (Some(cert), Some(key)) = (&cli.client_cert, &cli.client_key) {
+        builder = builder.client_identity(std::fs::read(cert)?, std::fs::read(key)?);
+    }
+    if cli.insecure {
+        builder = builder.verification(TlsVerification::Disabled);
+    }
+    builder.connect().await
+}
+
+/// Print the received messages until the connection closes.
+async fn print_messages(mut messages: ChannelMessages) {
+    while let Some(command) = messages.recv().await {
+        match command {
+            ClientCommand::ChannelMessage(channel, message, seq) => {
+                println!("  ← [{}#{}] {}", channel, seq, display(&message));
+            }
+            ClientCommand::ChannelSnapshot(channel, message, seq) => {
+                println!("  ← [{}#{} snapshot] {}", channel, seq, display(&message));
+            }
+            ClientCommand::ChannelClosed(channel) => println!("  ✗ Channel '{}' closed", channel),
+            _ => {}
+        }
+    }
+}
+
+fn display(message: &ChannelMessage) -> String {
+    match message {
+        ChannelMessage::Text(text) => text.clone(),
+        ChannelMessage::Json(value) => value.to_string(),
+        ChannelMessage::Binary(data) => format!("<{} bytes>", data.len()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize tracing
     tracing_subscriber::registry()
         .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
@@ -65,51 +137,45 @@ fn main() -> Result<(), Box<dyn Error>> {
         .init();
 
     let cli = Cli::parse();
-    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build()?);
 
-    match cli.command {
+    match cli.command.clone() {
         Commands::Subscribe { channel, duration } => {
-            let url = cli.url.clone();
-            let client_rt = Arc::clone(&runtime);
-
-            runtime.block_on(async move {
-                println!("Connecting to {}...", url);
-                let client = LastMileClient::connect(client_rt, url)?;
-                println!("✓ Connected");
-
-                println!("Subscribing to channel '{}'...", channel);
-                client.subscribe(&channel)?;
-                println!("✓ Subscribed. Listening for messages...");
-
-                if duration > 0 {
-                    println!("Will listen for {} seconds", duration);
-                    sleep(Duration::from_secs(duration)).await;
-                } else {
-                    println!("Listening forever (Ctrl+C to stop)...");
-                    loop {
-                        sleep(Duration::from_secs(3600)).await;
-                    }
-                }
-
-                Ok::<(), Box<dyn Error>>(())
-            })?;
+            println!("Connecting to {}...", cli.url);
+            let client = connect(&cli).await?;
+            println!("✓ Connected");
+
+            let messages = print_messages(client.messages());
+            println!("Subscribing to channel '{}'...", channel);
+            client.subscribe(&channel).await?;
+            println!("✓ Subscribed. Listening for messages...");
+
+            if duration > 0 {
+                println!("Will listen for {} seconds", duration);
+                let _ = timeout(Duration::from_secs(duration), messages).await;
+            } else {
+                println!("Listening until the connection closes (Ctrl+C to stop)...");
+                messages.await;
+            }
         }
 
         Commands::CreateChannel { channel } => {
-            let url = cli.url.clone();
-            let client_rt = Arc::clone(&runtime);
+            println!("Connecting to {}...", cli.url);
+            let client = connect(&cli).await?;
+            println!("✓ Connected");
 
-            runtime.block_on(async move {
-                println!("Connecting to {}...", url);
-                let client = LastMileClient::connect(client_rt, url)?;
-                println!("✓ Connected");
+            println!("Creating channel '{}'...", channel);
+            client.create_channel(&channel).await?;
+            println!("✓ Channel created");
+        }
 
-                println!("Creating channel '{}'...", channel);
-                client.create_channel(&channel)?;
-                println!("✓ Channel created");
+        Commands::DeleteChannel { channel } => {
+            println!("Connecting to {}...", cli.url);
+            let client = connect(&cli).await?;
+            println!("✓ Connected");
 
-                Ok::<(), Box<dyn Error>>(())
-            })?;
+            println!("Deleting channel '{}'...", channel);
+            client.delete_channel(&channel).await?;
+            println!("✓ Channel deleted");
         }
 
         Commands::Publish {
@@ -118,70 +184,56 @@ fn main() -> Result<(), Box<dyn Error>> {
             count,
             interval,
         } => {
-            let url = cli.url.clone();
-            let client_rt = Arc::clone(&runtime);
-
-            runtime.block_on(async move {
-                println!("Connecting to {}...", url);
-                let client = LastMileClient::connect(client_rt, url)?;
-                println!("✓ Connected");
-
-                println!("Publishing {} message(s) to '{}'...", count, channel);
-                for i in 1..=count {
-                    let msg = if count > 1 {
-                        format!("{} ({})", message, i)
-                    } else {
-                        message.clone()
-                    };
-
-                    client.notify_channel(&channel, msg.clone())?;
-                    println!("  → Published: {}", msg);
-
-                    if i < count {
-                        sleep(Duration::from_millis(interval)).await;
-                    }
-                }
+            println!("Connecting to {}...", cli.url);
+            let client = connect(&cli).await?;
+            println!("✓ Connected");
+
+            println!("Publishing {} message(s) to '{}'...", count, channel);
+            for i in 1..=count {
+                let msg = if count > 1 {
+                    format!("{} ({})", message, i)
+                } else {
+                    message.clone()
+                };
 
-                println!("✓ Done");
-                sleep(Duration::from_millis(500)).await; // Allow messages to flush
+                client.notify_channel(&channel, msg.clone()).await?;
+                println!("  → Published: {}", msg);
 
-                Ok::<(), Box<dyn Error>>(())
-            })?;
+                if i < count {
+                    sleep(Duration::from_millis(interval)).await;
+                }
+            }
+
+            println!("✓ Done");
         }
 
         Commands::Test { channel } => {
             println!("Running test scenario with channel '{}'...\n", channel);
 
-            let url = cli.url.clone();
-            let client_rt = Arc::clone(&runtime);
+            println!("1. Connecting to {}...", cli.url);
+            let client = connect(&cli).await?;
+            println!("✓ Connected\n");
 
-            runtime.block_on(async move {
-                println!("1. Connecting to {}...", url);
-                let client = LastMileClient::connect(client_rt, url)?;
-                println!("✓ Connected\n");
+            println!("2. Creating channel '{}'...", channel);
+            client.create_channel(&channel).await?;
+            println!("✓ Channel created\n");
 
-                println!("2. Creating channel '{}'...", channel);
-                client.create_channel(&channel)?;
-                println!("✓ Channel created\n");
+            println!("3. Subscribing to channel...");
+            client.subscribe(&channel).await?;
+            println!("✓ Subscribed\n");
 
-                println!("3. Subscribing to channel...");
-                client.subscribe(&channel)?;
-                println!("✓ Subscribed\n");
-
-                println!("4. Publishing 3 test messages...");
-                for i in 1..=3 {
-                    let msg = format!("Test message #{}", i);
-                    client.notify_channel(&channel, msg.clone())?;
-                    println!("  → Published: {}", msg);
-                    sleep(Duration::from_millis(500)).await;
-                }
+            println!("4. Publishing 3 test messages...");
+            for i in 1..=3 {
+                let msg = format!("Test message #{}", i);
+                client.notify_channel(&channel, msg.clone()).await?;
+                println!("  → Published: {}", msg);
+                sleep(Duration::from_millis(500)).await;
+            }
 
-                println!("\n✓ Test completed successfully!");
-                println!("Note: Messages are received asynchronously in the background.\n");
+            println!("\n✓ Test completed successfully!");
+            println!("Note: Messages are received asynchronously in the background.\n");
 
-                sleep(Duration::from_secs(1)).await;
-                Ok::<(), Box<dyn Error>>(())
-            })?;
+            sleep(Duration::from_secs(1)).await;
         }
     }
 
@@ -0,0 +1,151 @@
+//! A client for sync callers, waiting for the server's reply to each command.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use common::error::AppError;
+use common::message::{ChannelId, ChannelOptions, SubscribeOptions};
+use serde_json::Value;
+use tokio::runtime::Runtime;
+
+use crate::client::{self, ChannelMessages, LastMileClientBuilder};
+
+/// A `client::LastMileClient` whose methods block until the server replied to the command.
+///
+/// The connection is served by `runtime`, which must be multi-threaded to keep receiving
+/// between calls. The methods panic when called from async code, use
+/// `client::LastMileClient` there.
+pub struct LastMileClient {
+    runtime: Arc<Runtime>,
+    client: client::LastMileClient,
+}
+
+impl LastMileClient {
+    /// Connect to a TSLM server at the given URL.
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime` - Tokio runtime serving the connection
+    /// * `url` - WebSocket URL (e.g., "ws://localhost:8080")
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use last_mile_client::blocking::LastMileClient;
+    /// use std::sync::Arc;
+    /// use tokio::runtime::Builder;
+    ///
+    /// let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
+    /// let client = LastMileClient::connect(runtime, "ws://localhost:8080".to_string()).unwrap();
+    /// client.subscribe(&"prices".to_string()).unwrap();
+    /// ```
+    pub fn connect(runtime: Arc<Runtime>, url: String) -> Result<Self, AppError> {
+        LastMileClientBuilder::new(url).connect_blocking(runtime)
+    }
+
+    /// Connect to a TSLM server at the given URL, offering `token` as the
+    /// `Sec-WebSocket-Protocol` so listeners with `auth_tokens` accept the connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime` - Tokio runtime serving the connection
+    /// * `url` - WebSocket URL (e.g., "ws://localhost:8080")
+    /// * `token` - One of the listener's `auth_tokens`
+    pub fn connect_with_token(
+        runtime: Arc<Runtime>,
+        url: String,
+        token: String,
+    ) -> Result<Self, AppError> {
+        LastMileClientBuilder::new(url)
+            .token(token)
+            .connect_blocking(runtime)
+    }
+
+    pub(crate) fn open(
+        runtime: Arc<Runtime>,
+        builder: LastMileClientBuilder,
+    ) -> Result<Self, AppError> {
+        let client = runtime.block_on(builder.connect())?;
+        Ok(LastMileClient { runtime, client })
+    }
+
+    /// The async client, e.g. to send commands without waiting for their replies.
+    pub fn as_async(&self) -> &client::LastMileClient {
+        &self.client
+    }
+
+    /// The channel messages received from now on, read with `ChannelMessages::blocking_recv`,
+    /// see `client::LastMileClient::messages`.
+    pub fn messages(&self) -> ChannelMessages {
+        self.client.messages()
+    }
+
+    /// Subscribe to a channel to receive messages.
+    pub fn subscribe(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        self.runtime.block_on(self.client.subscribe(channel_id))
+    }
+
+    /// Subscribe to a channel with options, see `client::LastMileClient::subscribe_with_options`.
+    pub fn subscribe_with_options(
+        &self,
+        channel_id: &ChannelId,
+        options: SubscribeOptions,
+    ) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.subscribe_with_options(channel_id, options))
+    }
+
+    /// Unsubscribe from a channel to stop receiving its messages.
+    pub fn unsubscribe(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        self.runtime.block_on(self.client.unsubscribe(channel_id))
+    }
+
+    /// Create a new channel.
+    pub fn create_channel(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.create_channel(channel_id))
+    }
+
+    /// Create a new channel with options, see
+    /// `client::LastMileClient::create_channel_with_options`.
+    pub fn create_channel_with_options(
+        &self,
+        channel_id: &ChannelId,
+        options: ChannelOptions,
+    ) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.create_channel_with_options(channel_id, options))
+    }
+
+    /// Delete a channel. Its subscribers receive a `ChannelClosed` notice.
+    pub fn delete_channel(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.delete_channel(channel_id))
+    }
+
+    /// Publish a text message to a channel.
+    pub fn notify_channel(&self, channel_id: &ChannelId, text: String) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.notify_channel(channel_id, text))
+    }
+
+    /// Publish a JSON message to a channel.
+    pub fn notify_channel_json(
+        &self,
+        channel_id: &ChannelId,
+        value: Value,
+    ) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.notify_channel_json(channel_id, value))
+    }
+
+    /// Publish a binary message to a channel.
+    pub fn notify_channel_binary(
+        &self,
+        channel_id: &ChannelId,
+        data: Bytes,
+    ) -> Result<(), AppError> {
+        self.runtime
+            .block_on(self.client.notify_channel_binary(channel_id, data))
+    }
+}
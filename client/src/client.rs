@@ -1,54 +1,121 @@
 //! TSLM client implementation.
 
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
-use crate::websocket::{Websocket, WebsocketEventHandler};
+use crate::blocking;
+use crate::tls::{TlsOptions, TlsVerification};
+use crate::websocket::{Websocket, WebsocketEventHandler, connection_closed};
+use bytes::Bytes;
 use common::error::AppError;
-use common::message::{ChannelId, ChannelMessage, TerminalStreamCommand};
+use common::message::{
+    ChannelId, ChannelMessage, ChannelOptions, ClientCommand, CommandEnvelope, CommandId, Frame,
+    Protocol, SubscribeOptions, TerminalStreamCommand,
+};
+use futures_util::Stream;
 use serde_json::Value;
 use tokio::runtime::Runtime;
-use tracing::{debug, error};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, warn};
 use tungstenite::Message;
 
+/// The encoding of the client's connections.
+const PROTOCOL: Protocol = Protocol::V2Json;
+
 /// A client for connecting to and interacting with TSLM servers.
 ///
 /// Provides methods for creating channels, subscribing to channels,
-/// and publishing messages.
+/// and publishing messages. Each method sends its command right away, in call order, and
+/// returns a `CommandReply` resolving when the server answers it. Sync callers use
+/// `blocking::LastMileClient` instead.
+///
+/// The client speaks the v2 encoding (`tslm.v2.json`), so the messages received through
+/// `messages` carry their sequence numbers.
 pub struct LastMileClient {
-    #[allow(dead_code)]
     handler: Arc<LastMileClientHandler>,
     ws: Websocket<LastMileClientHandler>,
+    next_command_id: AtomicU64,
 }
 
 impl LastMileClient {
-    /// Connect to a TSLM server at the given URL.
+    /// Connect to a TSLM server at the given URL, on the current Tokio runtime.
     ///
     /// # Arguments
     ///
-    /// * `runtime` - Tokio runtime for async operations
     /// * `url` - WebSocket URL (e.g., "ws://localhost:8080")
     ///
     /// # Example
     ///
     /// ```no_run
     /// use last_mile_client::client::LastMileClient;
-    /// use std::sync::Arc;
-    /// use tokio::runtime::Builder;
     ///
-    /// let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
-    /// let client = LastMileClient::connect(runtime, "ws://localhost:8080".to_string()).unwrap();
+    /// # async fn run() -> Result<(), common::error::AppError> {
+    /// let client = LastMileClient::connect("ws://localhost:8080".to_string()).await?;
+    /// client.subscribe(&"prices".to_string()).await?;
+    /// # Ok(())
+    /// # }
     /// ```
-    pub fn connect(runtime: Arc<Runtime>, url: String) -> Result<Self, AppError> {
-        let handler = Arc::new(LastMileClientHandler {});
+    pub async fn connect(url: String) -> Result<Self, AppError> {
+        LastMileClientBuilder::new(url).connect().await
+    }
+
+    /// Connect to a TSLM server whose listener requires an authentication token.
+    ///
+    /// The token is offered through the `Sec-WebSocket-Protocol` header.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - WebSocket URL (e.g., "ws://localhost:8080")
+    /// * `token` - One of the listener's `auth_tokens`
+    pub async fn connect_with_token(url: String, token: String) -> Result<Self, AppError> {
+        LastMileClientBuilder::new(url).token(token).connect().await
+    }
+
+    async fn open(builder: LastMileClientBuilder) -> Result<Self, AppError> {
+        let handler = Arc::new(LastMileClientHandler::default());
 
-        let ws = Websocket::open(&runtime, url, Arc::clone(&handler)).map_err(AppError::from)?;
+        let connector = builder.tls.connector()?;
+        let protocols = builder
+            .token
+            .into_iter()
+            .chain(PROTOCOL.subprotocol().map(String::from))
+            .collect();
+        let ws = Websocket::open(builder.url, protocols, connector, Arc::clone(&handler))
+            .await
+            .map_err(AppError::from)?;
 
-        Ok(LastMileClient { handler, ws })
+        Ok(LastMileClient {
+            handler,
+            ws,
+            next_command_id: AtomicU64::new(1),
+        })
     }
-    fn send(&self, command: TerminalStreamCommand) -> Result<(), AppError> {
-        let message = serde_json::to_string(&command).map_err(AppError::from)?;
-        self.ws.send(Message::Text(message.into()));
-        Ok(())
+
+    /// Send the command with a fresh correlation id, the server's reply carries the same id.
+    fn send(&self, command: TerminalStreamCommand) -> CommandReply {
+        let id: CommandId = self.next_command_id.fetch_add(1, Ordering::Relaxed);
+        // Expected before sending, the reply may arrive before this returns.
+        let receiver = self.handler.expect_reply(id);
+        let envelope = CommandEnvelope::new(Some(id), command);
+        let sent = PROTOCOL
+            .encode_command(envelope)
+            .and_then(|frame| self.ws.send(to_message(frame)));
+        if let Err(err) = sent {
+            self.handler.reply(Some(id), Err(err));
+        }
+        CommandReply { receiver }
+    }
+
+    /// The channel messages, snapshots and `ChannelClosed` notices received from now on,
+    /// queued until read. Calling it again hands the following ones to the new stream, and
+    /// the stream ends when the connection closes. Until it is called they are only logged.
+    pub fn messages(&self) -> ChannelMessages {
+        ChannelMessages {
+            receiver: self.handler.forward_messages(),
+        }
     }
 
     /// Subscribe to a channel to receive messages.
@@ -56,28 +123,79 @@ impl LastMileClient {
     /// # Arguments
     ///
     /// * `channel_id` - The ID of the channel to subscribe to
-    pub fn subscribe(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+    pub fn subscribe(&self, channel_id: &ChannelId) -> CommandReply {
         let command = TerminalStreamCommand::Subscribe(channel_id.clone());
         self.send(command)
     }
 
+    /// Subscribe to a channel with options, e.g. resuming from the sequence number after
+    /// the last message received.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_id` - The ID of the channel to subscribe to
+    /// * `options` - The subscription options
+    pub fn subscribe_with_options(
+        &self,
+        channel_id: &ChannelId,
+        options: SubscribeOptions,
+    ) -> CommandReply {
+        let command = TerminalStreamCommand::SubscribeWithOptions(channel_id.clone(), options);
+        self.send(command)
+    }
+
+    /// Unsubscribe from a channel to stop receiving its messages.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_id` - The ID of the channel to unsubscribe from
+    pub fn unsubscribe(&self, channel_id: &ChannelId) -> CommandReply {
+        let command = TerminalStreamCommand::Unsubscribe(channel_id.clone());
+        self.send(command)
+    }
+
     /// Create a new channel.
     ///
     /// # Arguments
     ///
     /// * `channel_id` - The ID for the new channel
-    pub fn create_channel(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+    pub fn create_channel(&self, channel_id: &ChannelId) -> CommandReply {
         let command = TerminalStreamCommand::CreateChannel(channel_id.clone());
         self.send(command)
     }
 
+    /// Create a new channel with options, e.g. deleting it when this client disconnects.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_id` - The ID for the new channel
+    /// * `options` - The channel options
+    pub fn create_channel_with_options(
+        &self,
+        channel_id: &ChannelId,
+        options: ChannelOptions,
+    ) -> CommandReply {
+        let command = TerminalStreamCommand::CreateChannelWithOptions(channel_id.clone(), options);
+        self.send(command)
+    }
+
+    /// Delete a channel. Its subscribers receive a `ChannelClosed` notice.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_id` - The ID of the channel to delete
+    pub fn delete_channel(&self, channel_id: &ChannelId) -> CommandReply {
+        let command = TerminalStreamCommand::DeleteChannel(channel_id.clone());
+        self.send(command)
+    }
+
     /// Publish a text message to a channel.
     ///
     /// # Arguments
     ///
     /// * `channel_id` - The ID of the channel to publish to
     /// * `text` - The text message to publish
-    pub fn notify_channel(&self, channel_id: &ChannelId, text: String) -> Result<(), AppError> {
+    pub fn notify_channel(&self, channel_id: &ChannelId, text: String) -> CommandReply {
         let command =
             TerminalStreamCommand::NotifyChannel(channel_id.clone(), ChannelMessage::Text(text));
         self.send(command)
@@ -89,17 +207,233 @@ impl LastMileClient {
     ///
     /// * `channel_id` - The ID of the channel to publish to
     /// * `value` - The JSON value to publish
-    pub fn notify_channel_json(
-        &self,
-        channel_id: &ChannelId,
-        value: Value,
-    ) -> Result<(), AppError> {
+    pub fn notify_channel_json(&self, channel_id: &ChannelId, value: Value) -> CommandReply {
         let command =
             TerminalStreamCommand::NotifyChannel(channel_id.clone(), ChannelMessage::Json(value));
         self.send(command)
     }
+
+    /// Publish a binary message to a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel_id` - The ID of the channel to publish to
+    /// * `data` - The binary payload to publish
+    pub fn notify_channel_binary(&self, channel_id: &ChannelId, data: Bytes) -> CommandReply {
+        let command =
+            TerminalStreamCommand::NotifyChannel(channel_id.clone(), ChannelMessage::Binary(data));
+        self.send(command)
+    }
+}
+
+/// Configures a `LastMileClient` before connecting, e.g. with the TLS options of `wss://`
+/// URLs.
+///
+/// # Example
+///
+/// ```no_run
+/// use last_mile_client::client::LastMileClientBuilder;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let client = LastMileClientBuilder::new("wss://gateway.internal:8444")
+///     .root_certificate(std::fs::read("/etc/tslm/tls/ca.pem")?)
+///     .only_custom_roots(true)
+///     .client_identity(
+///         std::fs::read("/etc/tslm/tls/publisher.pem")?,
+///         std::fs::read("/etc/tslm/tls/publisher.key")?,
+///     )
+///     .connect()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct LastMileClientBuilder {
+    url: String,
+    token: Option<String>,
+    tls: TlsOptions,
+}
+
+impl LastMileClientBuilder {
+    /// A builder for the WebSocket URL (e.g., "wss://localhost:8443").
+    pub fn new(url: impl Into<String>) -> Self {
+        LastMileClientBuilder {
+            url: url.into(),
+            token: None,
+            tls: TlsOptions::default(),
+        }
+    }
+
+    /// Offer one of the listener's tokens, through the `Sec-WebSocket-Protocol` header.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Trust the roots of a PEM bundle, in addition to the system roots unless
+    /// `only_custom_roots` is set.
+    pub fn root_certificate(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.tls.root_certificates.push(pem.into());
+        self
+    }
+
+    /// Trust only the roots given to `root_certificate`, pinning the server's CA.
+    pub fn only_custom_roots(mut self, only_custom_roots: bool) -> Self {
+        self.tls.only_custom_roots = only_custom_roots;
+        self
+    }
+
+    /// Present a client certificate to listeners with mutual TLS: a PEM certificate chain
+    /// and its PEM PKCS#8 private key.
+    pub fn client_identity(
+        mut self,
+        cert_chain_pem: impl Into<Vec<u8>>,
+        key_pem: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.tls.identity = Some((cert_chain_pem.into(), key_pem.into()));
+        self
+    }
+
+    /// How the server certificate is verified (default: `TlsVerification::Full`).
+    pub fn verification(mut self, verification: TlsVerification) -> Self {
+        self.tls.verification = verification;
+        self
+    }
+
+    /// Connect to the server, on the current Tokio runtime. Invalid TLS options fail with
+    /// `InvalidConfig`.
+    pub async fn connect(self) -> Result<LastMileClient, AppError> {
+        LastMileClient::open(self).await
+    }
+
+    /// Connect to the server with a client for sync callers, see `blocking::LastMileClient`.
+    ///
+    /// # Arguments
+    ///
+    /// * `runtime` - Tokio runtime serving the connection
+    pub fn connect_blocking(
+        self,
+        runtime: Arc<Runtime>,
+    ) -> Result<blocking::LastMileClient, AppError> {
+        blocking::LastMileClient::open(runtime, self)
+    }
+}
+
+/// The server's reply to a command, which was sent when the method returning it was called.
+///
+/// Resolves to `Ok` once the server executed the command, to `AppError::CommandRejected`
+/// with the server's error when it did not, and to an error when the connection closed
+/// before the reply. Dropping it does not cancel the command.
+pub struct CommandReply {
+    receiver: oneshot::Receiver<Result<(), AppError>>,
+}
+
+impl Future for CommandReply {
+    type Output = Result<(), AppError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|reply| reply.unwrap_or_else(|_| Err(connection_closed())))
+    }
+}
+
+/// Channel messages received by a `LastMileClient`, see `LastMileClient::messages`.
+///
+/// Yields `ClientCommand::ChannelMessage`, `ClientCommand::ChannelSnapshot` and
+/// `ClientCommand::ChannelClosed`, and ends when the connection closes.
+pub struct ChannelMessages {
+    receiver: mpsc::UnboundedReceiver<ClientCommand>,
+}
+
+impl ChannelMessages {
+    /// The next message, `None` once the connection closed.
+    pub async fn recv(&mut self) -> Option<ClientCommand> {
+        self.receiver.recv().await
+    }
+
+    /// The next message for sync callers, see `blocking::LastMileClient`. Panics when
+    /// called from async code.
+    pub fn blocking_recv(&mut self) -> Option<ClientCommand> {
+        self.receiver.blocking_recv()
+    }
+}
+
+impl Stream for ChannelMessages {
+    type Item = ClientCommand;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Handles the events of the client's websocket, passing replies to the commands waiting
+/// for them and channel messages to `ChannelMessages`.
+#[derive(Default)]
+pub struct LastMileClientHandler {
+    state: Mutex<HandlerState>,
+}
+
+#[derive(Default)]
+struct HandlerState {
+    replies: HashMap<CommandId, oneshot::Sender<Result<(), AppError>>>,
+    messages: Option<mpsc::UnboundedSender<ClientCommand>>,
+    /// Set once the connection closed, replies expected afterwards fail right away.
+    closed: bool,
+}
+
+impl LastMileClientHandler {
+    fn expect_reply(&self, id: CommandId) -> oneshot::Receiver<Result<(), AppError>> {
+        let (sender, receiver) = oneshot::channel();
+        // Dropping the sender fails the command as if disconnected, which is the case when
+        // the connection closed or the lock is poisoned.
+        if let Ok(mut state) = self.state.lock()
+            && !state.closed
+        {
+            state.replies.insert(id, sender);
+        }
+        receiver
+    }
+
+    /// Pass the reply to the command waiting for it, returns whether there was one.
+    fn reply(&self, id: Option<CommandId>, reply: Result<(), AppError>) -> bool {
+        let sender = id.and_then(|id| self.state.lock().ok()?.replies.remove(&id));
+        match sender {
+            Some(sender) => {
+                // Nobody is waiting when the command's reply was dropped.
+                let _ = sender.send(reply);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn forward_messages(&self) -> mpsc::UnboundedReceiver<ClientCommand> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        // After the connection closed the sender is dropped, ending the stream.
+        if let Ok(mut state) = self.state.lock()
+            && !state.closed
+        {
+            state.messages = Some(sender);
+        }
+        receiver
+    }
+
+    /// Pass the message to `ChannelMessages`, returns whether there was one.
+    fn forward(&self, message: ClientCommand) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        match state.messages.as_ref().map(|sender| sender.send(message)) {
+            Some(Ok(())) => true,
+            Some(Err(_)) => {
+                // The stream was dropped.
+                state.messages = None;
+                false
+            }
+            None => false,
+        }
+    }
 }
-pub struct LastMileClientHandler {}
 
 impl WebsocketEventHandler for LastMileClientHandler {
     fn on_connect(&self) {
@@ -107,7 +441,44 @@ impl WebsocketEventHandler for LastMileClientHandler {
     }
 
     fn on_message(&self, message: Message) {
-        debug!("TSLM message: {}", message);
+        let data = match message {
+            Message::Text(ref text) => text.as_bytes(),
+            Message::Binary(ref data) => data.as_ref(),
+            _ => {
+                debug!("TSLM message: {}", message);
+                return;
+            }
+        };
+        match PROTOCOL.decode_client_command(data) {
+            Ok(ClientCommand::Error(err)) => {
+                debug!(
+                    "TSLM command {:?} failed ({:?}): {}",
+                    err.id, err.kind, err.message
+                );
+                let id = err.id;
+                if !self.reply(id, Err(AppError::CommandRejected(err))) {
+                    warn!("TSLM error without a pending command: {}", message);
+                }
+            }
+            Ok(ClientCommand::Success(success)) => {
+                debug!(
+                    "TSLM command {:?} succeeded: {} '{}'",
+                    success.id, success.command, success.channel_id
+                );
+                self.reply(success.id, Ok(()));
+            }
+            Ok(
+                command @ (ClientCommand::ChannelMessage(..)
+                | ClientCommand::ChannelSnapshot(..)
+                | ClientCommand::ChannelClosed(_)),
+            ) => {
+                if !self.forward(command) {
+                    debug!("TSLM message: {}", message);
+                }
+            }
+            Ok(ClientCommand::Text(_)) => debug!("TSLM message: {}", message),
+            Err(err) => warn!("TSLM message could not be decoded ({}): {}", err, message),
+        }
     }
 
     fn on_error(&self, error: AppError) {
@@ -116,5 +487,130 @@ impl WebsocketEventHandler for LastMileClientHandler {
 
     fn on_close(&self) {
         debug!("TSLM client closed.");
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.closed = true;
+        state.messages = None;
+        for (_, sender) in state.replies.drain() {
+            let _ = sender.send(Err(connection_closed()));
+        }
+    }
+}
+
+fn to_message(frame: Frame) -> Message {
+    match frame {
+        Frame::Text(text) => Message::Text(text.into()),
+        Frame::Binary(data) => Message::Binary(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::{CommandError, CommandSuccess, ErrorKind, V2};
+
+    fn reply_message(command: ClientCommand) -> Message {
+        to_message(PROTOCOL.encode_client_command(command).unwrap())
+    }
+
+    fn success(id: CommandId) -> Message {
+        let command = TerminalStreamCommand::Subscribe(String::from("prices"));
+        reply_message(ClientCommand::Success(CommandSuccess::new(
+            Some(id),
+            &command,
+        )))
+    }
+
+    #[tokio::test]
+    async fn test_success_resolves_reply() {
+        let handler = LastMileClientHandler::default();
+        let receiver = handler.expect_reply(1);
+
+        handler.on_message(success(1));
+        let reply = CommandReply { receiver }.await;
+        assert!(reply.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_error_rejects_command() {
+        let handler = LastMileClientHandler::default();
+        let receiver = handler.expect_reply(2);
+
+        let err = AppError::PermissionDenied(String::from("Subscribe"));
+        handler.on_message(reply_message(ClientCommand::Error(CommandError::new(
+            Some(2),
+            &err,
+        ))));
+        match (CommandReply { receiver }).await {
+            Err(AppError::CommandRejected(err)) => {
+                assert_eq!(err.id, Some(2));
+                assert_eq!(err.kind, ErrorKind::PermissionDenied);
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_reply_is_ignored() {
+        let handler = LastMileClientHandler::default();
+        let mut receiver = handler.expect_reply(3);
+
+        handler.on_message(success(4));
+        assert!(receiver.try_recv().is_err());
+
+        handler.on_message(success(3));
+        assert!(matches!(receiver.try_recv(), Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn test_close_fails_pending_and_later_commands() {
+        let handler = LastMileClientHandler::default();
+        let pending = handler.expect_reply(5);
+
+        handler.on_close();
+        assert!(matches!(
+            (CommandReply { receiver: pending }).await,
+            Err(AppError::WebSocket(_))
+        ));
+
+        // a command sent after the connection closed does not wait for a reply
+        let later = handler.expect_reply(6);
+        assert!(matches!(
+            (CommandReply { receiver: later }).await,
+            Err(AppError::WebSocket(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_channel_messages() {
+        let handler = LastMileClientHandler::default();
+        let message = |text: &str| {
+            Message::Text(
+                format!(
+                    r#"{{"v":{},"type":"ChannelMessage","channel_id":"prices","seq":7,"message":{{"type":"Text","data":"{}"}}}}"#,
+                    V2, text
+                )
+                .into(),
+            )
+        };
+
+        // only logged before anyone asks for them
+        handler.on_message(message("dropped"));
+        let mut messages = ChannelMessages {
+            receiver: handler.forward_messages(),
+        };
+        handler.on_message(message("hello"));
+        match messages.recv().await {
+            Some(ClientCommand::ChannelMessage(id, ChannelMessage::Text(text), seq)) => {
+                assert_eq!(id, "prices");
+                assert_eq!(text, "hello");
+                assert_eq!(seq, 7);
+            }
+            other => panic!("Unexpected message {:?}", other),
+        }
+
+        handler.on_close();
+        assert!(messages.recv().await.is_none());
     }
 }
@@ -2,21 +2,26 @@
 //!
 //! A WebSocket client library for connecting to TSLM servers.
 //!
+//! The client runs on the current Tokio runtime and its commands resolve once the server
+//! replied to them. `blocking::LastMileClient` wraps it for sync callers.
+//!
 //! ## Example
 //!
 //! ```no_run
 //! use last_mile_client::client::LastMileClient;
-//! use std::sync::Arc;
-//! use tokio::runtime::Builder;
 //!
-//! let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().unwrap());
-//! let client = LastMileClient::connect(runtime, "ws://localhost:8080".to_string()).unwrap();
+//! # async fn run() -> Result<(), common::error::AppError> {
+//! let client = LastMileClient::connect("ws://localhost:8080".to_string()).await?;
 //!
 //! let channel_id = "my_channel".to_string();
-//! client.create_channel(&channel_id).unwrap();
-//! client.subscribe(&channel_id).unwrap();
-//! client.notify_channel(&channel_id, "Hello, world!".to_string()).unwrap();
+//! client.create_channel(&channel_id).await?;
+//! client.subscribe(&channel_id).await?;
+//! client.notify_channel(&channel_id, "Hello, world!".to_string()).await?;
+//! # Ok(())
+//! # }
 //! ```
 
+pub mod blocking;
 pub mod client;
+pub mod tls;
 mod websocket;
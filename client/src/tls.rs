@@ -0,0 +1,168 @@
+//! TLS options of `wss://` connections.
+
+use common::error::AppError;
+use native_tls::{Certificate, Identity, TlsConnector};
+use tokio_tungstenite::Connector;
+
+const PEM_CERTIFICATE_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+
+/// How the server certificate of `wss://` connections is verified.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TlsVerification {
+    /// The certificate must chain to a trusted root and be valid for the URL's host.
+    #[default]
+    Full,
+    /// The certificate must chain to a trusted root, whatever host it was issued for.
+    IgnoreHostname,
+    /// Any certificate is accepted, which leaves the connection open to interception.
+    /// Only meant for test environments with throwaway certificates.
+    Disabled,
+}
+
+/// The TLS options set on a `LastMileClientBuilder`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TlsOptions {
+    /// PEM bundles of roots trusted in addition to, or instead of, the system roots
+    pub root_certificates: Vec<Vec<u8>>,
+    pub only_custom_roots: bool,
+    /// PEM certificate chain and PKCS#8 key presented to listeners with mutual TLS
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    pub verification: TlsVerification,
+}
+
+impl TlsOptions {
+    /// The connector of these options, `None` for the defaults.
+    pub fn connector(&self) -> Result<Option<Connector>, AppError> {
+        if self.root_certificates.is_empty()
+            && !self.only_custom_roots
+            && self.identity.is_none()
+            && self.verification == TlsVerification::Full
+        {
+            return Ok(None);
+        }
+        let mut builder = TlsConnector::builder();
+        for bundle in &self.root_certificates {
+            for pem in pem_certificates(bundle)? {
+                builder.add_root_certificate(Certificate::from_pem(pem).map_err(invalid)?);
+            }
+        }
+        builder.disable_built_in_roots(self.only_custom_roots);
+        if let Some((cert_chain, key)) = &self.identity {
+            builder.identity(Identity::from_pkcs8(cert_chain, key).map_err(invalid)?);
+        }
+        match self.verification {
+            TlsVerification::Full => {}
+            TlsVerification::IgnoreHostname => {
+                builder.danger_accept_invalid_hostnames(true);
+            }
+            TlsVerification::Disabled => {
+                builder.danger_accept_invalid_certs(true);
+            }
+        }
+        Ok(Some(Connector::NativeTls(
+            builder.build().map_err(invalid)?,
+        )))
+    }
+}
+
+/// The certificates of a PEM bundle, `Certificate::from_pem` only reads the first one.
+fn pem_certificates(bundle: &[u8]) -> Result<Vec<&[u8]>, AppError> {
+    let text = std::str::from_utf8(bundle).map_err(invalid)?;
+    let certificates: Vec<&[u8]> = text
+        .match_indices(PEM_CERTIFICATE_BEGIN)
+        .map(|(start, _)| &bundle[start..])
+        .collect();
+    if certificates.is_empty() {
+        return Err(invalid("no certificate in the root certificates"));
+    }
+    Ok(certificates)
+}
+
+fn invalid(err: impl std::fmt::Display) -> AppError {
+    AppError::InvalidConfig(format!("TLS: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate_pem() -> String {
+        rcgen::generate_simple_self_signed(vec![String::from("localhost")])
+            .unwrap()
+            .cert
+            .pem()
+    }
+
+    #[test]
+    fn test_default_options() {
+        assert!(TlsOptions::default().connector().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pem_bundle() {
+        let first = certificate_pem();
+        let second = certificate_pem();
+        let bundle = format!("{}\n{}", first, second);
+
+        let certificates = pem_certificates(bundle.as_bytes()).unwrap();
+        assert_eq!(certificates.len(), 2);
+        // each slice runs to the end of the bundle, parsing only reads its first certificate
+        assert!(certificates[0].starts_with(first.as_bytes()));
+        assert_eq!(certificates[1], second.as_bytes());
+
+        let options = TlsOptions {
+            root_certificates: vec![bundle.into_bytes()],
+            only_custom_roots: true,
+            ..TlsOptions::default()
+        };
+        assert!(options.connector().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_bundle_without_certificate() {
+        let options = TlsOptions {
+            root_certificates: vec![b"not a certificate".to_vec()],
+            ..TlsOptions::default()
+        };
+        assert!(matches!(
+            options.connector(),
+            Err(AppError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_identity() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("publisher")]).unwrap();
+        let options = TlsOptions {
+            identity: Some((
+                cert.cert.pem().into_bytes(),
+                cert.key_pair.serialize_pem().into_bytes(),
+            )),
+            ..TlsOptions::default()
+        };
+        assert!(options.connector().unwrap().is_some());
+
+        // the key does not match the certificate
+        let other = rcgen::KeyPair::generate().unwrap();
+        let options = TlsOptions {
+            identity: Some((
+                cert.cert.pem().into_bytes(),
+                other.serialize_pem().into_bytes(),
+            )),
+            ..TlsOptions::default()
+        };
+        assert!(matches!(
+            options.connector(),
+            Err(AppError::InvalidConfig(_))
+        ));
+
+        let options = TlsOptions {
+            identity: Some((b"garbage".to_vec(), b"garbage".to_vec())),
+            ..TlsOptions::default()
+        };
+        assert!(matches!(
+            options.connector(),
+            Err(AppError::InvalidConfig(_))
+        ));
+    }
+}
@@ -3,19 +3,19 @@ use std::sync::Arc;
 
 use common::error::AppError;
 use futures_util::{SinkExt, StreamExt, future, pin_mut};
-use tokio::runtime::Runtime;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::task::JoinHandle;
-use tokio_tungstenite::tungstenite::http::Uri;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::error;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tokio_tungstenite::tungstenite::http::{HeaderValue, Uri};
+use tokio_tungstenite::{Connector, connect_async_tls_with_config, tungstenite::Message};
 
 pub struct Websocket<H>
 where
     H: WebsocketEventHandler + Sync + Send + 'static,
 {
     #[allow(dead_code)]
-    handle: JoinHandle<Result<(), AppError>>,
+    handle: JoinHandle<()>,
     sender: UnboundedSender<Message>,
     #[allow(dead_code)]
     pub handler: Arc<H>,
@@ -32,29 +32,45 @@ impl<H> Websocket<H>
 where
     H: WebsocketEventHandler + Sync + Send + 'static,
 {
-    pub fn open(runtime: &Runtime, url: String, handler: Arc<H>) -> Result<Self, AppError> {
+    /// Open the websocket, offering the `protocols` as the `Sec-WebSocket-Protocol`, e.g.
+    /// a token for token protected listeners and an encoding. `wss://` URLs use the given
+    /// TLS `connector`, or one with the system roots.
+    ///
+    /// Resolves once the handshake completed, the connection is then served by a task
+    /// spawned on the current Tokio runtime.
+    pub async fn open(
+        url: String,
+        protocols: Vec<String>,
+        connector: Option<Connector>,
+        handler: Arc<H>,
+    ) -> Result<Self, AppError> {
         let uri = Uri::from_str(url.as_str()).map_err(AppError::from)?;
+        let mut request = uri.into_client_request().map_err(AppError::from)?;
+        if !protocols.is_empty() {
+            let value = HeaderValue::from_str(&protocols.join(", ")).map_err(AppError::from)?;
+            request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+        }
 
-        let (otx, mut orx) = tokio::sync::mpsc::unbounded_channel::<Message>();
+        let (ws_stream, _response) = connect_async_tls_with_config(request, None, false, connector)
+            .await
+            .map_err(AppError::from)?;
 
-        let handler_ref = Arc::clone(&handler);
-        let handle: JoinHandle<Result<(), AppError>> = runtime.spawn(async move {
-            let (ws_stream, _response) = connect_async(&uri).await.map_err(AppError::from)?;
+        handler.on_connect();
 
-            handler_ref.on_connect();
+        let (otx, mut orx) = tokio::sync::mpsc::unbounded_channel::<Message>();
 
+        let handler_ref = Arc::clone(&handler);
+        let handle = tokio::spawn(async move {
             let (mut write, read) = ws_stream.split();
 
             let send_handler_ref = Arc::clone(&handler_ref);
-            let send_fut = async move {
+            let send_fut = async {
                 while let Some(msg) = orx.recv().await {
-                    match write.send(msg).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            // error!("Websocket error while trying to send message.");
-                            send_handler_ref.on_error(AppError::from(err));
-                        }
-                    };
+                    if let Err(err) = write.send(msg).await {
+                        // The connection is unusable, end it rather than wait for the read side.
+                        send_handler_ref.on_error(AppError::from(err));
+                        break;
+                    }
                 }
             };
 
@@ -66,19 +82,21 @@ where
                             recv_handler_ref.on_message(message);
                         }
                         Err(err) => {
-                            // error!("Error reading websocket message: {:?}", err);
                             recv_handler_ref.on_error(AppError::from(err));
                         }
                     }
                 },
             );
 
-            pin_mut!(recv_fut, send_fut);
-            future::select(recv_fut, send_fut).await;
+            {
+                pin_mut!(recv_fut, send_fut);
+                future::select(recv_fut, send_fut).await;
+            }
 
+            // Messages queued from now on fail to send, before the handler fails the pending
+            // commands.
+            orx.close();
             handler_ref.on_close();
-
-            Ok(())
         });
 
         Ok(Websocket {
@@ -88,12 +106,12 @@ where
         })
     }
 
-    pub fn send(&self, message: Message) {
-        match self.sender.send(message) {
-            Ok(_) => {}
-            Err(err) => {
-                error!("Error!: {}", err);
-            }
-        }
+    /// Queue the message, failing once the connection is closed.
+    pub fn send(&self, message: Message) -> Result<(), AppError> {
+        self.sender.send(message).map_err(|_| connection_closed())
     }
 }
+
+pub fn connection_closed() -> AppError {
+    AppError::WebSocket(String::from("Connection closed"))
+}
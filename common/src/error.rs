@@ -2,6 +2,8 @@
 
 use thiserror::Error;
 
+use crate::message::CommandError;
+
 /// Application-level error type.
 ///
 /// Represents various error conditions that can occur in the TSLM system.
@@ -27,6 +29,14 @@ pub enum AppError {
     #[error("Channel not found: {0}")]
     ChannelNotFound(String),
 
+    /// Channel already exists
+    #[error("Channel already exists: {0}")]
+    ChannelAlreadyExists(String),
+
+    /// The messages from the requested sequence number were evicted from the channel history
+    #[error("Sequence gap: {requested} is no longer available (oldest available: {oldest})")]
+    SequenceGap { requested: u64, oldest: u64 },
+
     /// Endpoint not found
     #[error("Endpoint not found: {0}")]
     EndpointNotFound(String),
@@ -39,6 +49,10 @@ pub enum AppError {
     #[error("Invalid configuration: {0}")]
     InvalidConfig(String),
 
+    /// Message that could not be decoded as a command
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+
     /// Message too large
     #[error("Message too large: {size} bytes (max: {max} bytes)")]
     MessageTooLarge { size: usize, max: usize },
@@ -51,6 +65,10 @@ pub enum AppError {
     #[error("Connection limit exceeded: {0}")]
     ConnectionLimitExceeded(String),
 
+    /// A command the server answered with an error
+    #[error("{}", .0.message)]
+    CommandRejected(CommandError),
+
     /// Serialization error
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
@@ -2,12 +2,33 @@
 //!
 //! This module defines the messages exchanged between clients and the server.
 
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::error::AppError;
+
 /// Unique identifier for a channel.
 pub type ChannelId = String;
 
+/// Client supplied identifier correlating a command with its reply.
+pub type CommandId = u64;
+
+/// Position of a message in its channel, increasing by one with every publish.
+pub type Sequence = u64;
+
+/// WebSocket subprotocol selecting the v2 JSON encoding, see `Protocol`.
+pub const PROTOCOL_V2_JSON: &str = "tslm.v2.json";
+
+/// WebSocket subprotocol selecting the v2 encoding in MessagePack binary frames.
+pub const PROTOCOL_V2_MSGPACK: &str = "tslm.v2.msgpack";
+
+/// WebSocket close code sent when the connection's credentials (e.g. a JWT) expire.
+pub const TOKEN_EXPIRED_CLOSE_CODE: u16 = 4001;
+
+/// WebSocket close code sent when the client reads too slowly to keep up with its channels.
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 4002;
+
 /// Messages published to channels.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ChannelMessage {
@@ -15,6 +36,57 @@ pub enum ChannelMessage {
     Text(String),
     /// JSON structured message
     Json(Value),
+    /// Opaque binary payload, fanned out without being decoded or copied. Base64 in JSON.
+    Binary(#[serde(with = "binary")] Bytes),
+}
+
+/// Options set when a channel is created.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ChannelOptions {
+    /// Delete the channel when the endpoint that created it disconnects
+    pub delete_on_disconnect: bool,
+    /// Succeed without changes when the channel already exists
+    pub if_not_exists: bool,
+    /// Keep the last published message and send it to new subscribers as a
+    /// `ClientCommand::ChannelSnapshot`
+    pub retain_last: bool,
+    /// Keep up to this many published messages for subscribers resuming with `from_seq`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_size: Option<usize>,
+    /// Keep the messages published in the last this many seconds for subscribers resuming
+    /// with `from_seq`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_seconds: Option<u64>,
+    /// Keep the published messages in a log on the server's disk instead of the history,
+    /// so they and the channel itself survive a restart. Requires the server `storage`
+    pub durable: bool,
+    /// Send each subscriber at most this many updates per second, a subscriber waiting
+    /// for its next update only gets the latest message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflate_per_second: Option<u32>,
+    /// With `conflate_per_second`, keep the latest message per value of this JSON field
+    /// (a `.` separated path) instead of only the latest message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conflate_key: Option<String>,
+}
+
+/// Options set when subscribing to a channel.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct SubscribeOptions {
+    /// Resume from this sequence number, first replaying the messages the channel still
+    /// keeps in its history. Fails with `ErrorKind::SequenceGap` when they are gone.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_seq: Option<Sequence>,
+    /// Only receive the JSON messages matching this expression, e.g.
+    /// `symbol == "AAPL" && price > 100`. Evaluated by the server for each message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    /// Only receive these fields of JSON messages, as JSON pointers (`/quote/bid`) or
+    /// `.` separated paths (`quote.bid`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub projection: Option<Vec<String>>,
 }
 
 /// Commands sent from clients to the server.
@@ -22,24 +94,610 @@ pub enum ChannelMessage {
 pub enum TerminalStreamCommand {
     /// Create a new channel with the given ID
     CreateChannel(ChannelId),
+    /// Create a new channel with the given ID and options
+    CreateChannelWithOptions(ChannelId, ChannelOptions),
+    /// Delete a channel, its subscribers are notified with `ClientCommand::ChannelClosed`
+    DeleteChannel(ChannelId),
     /// Subscribe to receive messages from a channel
     Subscribe(ChannelId),
+    /// Subscribe to a channel with options, e.g. resuming from a sequence number
+    SubscribeWithOptions(ChannelId, SubscribeOptions),
+    /// Stop receiving messages from a channel
+    Unsubscribe(ChannelId),
     /// Publish a message to a channel
     NotifyChannel(ChannelId, ChannelMessage),
 }
 
+impl TerminalStreamCommand {
+    /// The command name, as used for the variant on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TerminalStreamCommand::CreateChannel(_) => "CreateChannel",
+            TerminalStreamCommand::CreateChannelWithOptions(_, _) => "CreateChannelWithOptions",
+            TerminalStreamCommand::DeleteChannel(_) => "DeleteChannel",
+            TerminalStreamCommand::Subscribe(_) => "Subscribe",
+            TerminalStreamCommand::SubscribeWithOptions(_, _) => "SubscribeWithOptions",
+            TerminalStreamCommand::Unsubscribe(_) => "Unsubscribe",
+            TerminalStreamCommand::NotifyChannel(_, _) => "NotifyChannel",
+        }
+    }
+
+    /// The channel the command applies to.
+    pub fn channel_id(&self) -> &ChannelId {
+        match self {
+            TerminalStreamCommand::CreateChannel(channel_id)
+            | TerminalStreamCommand::CreateChannelWithOptions(channel_id, _)
+            | TerminalStreamCommand::DeleteChannel(channel_id)
+            | TerminalStreamCommand::Subscribe(channel_id)
+            | TerminalStreamCommand::SubscribeWithOptions(channel_id, _)
+            | TerminalStreamCommand::Unsubscribe(channel_id)
+            | TerminalStreamCommand::NotifyChannel(channel_id, _) => channel_id,
+        }
+    }
+}
+
+/// A command as sent on the wire, with an optional correlation id next to the command,
+/// e.g. `{"id": 7, "Subscribe": "prices"}`. Replies to the command carry the same id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<CommandId>,
+    #[serde(flatten)]
+    pub command: TerminalStreamCommand,
+}
+
+impl CommandEnvelope {
+    pub fn new(id: Option<CommandId>, command: TerminalStreamCommand) -> Self {
+        CommandEnvelope { id, command }
+    }
+}
+
+impl From<TerminalStreamCommand> for CommandEnvelope {
+    fn from(command: TerminalStreamCommand) -> Self {
+        CommandEnvelope::new(None, command)
+    }
+}
+
+/// Acknowledgment of a successfully executed command.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CommandSuccess {
+    /// The id of the command, when it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<CommandId>,
+    /// The executed command, e.g. `Subscribe`
+    pub command: String,
+    /// The channel the command applied to
+    pub channel_id: ChannelId,
+}
+
+impl CommandSuccess {
+    pub fn new(id: Option<CommandId>, command: &TerminalStreamCommand) -> Self {
+        CommandSuccess {
+            id,
+            command: command.name().to_string(),
+            channel_id: command.channel_id().clone(),
+        }
+    }
+}
+
+/// Machine readable category of a `CommandError`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The message could not be parsed as a command
+    InvalidMessage,
+    /// The message exceeds the size limit
+    MessageTooLarge,
+    /// The connection sent too many messages
+    RateLimitExceeded,
+    /// The endpoint lacks the permission, or the channel is outside its ACL
+    PermissionDenied,
+    /// The channel does not exist
+    ChannelNotFound,
+    /// The channel already exists
+    ChannelAlreadyExists,
+    /// The messages from the requested sequence number are no longer kept
+    SequenceGap,
+    /// Any other server side failure
+    Internal,
+}
+
+impl From<&AppError> for ErrorKind {
+    fn from(err: &AppError) -> Self {
+        match err {
+            AppError::Serialization(_) | AppError::InvalidMessage(_) => ErrorKind::InvalidMessage,
+            AppError::MessageTooLarge { .. } => ErrorKind::MessageTooLarge,
+            AppError::RateLimitExceeded(_) => ErrorKind::RateLimitExceeded,
+            AppError::PermissionDenied(_) => ErrorKind::PermissionDenied,
+            AppError::ChannelNotFound(_) => ErrorKind::ChannelNotFound,
+            AppError::ChannelAlreadyExists(_) => ErrorKind::ChannelAlreadyExists,
+            AppError::SequenceGap { .. } => ErrorKind::SequenceGap,
+            AppError::CommandRejected(err) => err.kind,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// Failure of a command, or of a message that could not be handled as one.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CommandError {
+    /// The id of the command, when it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<CommandId>,
+    pub kind: ErrorKind,
+    /// Human readable description
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(id: Option<CommandId>, err: &AppError) -> Self {
+        CommandError {
+            id,
+            kind: ErrorKind::from(err),
+            message: err.to_string(),
+        }
+    }
+}
+
 /// Messages sent from the server to clients.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ClientCommand {
     /// Text primitive, useful for debugging
     #[allow(dead_code)]
     Text(String),
-    /// An incoming message from the given channel
-    ChannelMessage(ChannelId, ChannelMessage),
+    /// An incoming message from the given channel, with its sequence number. The legacy
+    /// encoding leaves the sequence number out, only v2 messages carry it.
+    ChannelMessage(ChannelId, ChannelMessage, #[serde(skip)] Sequence),
+    /// The last message published to the given channel before subscribing, with its
+    /// sequence number, sent once on subscription to channels retaining their last message.
+    /// Like `ChannelMessage`, only v2 messages carry the sequence number.
+    ChannelSnapshot(ChannelId, ChannelMessage, #[serde(skip)] Sequence),
+    /// The given channel was deleted, no more messages will come from it
+    ChannelClosed(ChannelId),
     /// Error response when a command fails
-    Error(String),
+    Error(CommandError),
     /// Success acknowledgment for a command
-    Success(String),
+    Success(CommandSuccess),
+}
+
+/// Version carried in the `v` field of v2 messages.
+pub const V2: u8 = 2;
+
+/// A channel message in the v2 encoding, e.g. `{"type": "Text", "data": "hello"}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", content = "data")]
+pub enum ChannelMessageV2 {
+    Text(String),
+    Json(Value),
+    Binary(#[serde(with = "binary")] Bytes),
+}
+
+impl From<ChannelMessage> for ChannelMessageV2 {
+    fn from(msg: ChannelMessage) -> Self {
+        match msg {
+            ChannelMessage::Text(text) => ChannelMessageV2::Text(text),
+            ChannelMessage::Json(value) => ChannelMessageV2::Json(value),
+            ChannelMessage::Binary(data) => ChannelMessageV2::Binary(data),
+        }
+    }
+}
+
+impl From<ChannelMessageV2> for ChannelMessage {
+    fn from(msg: ChannelMessageV2) -> Self {
+        match msg {
+            ChannelMessageV2::Text(text) => ChannelMessage::Text(text),
+            ChannelMessageV2::Json(value) => ChannelMessage::Json(value),
+            ChannelMessageV2::Binary(data) => ChannelMessage::Binary(data),
+        }
+    }
+}
+
+/// A command in the v2 encoding, tagged by `type` with named fields, e.g.
+/// `{"v": 2, "id": 7, "type": "NotifyChannel", "channel_id": "prices", "message": {...}}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommandV2 {
+    pub v: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<CommandId>,
+    #[serde(flatten)]
+    pub command: CommandBodyV2,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CommandBodyV2 {
+    CreateChannel {
+        channel_id: ChannelId,
+        #[serde(default)]
+        options: ChannelOptions,
+    },
+    DeleteChannel {
+        channel_id: ChannelId,
+    },
+    Subscribe {
+        channel_id: ChannelId,
+        #[serde(default)]
+        options: SubscribeOptions,
+    },
+    Unsubscribe {
+        channel_id: ChannelId,
+    },
+    NotifyChannel {
+        channel_id: ChannelId,
+        message: ChannelMessageV2,
+    },
+}
+
+impl From<CommandEnvelope> for CommandV2 {
+    fn from(envelope: CommandEnvelope) -> Self {
+        let command = match envelope.command {
+            TerminalStreamCommand::CreateChannel(channel_id) => CommandBodyV2::CreateChannel {
+                channel_id,
+                options: ChannelOptions::default(),
+            },
+            TerminalStreamCommand::CreateChannelWithOptions(channel_id, options) => {
+                CommandBodyV2::CreateChannel {
+                    channel_id,
+                    options,
+                }
+            }
+            TerminalStreamCommand::DeleteChannel(channel_id) => {
+                CommandBodyV2::DeleteChannel { channel_id }
+            }
+            TerminalStreamCommand::Subscribe(channel_id) => CommandBodyV2::Subscribe {
+                channel_id,
+                options: SubscribeOptions::default(),
+            },
+            TerminalStreamCommand::SubscribeWithOptions(channel_id, options) => {
+                CommandBodyV2::Subscribe {
+                    channel_id,
+                    options,
+                }
+            }
+            TerminalStreamCommand::Unsubscribe(channel_id) => {
+                CommandBodyV2::Unsubscribe { channel_id }
+            }
+            TerminalStreamCommand::NotifyChannel(channel_id, msg) => CommandBodyV2::NotifyChannel {
+                channel_id,
+                message: msg.into(),
+            },
+        };
+        CommandV2 {
+            v: V2,
+            id: envelope.id,
+            command,
+        }
+    }
+}
+
+impl From<CommandV2> for CommandEnvelope {
+    fn from(command: CommandV2) -> Self {
+        let command_v1 = match command.command {
+            CommandBodyV2::CreateChannel {
+                channel_id,
+                options,
+            } if options == ChannelOptions::default() => {
+                TerminalStreamCommand::CreateChannel(channel_id)
+            }
+            CommandBodyV2::CreateChannel {
+                channel_id,
+                options,
+            } => TerminalStreamCommand::CreateChannelWithOptions(channel_id, options),
+            CommandBodyV2::DeleteChannel { channel_id } => {
+                TerminalStreamCommand::DeleteChannel(channel_id)
+            }
+            CommandBodyV2::Subscribe {
+                channel_id,
+                options,
+            } if options == SubscribeOptions::default() => {
+                TerminalStreamCommand::Subscribe(channel_id)
+            }
+            CommandBodyV2::Subscribe {
+                channel_id,
+                options,
+            } => TerminalStreamCommand::SubscribeWithOptions(channel_id, options),
+            CommandBodyV2::Unsubscribe { channel_id } => {
+                TerminalStreamCommand::Unsubscribe(channel_id)
+            }
+            CommandBodyV2::NotifyChannel {
+                channel_id,
+                message,
+            } => TerminalStreamCommand::NotifyChannel(channel_id, message.into()),
+        };
+        CommandEnvelope::new(command.id, command_v1)
+    }
+}
+
+/// A server message in the v2 encoding, e.g.
+/// `{"v": 2, "type": "ChannelMessage", "channel_id": "prices", "message": {...}}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientCommandV2 {
+    pub v: u8,
+    #[serde(flatten)]
+    pub command: ClientCommandBodyV2,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientCommandBodyV2 {
+    Text {
+        text: String,
+    },
+    ChannelMessage {
+        channel_id: ChannelId,
+        seq: Sequence,
+        message: ChannelMessageV2,
+    },
+    ChannelSnapshot {
+        channel_id: ChannelId,
+        seq: Sequence,
+        message: ChannelMessageV2,
+    },
+    ChannelClosed {
+        channel_id: ChannelId,
+    },
+    Error(CommandError),
+    Success(CommandSuccess),
+}
+
+impl From<ClientCommand> for ClientCommandV2 {
+    fn from(command: ClientCommand) -> Self {
+        let command = match command {
+            ClientCommand::Text(text) => ClientCommandBodyV2::Text { text },
+            ClientCommand::ChannelMessage(channel_id, msg, seq) => {
+                ClientCommandBodyV2::ChannelMessage {
+                    channel_id,
+                    seq,
+                    message: msg.into(),
+                }
+            }
+            ClientCommand::ChannelSnapshot(channel_id, msg, seq) => {
+                ClientCommandBodyV2::ChannelSnapshot {
+                    channel_id,
+                    seq,
+                    message: msg.into(),
+                }
+            }
+            ClientCommand::ChannelClosed(channel_id) => {
+                ClientCommandBodyV2::ChannelClosed { channel_id }
+            }
+            ClientCommand::Error(err) => ClientCommandBodyV2::Error(err),
+            ClientCommand::Success(success) => ClientCommandBodyV2::Success(success),
+        };
+        ClientCommandV2 { v: V2, command }
+    }
+}
+
+impl From<ClientCommandV2> for ClientCommand {
+    fn from(command: ClientCommandV2) -> Self {
+        match command.command {
+            ClientCommandBodyV2::Text { text } => ClientCommand::Text(text),
+            ClientCommandBodyV2::ChannelMessage {
+                channel_id,
+                seq,
+                message,
+            } => ClientCommand::ChannelMessage(channel_id, message.into(), seq),
+            ClientCommandBodyV2::ChannelSnapshot {
+                channel_id,
+                seq,
+                message,
+            } => ClientCommand::ChannelSnapshot(channel_id, message.into(), seq),
+            ClientCommandBodyV2::ChannelClosed { channel_id } => {
+                ClientCommand::ChannelClosed(channel_id)
+            }
+            ClientCommandBodyV2::Error(err) => ClientCommand::Error(err),
+            ClientCommandBodyV2::Success(success) => ClientCommand::Success(success),
+        }
+    }
+}
+
+/// An encoded message, sent as a text or a binary websocket frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Bytes),
+}
+
+impl Frame {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Frame::Text(text) => text.as_bytes(),
+            Frame::Binary(data) => data,
+        }
+    }
+}
+
+/// Wire encoding of a connection, negotiated through the websocket subprotocol.
+///
+/// `Legacy` serializes the enums with serde's external tagging and is used unless the
+/// client offers `tslm.v2.json` or `tslm.v2.msgpack`. `MessagePack` carries the v2 messages,
+/// with their named fields, in binary frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Legacy,
+    V2Json,
+    MessagePack,
+}
+
+impl Protocol {
+    /// Pick the encoding from the subprotocols offered by the client.
+    pub fn negotiate<'a>(offered: impl IntoIterator<Item = &'a str>) -> Self {
+        offered
+            .into_iter()
+            .find_map(Protocol::from_subprotocol)
+            .unwrap_or_default()
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        match name {
+            PROTOCOL_V2_JSON => Some(Protocol::V2Json),
+            PROTOCOL_V2_MSGPACK => Some(Protocol::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// The subprotocol to request, and to echo back in the handshake.
+    pub fn subprotocol(&self) -> Option<&'static str> {
+        match self {
+            Protocol::Legacy => None,
+            Protocol::V2Json => Some(PROTOCOL_V2_JSON),
+            Protocol::MessagePack => Some(PROTOCOL_V2_MSGPACK),
+        }
+    }
+
+    /// Whether the encoding uses binary frames rather than text frames.
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Protocol::MessagePack)
+    }
+
+    pub fn decode_command(&self, data: &[u8]) -> Result<CommandEnvelope, AppError> {
+        match self {
+            Protocol::Legacy => Ok(serde_json::from_slice(data)?),
+            Protocol::V2Json => {
+                let command: CommandV2 = serde_json::from_slice(data)?;
+                check_version(command.v)?;
+                Ok(command.into())
+            }
+            Protocol::MessagePack => {
+                let command: CommandV2 = from_msgpack(data)?;
+                check_version(command.v)?;
+                Ok(command.into())
+            }
+        }
+    }
+
+    pub fn encode_command(&self, envelope: CommandEnvelope) -> Result<Frame, AppError> {
+        match self {
+            Protocol::Legacy => Ok(Frame::Text(serde_json::to_string(&envelope)?)),
+            Protocol::V2Json => Ok(Frame::Text(serde_json::to_string(&CommandV2::from(
+                envelope,
+            ))?)),
+            Protocol::MessagePack => to_msgpack(&CommandV2::from(envelope)),
+        }
+    }
+
+    pub fn decode_client_command(&self, data: &[u8]) -> Result<ClientCommand, AppError> {
+        match self {
+            Protocol::Legacy => Ok(serde_json::from_slice(data)?),
+            Protocol::V2Json => {
+                let command: ClientCommandV2 = serde_json::from_slice(data)?;
+                check_version(command.v)?;
+                Ok(command.into())
+            }
+            Protocol::MessagePack => {
+                let command: ClientCommandV2 = from_msgpack(data)?;
+                check_version(command.v)?;
+                Ok(command.into())
+            }
+        }
+    }
+
+    pub fn encode_client_command(&self, command: ClientCommand) -> Result<Frame, AppError> {
+        match self {
+            Protocol::Legacy => Ok(Frame::Text(serde_json::to_string(&command)?)),
+            Protocol::V2Json => Ok(Frame::Text(serde_json::to_string(&ClientCommandV2::from(
+                command,
+            ))?)),
+            Protocol::MessagePack => to_msgpack(&ClientCommandV2::from(command)),
+        }
+    }
+
+    /// Best effort read of the `id` of a message that could not be decoded as a command,
+    /// so the error reply can still be correlated.
+    pub fn peek_command_id(&self, data: &[u8]) -> Option<CommandId> {
+        #[derive(Deserialize)]
+        struct IdOnly {
+            id: Option<CommandId>,
+        }
+        let id_only: IdOnly = match self {
+            Protocol::Legacy | Protocol::V2Json => serde_json::from_slice(data).ok()?,
+            Protocol::MessagePack => rmp_serde::from_slice(data).ok()?,
+        };
+        id_only.id
+    }
+}
+
+fn check_version(v: u8) -> Result<(), AppError> {
+    if v == V2 {
+        Ok(())
+    } else {
+        Err(AppError::InvalidMessage(format!(
+            "unsupported protocol version {}",
+            v
+        )))
+    }
+}
+
+fn from_msgpack<T: for<'de> Deserialize<'de>>(data: &[u8]) -> Result<T, AppError> {
+    rmp_serde::from_slice(data).map_err(|err| AppError::InvalidMessage(err.to_string()))
+}
+
+fn to_msgpack<T: Serialize>(value: &T) -> Result<Frame, AppError> {
+    let data = rmp_serde::to_vec_named(value).map_err(|err| AppError::msg(err.to_string()))?;
+    Ok(Frame::Binary(Bytes::from(data)))
+}
+
+/// Binary payloads as base64 strings in human-readable encodings such as JSON, and as
+/// native bytes otherwise. Both are accepted whatever the encoding, as tagged enums may
+/// buffer the payload before deserializing it, and so are arrays of byte values, as sent by
+/// clients predating base64.
+mod binary {
+    use std::fmt;
+
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use bytes::Bytes;
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(data))
+        } else {
+            serializer.serialize_bytes(data)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BinaryVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BinaryVisitor)
+        }
+    }
+
+    struct BinaryVisitor;
+
+    impl<'de> Visitor<'de> for BinaryVisitor {
+        type Value = Bytes;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a base64 string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Bytes, E> {
+            STANDARD
+                .decode(value)
+                .map(Bytes::from)
+                .map_err(|err| E::custom(format!("invalid base64: {}", err)))
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Bytes, E> {
+            Ok(Bytes::copy_from_slice(value))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Bytes, E> {
+            Ok(Bytes::from(value))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+            let mut data = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element::<u8>()? {
+                data.push(byte);
+            }
+            Ok(Bytes::from(data))
+        }
+    }
 }
 
 #[cfg(test)]
@@ -65,11 +723,129 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deserialize_unsubscribe_command() {
+        let json = r#"{"Unsubscribe":"test_channel"}"#;
+        let cmd: TerminalStreamCommand = serde_json::from_str(json).unwrap();
+
+        match cmd {
+            TerminalStreamCommand::Unsubscribe(id) => assert_eq!(id, "test_channel"),
+            _ => panic!("Wrong command type"),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_create_channel_with_options() {
+        let json = r#"{"CreateChannelWithOptions":["test_channel",{"delete_on_disconnect":true}]}"#;
+        let cmd: TerminalStreamCommand = serde_json::from_str(json).unwrap();
+
+        match cmd {
+            TerminalStreamCommand::CreateChannelWithOptions(id, options) => {
+                assert_eq!(id, "test_channel");
+                assert!(options.delete_on_disconnect);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        // missing options take their defaults
+        let options: ChannelOptions = serde_json::from_str("{}").unwrap();
+        assert_eq!(options, ChannelOptions::default());
+    }
+
+    #[test]
+    fn test_sequenced_channel_message() {
+        let json = r#"{"SubscribeWithOptions":["prices",{"from_seq":42}]}"#;
+        let cmd: TerminalStreamCommand = serde_json::from_str(json).unwrap();
+        match cmd {
+            TerminalStreamCommand::SubscribeWithOptions(id, options) => {
+                assert_eq!(id, "prices");
+                assert_eq!(options.from_seq, Some(42));
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let options = SubscribeOptions {
+            filter: Some(String::from(r#"symbol == "AAPL""#)),
+            ..SubscribeOptions::default()
+        };
+        let json = serde_json::to_string(&options).unwrap();
+        // unset options are left out
+        assert_eq!(json, r#"{"filter":"symbol == \"AAPL\""}"#);
+        assert_eq!(
+            serde_json::from_str::<SubscribeOptions>(&json).unwrap(),
+            options
+        );
+
+        let cmd = ClientCommand::ChannelMessage(
+            String::from("prices"),
+            ChannelMessage::Text(String::from("hello")),
+            42,
+        );
+        let json = serde_json::to_string(&cmd).unwrap();
+        // the legacy encoding is unchanged, the sequence number is only sent in v2
+        assert_eq!(json, r#"{"ChannelMessage":["prices",{"Text":"hello"}]}"#);
+        match serde_json::from_str::<ClientCommand>(&json).unwrap() {
+            ClientCommand::ChannelMessage(id, ChannelMessage::Text(text), seq) => {
+                assert_eq!(id, "prices");
+                assert_eq!(text, "hello");
+                assert_eq!(seq, 0);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let err = AppError::SequenceGap {
+            requested: 42,
+            oldest: 50,
+        };
+        assert_eq!(ErrorKind::from(&err), ErrorKind::SequenceGap);
+    }
+
+    #[test]
+    fn test_deserialize_command_envelope() {
+        let json = r#"{"id":7,"Subscribe":"test_channel"}"#;
+        let envelope: CommandEnvelope = serde_json::from_str(json).unwrap();
+        assert_eq!(envelope.id, Some(7));
+        assert_eq!(envelope.command.name(), "Subscribe");
+        assert_eq!(envelope.command.channel_id(), "test_channel");
+
+        // the id is optional
+        let json = r#"{"NotifyChannel":["test_channel",{"Text":"hello"}]}"#;
+        let envelope: CommandEnvelope = serde_json::from_str(json).unwrap();
+        assert!(envelope.id.is_none());
+        assert_eq!(envelope.command.name(), "NotifyChannel");
+    }
+
+    #[test]
+    fn test_serialize_command_envelope() {
+        let envelope = CommandEnvelope::new(
+            Some(3),
+            TerminalStreamCommand::Subscribe(String::from("test_channel")),
+        );
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(json, r#"{"id":3,"Subscribe":"test_channel"}"#);
+
+        let envelope = CommandEnvelope::from(TerminalStreamCommand::Subscribe(String::from("c")));
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(json, r#"{"Subscribe":"c"}"#);
+    }
+
     #[test]
     fn test_serialize_client_command() {
-        let cmd = ClientCommand::Error(String::from("test error"));
+        let err = AppError::ChannelNotFound(String::from("test_channel"));
+        let cmd = ClientCommand::Error(CommandError::new(Some(1), &err));
+        let json = serde_json::to_string(&cmd).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Error":{"id":1,"kind":"ChannelNotFound","message":"Channel not found: test_channel"}}"#
+        );
+
+        let command = TerminalStreamCommand::Subscribe(String::from("test_channel"));
+        let cmd = ClientCommand::Success(CommandSuccess::new(None, &command));
         let json = serde_json::to_string(&cmd).unwrap();
-        assert!(json.contains("Error"));
+        assert_eq!(
+            json,
+            r#"{"Success":{"command":"Subscribe","channel_id":"test_channel"}}"#
+        );
     }
 
     #[test]
@@ -83,4 +859,190 @@ mod tests {
             _ => panic!("Wrong message type"),
         }
     }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        assert_eq!(Protocol::negotiate(["token"]), Protocol::Legacy);
+        assert_eq!(
+            Protocol::negotiate(["token", PROTOCOL_V2_JSON]),
+            Protocol::V2Json
+        );
+    }
+
+    #[test]
+    fn test_decode_v2_command() {
+        let json = r#"{"v":2,"id":7,"type":"NotifyChannel","channel_id":"prices","message":{"type":"Json","data":{"px":1}}}"#;
+        let envelope = Protocol::V2Json.decode_command(json.as_bytes()).unwrap();
+        assert_eq!(envelope.id, Some(7));
+        match envelope.command {
+            TerminalStreamCommand::NotifyChannel(id, ChannelMessage::Json(value)) => {
+                assert_eq!(id, "prices");
+                assert_eq!(value["px"], 1);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let json = r#"{"v":2,"type":"CreateChannel","channel_id":"prices","options":{"if_not_exists":true}}"#;
+        let envelope = Protocol::V2Json.decode_command(json.as_bytes()).unwrap();
+        match envelope.command {
+            TerminalStreamCommand::CreateChannelWithOptions(id, options) => {
+                assert_eq!(id, "prices");
+                assert!(options.if_not_exists);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        // subscribing from a sequence number
+        let json = r#"{"v":2,"type":"Subscribe","channel_id":"prices","options":{"from_seq":42}}"#;
+        let envelope = Protocol::V2Json.decode_command(json.as_bytes()).unwrap();
+        match envelope.command {
+            TerminalStreamCommand::SubscribeWithOptions(id, options) => {
+                assert_eq!(id, "prices");
+                assert_eq!(options.from_seq, Some(42));
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        // the version is required and checked
+        let json = r#"{"type":"Subscribe","channel_id":"prices"}"#;
+        assert!(Protocol::V2Json.decode_command(json.as_bytes()).is_err());
+        let json = r#"{"v":3,"type":"Subscribe","channel_id":"prices"}"#;
+        assert!(Protocol::V2Json.decode_command(json.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_encode_v2_client_command() {
+        let cmd = ClientCommand::ChannelMessage(
+            String::from("prices"),
+            ChannelMessage::Text(String::from("hello")),
+            4,
+        );
+        let frame = Protocol::V2Json.encode_client_command(cmd).unwrap();
+        assert_eq!(
+            frame,
+            Frame::Text(String::from(
+                r#"{"v":2,"type":"ChannelMessage","channel_id":"prices","seq":4,"message":{"type":"Text","data":"hello"}}"#
+            ))
+        );
+
+        let command = TerminalStreamCommand::Subscribe(String::from("prices"));
+        let cmd = ClientCommand::Success(CommandSuccess::new(Some(7), &command));
+        let frame = Protocol::V2Json.encode_client_command(cmd).unwrap();
+        assert_eq!(
+            frame.as_bytes(),
+            br#"{"v":2,"type":"Success","id":7,"command":"Subscribe","channel_id":"prices"}"#
+        );
+        match Protocol::V2Json
+            .decode_client_command(frame.as_bytes())
+            .unwrap()
+        {
+            ClientCommand::Success(success) => assert_eq!(success.id, Some(7)),
+            _ => panic!("Wrong command type"),
+        }
+    }
+
+    #[test]
+    fn test_binary_json_round_trip() {
+        let payload = Bytes::from_static(&[0, 159, 146, 150]);
+        let cmd = ClientCommand::ChannelMessage(
+            String::from("ticks"),
+            ChannelMessage::Binary(payload.clone()),
+            1,
+        );
+        let frame = Protocol::V2Json.encode_client_command(cmd).unwrap();
+        assert_eq!(
+            frame.as_bytes(),
+            br#"{"v":2,"type":"ChannelMessage","channel_id":"ticks","seq":1,"message":{"type":"Binary","data":"AJ+Slg=="}}"#
+        );
+        match Protocol::V2Json
+            .decode_client_command(frame.as_bytes())
+            .unwrap()
+        {
+            ClientCommand::ChannelMessage(_, ChannelMessage::Binary(data), _) => {
+                assert_eq!(data, payload)
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let envelope = CommandEnvelope::new(
+            None,
+            TerminalStreamCommand::NotifyChannel(
+                String::from("ticks"),
+                ChannelMessage::Binary(payload.clone()),
+            ),
+        );
+        let frame = Protocol::Legacy.encode_command(envelope).unwrap();
+        assert!(String::from_utf8_lossy(frame.as_bytes()).contains(r#"{"Binary":"AJ+Slg=="}"#));
+        let decoded = [
+            frame.as_bytes(),
+            // arrays of byte values are still accepted
+            br#"{"NotifyChannel":["ticks",{"Binary":[0,159,146,150]}]}"#,
+        ];
+        for data in decoded {
+            match Protocol::Legacy.decode_command(data).unwrap().command {
+                TerminalStreamCommand::NotifyChannel(_, ChannelMessage::Binary(data)) => {
+                    assert_eq!(data, payload)
+                }
+                _ => panic!("Wrong command type"),
+            }
+        }
+
+        let json = r#"{"v":2,"type":"NotifyChannel","channel_id":"ticks","message":{"type":"Binary","data":"not base64!"}}"#;
+        assert!(Protocol::V2Json.decode_command(json.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let payload = Bytes::from_static(&[0, 159, 146, 150]);
+        let envelope = CommandEnvelope::new(
+            Some(9),
+            TerminalStreamCommand::NotifyChannel(
+                String::from("ticks"),
+                ChannelMessage::Binary(payload.clone()),
+            ),
+        );
+        let frame = Protocol::MessagePack.encode_command(envelope).unwrap();
+        assert!(matches!(frame, Frame::Binary(_)));
+        // the payload is a native `bin 8` value, not base64
+        assert!(
+            frame
+                .as_bytes()
+                .windows(6)
+                .any(|window| window == [0xc4, 4, 0, 159, 146, 150])
+        );
+        assert_eq!(
+            Protocol::MessagePack.peek_command_id(frame.as_bytes()),
+            Some(9)
+        );
+
+        let envelope = Protocol::MessagePack
+            .decode_command(frame.as_bytes())
+            .unwrap();
+        assert_eq!(envelope.id, Some(9));
+        match envelope.command {
+            TerminalStreamCommand::NotifyChannel(id, ChannelMessage::Binary(data)) => {
+                assert_eq!(id, "ticks");
+                assert_eq!(data, payload);
+            }
+            _ => panic!("Wrong command type"),
+        }
+
+        let cmd = ClientCommand::ChannelMessage(
+            String::from("ticks"),
+            ChannelMessage::Binary(payload),
+            1,
+        );
+        let frame = Protocol::MessagePack.encode_client_command(cmd).unwrap();
+        match Protocol::MessagePack
+            .decode_client_command(frame.as_bytes())
+            .unwrap()
+        {
+            ClientCommand::ChannelMessage(id, ChannelMessage::Binary(data), seq) => {
+                assert_eq!(id, "ticks");
+                assert_eq!(seq, 1);
+                assert_eq!(data.as_ref(), &[0, 159, 146, 150]);
+            }
+            _ => panic!("Wrong command type"),
+        }
+    }
 }
@@ -12,6 +12,102 @@ pub enum Permission {
     Subscribe,
     CreateChannel,
     NotifyChannel,
+    DeleteChannel,
+}
+
+/// Restricts a permission to the channels matching one of the patterns
+/// (`*` and `?` wildcards). It does not grant the permission by itself.
+///
+/// Unlike in subscription patterns, where `*` is exactly one `.` separated token, `*` here
+/// matches any run of characters including dots: `prices.*` allows `prices.us.AAPL`. A
+/// pattern subscription is checked against each channel it matches, not the pattern.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AclRuleConfig {
+    pub permission: Permission,
+    pub channels: Vec<String>,
+}
+
+/// A token with its own permissions, see `ListenerConfig::tokens`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+    pub permissions: HashSet<Permission>,
+    /// Optional channel id patterns (`*` and `?` wildcards) the token may use.
+    /// When missing the token can use any channel.
+    pub channels: Option<Vec<String>>,
+    /// Optional per permission channel restrictions for this token.
+    pub acl: Option<Vec<AclRuleConfig>>,
+}
+
+/// Clients identified by their TLS certificate, see `ListenerConfig::client_certs`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientCertConfig {
+    /// Patterns (`*` and `?` wildcards) matched against the certificate's subject common
+    /// name and its DNS and URI subject alternative names.
+    pub names: Vec<String>,
+    pub permissions: HashSet<Permission>,
+    /// Optional channel id patterns (`*` and `?` wildcards) the client may use.
+    pub channels: Option<Vec<String>>,
+    /// Optional per permission channel restrictions for this client.
+    pub acl: Option<Vec<AclRuleConfig>>,
+    /// Add the permissions to `default_endpoint_permissions` instead of replacing them,
+    /// `channels` and `acl` then apply on top of the listener's `acl` (default: false)
+    pub extend_default_permissions: Option<bool>,
+}
+
+/// Whether clients of a listener with `tls_client_ca` must present a certificate.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// The TLS handshake fails without a certificate issued by the CA bundle.
+    #[default]
+    Required,
+    /// Clients may connect without a certificate and authenticate as on other listeners.
+    /// A certificate they present must still be issued by the CA bundle.
+    Optional,
+}
+
+/// What happens to a subscriber whose outgoing queue (`channel_buffer_size`) is full.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued message to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Replace the queued message of the same channel with the new one, falling back to
+    /// dropping the oldest message when the channel has none queued.
+    ConflateLatest,
+    /// Close the connection.
+    Disconnect,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    ES256,
+}
+
+/// JWT authentication, see `ListenerConfig::jwt`.
+///
+/// The token claims carry the endpoint's `permissions` and optional `channels` / `acl`,
+/// `exp` is mandatory.
+#[derive(Deserialize, Debug, Clone)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    /// Shared secret for HS256.
+    pub secret: Option<String>,
+    /// PEM public key for RS256 / ES256.
+    pub public_key_file: Option<PathBuf>,
+    /// JWKS file for RS256 / ES256, keys are selected by the token `kid`.
+    pub jwks_file: Option<PathBuf>,
+    /// Required `iss` claim.
+    pub issuer: Option<String>,
+    /// Required `aud` claim.
+    pub audience: Option<String>,
+    /// Clock skew tolerance when checking `exp`, also extending the connection past it
+    /// (default: 60 seconds)
+    pub leeway_seconds: Option<u64>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -20,8 +116,15 @@ pub struct ListenerConfig {
     pub port: u16,
     pub default_endpoint_permissions: Option<HashSet<Permission>>,
     /// Optional authentication tokens. If specified, clients must provide one of these tokens
-    /// in the Sec-WebSocket-Protocol header to connect.
+    /// in the Sec-WebSocket-Protocol header (or as an `Authorization: Bearer` header) to connect.
     pub auth_tokens: Option<HashSet<String>>,
+    /// Optional tokens with their own permissions and channel patterns. Endpoints authenticated
+    /// with one of these get its permissions instead of `default_endpoint_permissions`.
+    pub tokens: Option<Vec<TokenConfig>>,
+    /// Optional per permission channel restrictions for endpoints using the default permissions.
+    pub acl: Option<Vec<AclRuleConfig>>,
+    /// Optional JWT authentication, accepted alongside the static tokens.
+    pub jwt: Option<JwtConfig>,
     /// Maximum message size in bytes (default: 64KB)
     pub max_message_size: Option<usize>,
     /// Maximum frame size in bytes (default: 16MB)
@@ -30,8 +133,32 @@ pub struct ListenerConfig {
     pub max_connections: Option<usize>,
     /// Channel buffer size for backpressure control (default: unbounded, 0 = unbounded)
     pub channel_buffer_size: Option<usize>,
+    /// What to do when a subscriber's buffer is full (default: DropOldest)
+    pub slow_consumer_policy: Option<SlowConsumerPolicy>,
     /// Rate limit: messages per second per connection (default: no limit)
     pub rate_limit_per_second: Option<u32>,
+    /// Create channels implicitly: `Subscribe` creates a pending channel and `NotifyChannel`
+    /// creates it when the endpoint also holds `CreateChannel` (default: false)
+    pub auto_create_channels: Option<bool>,
+    /// PEM certificate chain, the listener serves `wss://` when set along with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of `tls_cert`
+    pub tls_key: Option<PathBuf>,
+    /// ALPN protocols offered in the TLS handshake, only "http/1.1" is supported
+    /// (default: ["http/1.1"])
+    pub tls_alpn: Option<Vec<String>>,
+    /// Seconds between checks for changed TLS files, which are then loaded again
+    /// (default: 10)
+    pub tls_reload_interval_secs: Option<u64>,
+    /// Seconds a client has to complete the TLS handshake (default: 10)
+    pub tls_handshake_timeout_secs: Option<u64>,
+    /// PEM CA bundle verifying client certificates (mutual TLS), reloaded like `tls_cert`
+    pub tls_client_ca: Option<PathBuf>,
+    /// Whether clients must present a certificate (default: Required)
+    pub tls_client_auth: Option<ClientAuth>,
+    /// Optional identities of clients by certificate name. A connection whose certificate
+    /// matches one gets its permissions, without a token.
+    pub client_certs: Option<Vec<ClientCertConfig>>,
 }
 
 impl ListenerConfig {
@@ -42,6 +169,59 @@ impl ListenerConfig {
     pub fn get_max_frame_size(&self) -> usize {
         self.max_frame_size.unwrap_or(16 * 1024 * 1024) // 16MB default
     }
+
+    pub fn get_tls_alpn(&self) -> Vec<String> {
+        self.tls_alpn
+            .clone()
+            .unwrap_or_else(|| vec![String::from("http/1.1")])
+    }
+
+    pub fn get_tls_reload_interval_secs(&self) -> u64 {
+        self.tls_reload_interval_secs.unwrap_or(10)
+    }
+
+    pub fn get_tls_handshake_timeout_secs(&self) -> u64 {
+        self.tls_handshake_timeout_secs.unwrap_or(10)
+    }
+}
+
+/// When the durable channel logs are flushed to disk.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every published message.
+    Always,
+    /// Every `fsync_interval_ms`.
+    #[default]
+    Periodic,
+    /// Leave it to the operating system.
+    Never,
+}
+
+/// Local disk storage of the channels created with `durable`, see `Settings::storage`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct StorageConfig {
+    /// Directory holding one sub directory of log segments per channel.
+    pub path: PathBuf,
+    /// Size at which a new segment is started (default: 16MB)
+    pub segment_bytes: Option<u64>,
+    /// Maximum size of a channel log, older segments are deleted (default: unlimited)
+    pub retention_bytes: Option<u64>,
+    /// Maximum age of a channel log, older segments are deleted (default: unlimited)
+    pub retention_seconds: Option<u64>,
+    /// When to flush the logs to disk (default: Periodic)
+    pub fsync: Option<FsyncPolicy>,
+    /// Interval of the `Periodic` fsync policy (default: 1000)
+    pub fsync_interval_ms: Option<u64>,
+}
+
+impl StorageConfig {
+    pub fn get_segment_bytes(&self) -> u64 {
+        self.segment_bytes.unwrap_or(16 * 1024 * 1024) // 16MB default
+    }
+
+    pub fn get_fsync_interval_ms(&self) -> u64 {
+        self.fsync_interval_ms.unwrap_or(1000)
+    }
 }
 
 const DEFAULT_TSLM_FILE_NAME: &str = "tslm";
@@ -49,6 +229,8 @@ const DEFAULT_TSLM_FILE_NAME: &str = "tslm";
 #[derive(Deserialize, Debug)]
 pub struct Settings {
     pub listener: Map<String, ListenerConfig>,
+    /// Optional storage for durable channels, shared by all the listeners.
+    pub storage: Option<StorageConfig>,
 }
 
 impl Settings {
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use common::message::ChannelId;
+
+use crate::settings::{AclRuleConfig, Permission};
+
+/// A glob pattern over channel ids.
+///
+/// `*` matches any run of characters (including none) and `?` matches exactly one
+/// character, so `prices.*` matches `prices.AAPL` and `orders.eu-?` matches `orders.eu-1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelPattern {
+    pattern: String,
+}
+
+impl ChannelPattern {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        ChannelPattern {
+            pattern: pattern.into(),
+        }
+    }
+
+    pub fn matches(&self, channel_id: &ChannelId) -> bool {
+        self.glob(channel_id, None)
+    }
+
+    /// Like `matches`, with `*` and `?` never matching a `.`, so wildcards stay within one
+    /// label as in DNS names: `*.example.com` matches `api.example.com` but not
+    /// `eu.api.example.com`.
+    pub fn matches_labels(&self, name: &str) -> bool {
+        self.glob(name, Some('.'))
+    }
+
+    fn glob(&self, candidate: &str, separator: Option<char>) -> bool {
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        let candidate: Vec<char> = candidate.chars().collect();
+        let wildcard = |ch: char| Some(ch) != separator;
+
+        // Iterative wildcard matching with single-star backtracking. A star cannot extend
+        // over a separator, and neither can an earlier star, since the separators in
+        // between were matched literally.
+        let (mut p, mut c) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+        while c < candidate.len() {
+            if p < pattern.len()
+                && (pattern[p] == candidate[c] || (pattern[p] == '?' && wildcard(candidate[c])))
+            {
+                p += 1;
+                c += 1;
+            } else if p < pattern.len() && pattern[p] == '*' {
+                star = Some((p, c));
+                p += 1;
+            } else if let Some((star_p, star_c)) = star
+                && wildcard(candidate[star_c])
+            {
+                p = star_p + 1;
+                c = star_c + 1;
+                star = Some((star_p, star_c + 1));
+            } else {
+                return false;
+            }
+        }
+        pattern[p..].iter().all(|ch| *ch == '*')
+    }
+}
+
+impl fmt::Display for ChannelPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Channel restrictions for an endpoint.
+///
+/// `channels` limits every command to the matching channels, while `rules` limit a single
+/// permission (e.g. `Subscribe` on `prices.*`). A permission without rules is only limited
+/// by `channels`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    channels: Option<Vec<ChannelPattern>>,
+    rules: HashMap<Permission, Vec<ChannelPattern>>,
+}
+
+impl Acl {
+    pub fn new(channels: Option<&Vec<String>>, rules: &[AclRuleConfig]) -> Self {
+        let mut acl = Acl {
+            channels: channels.map(|channels| channels.iter().map(ChannelPattern::new).collect()),
+            rules: HashMap::default(),
+        };
+        for rule in rules {
+            acl.rules
+                .entry(rule.permission.clone())
+                .or_default()
+                .extend(rule.channels.iter().map(ChannelPattern::new));
+        }
+        acl
+    }
+
+    /// This ACL restricted to `other`'s channels, if it has any, with `other`'s rules
+    /// replacing its own for the same permission.
+    pub fn overridden_by(&self, other: &Acl) -> Acl {
+        let mut rules = self.rules.clone();
+        rules.extend(other.rules.clone());
+        Acl {
+            channels: other.channels.clone().or_else(|| self.channels.clone()),
+            rules,
+        }
+    }
+
+    pub fn allows(&self, permission: &Permission, channel_id: &ChannelId) -> bool {
+        let matches_any =
+            |patterns: &Vec<ChannelPattern>| patterns.iter().any(|p| p.matches(channel_id));
+
+        if let Some(ref channels) = self.channels
+            && !matches_any(channels)
+        {
+            return false;
+        }
+        self.rules.get(permission).is_none_or(matches_any)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, channel_id: &str) -> bool {
+        ChannelPattern::new(pattern).matches(&channel_id.to_string())
+    }
+
+    #[test]
+    fn test_exact_match() {
+        assert!(matches("prices", "prices"));
+        assert!(!matches("prices", "prices.AAPL"));
+    }
+
+    #[test]
+    fn test_star_match() {
+        assert!(matches("prices.*", "prices.AAPL"));
+        assert!(matches("prices.*", "prices."));
+        assert!(matches("orders.eu-*", "orders.eu-west"));
+        assert!(matches("*", "anything"));
+        assert!(matches("*.eu.*", "orders.eu.fr"));
+        assert!(!matches("prices.*", "orders.AAPL"));
+        assert!(!matches("orders.eu-*", "orders.us-east"));
+    }
+
+    #[test]
+    fn test_label_match() {
+        let matches = |pattern: &str, name: &str| ChannelPattern::new(pattern).matches_labels(name);
+        assert!(matches("*.example.com", "api.example.com"));
+        assert!(!matches("*.example.com", "eu.api.example.com"));
+        assert!(matches("publisher-*", "publisher-eu"));
+        assert!(!matches("publisher-*", "publisher-eu.example.com"));
+        assert!(matches(
+            "spiffe://prod/publisher/*",
+            "spiffe://prod/publisher/eu"
+        ));
+        assert!(!matches("api?example.com", "api.example.com"));
+        assert!(matches("*-*.example.com", "a-b.example.com"));
+    }
+
+    #[test]
+    fn test_question_mark_match() {
+        assert!(matches("orders.eu-?", "orders.eu-1"));
+        assert!(!matches("orders.eu-?", "orders.eu-12"));
+    }
+
+    #[test]
+    fn test_acl_rules() {
+        let acl = Acl::new(
+            None,
+            &[
+                AclRuleConfig {
+                    permission: Permission::Subscribe,
+                    channels: vec![String::from("prices.*")],
+                },
+                AclRuleConfig {
+                    permission: Permission::NotifyChannel,
+                    channels: vec![String::from("orders.eu-*")],
+                },
+            ],
+        );
+        assert!(acl.allows(&Permission::Subscribe, &String::from("prices.AAPL")));
+        assert!(!acl.allows(&Permission::Subscribe, &String::from("orders.eu-1")));
+        assert!(acl.allows(&Permission::NotifyChannel, &String::from("orders.eu-1")));
+        assert!(!acl.allows(&Permission::NotifyChannel, &String::from("orders.us-1")));
+        // no rules for CreateChannel
+        assert!(acl.allows(&Permission::CreateChannel, &String::from("anything")));
+    }
+
+    #[test]
+    fn test_acl_channels() {
+        let channels = vec![String::from("prices.*")];
+        let acl = Acl::new(
+            Some(&channels),
+            &[AclRuleConfig {
+                permission: Permission::Subscribe,
+                channels: vec![String::from("*.AAPL")],
+            }],
+        );
+        assert!(acl.allows(&Permission::Subscribe, &String::from("prices.AAPL")));
+        assert!(!acl.allows(&Permission::Subscribe, &String::from("prices.MSFT")));
+        assert!(!acl.allows(&Permission::Subscribe, &String::from("orders.AAPL")));
+        assert!(acl.allows(&Permission::NotifyChannel, &String::from("prices.MSFT")));
+    }
+
+    #[test]
+    fn test_acl_overridden() {
+        let listener = Acl::new(
+            None,
+            &[
+                AclRuleConfig {
+                    permission: Permission::Subscribe,
+                    channels: vec![String::from("prices.*")],
+                },
+                AclRuleConfig {
+                    permission: Permission::NotifyChannel,
+                    channels: vec![String::from("prices.*")],
+                },
+            ],
+        );
+        let channels = vec![String::from("prices.*"), String::from("orders.*")];
+        let client = Acl::new(
+            Some(&channels),
+            &[AclRuleConfig {
+                permission: Permission::NotifyChannel,
+                channels: vec![String::from("orders.*")],
+            }],
+        );
+        let acl = listener.overridden_by(&client);
+        assert!(acl.allows(&Permission::Subscribe, &String::from("prices.AAPL")));
+        assert!(!acl.allows(&Permission::Subscribe, &String::from("orders.1")));
+        assert!(acl.allows(&Permission::NotifyChannel, &String::from("orders.1")));
+        assert!(!acl.allows(&Permission::NotifyChannel, &String::from("prices.AAPL")));
+        assert!(!acl.allows(&Permission::CreateChannel, &String::from("status")));
+
+        // without channels of its own, the client keeps the listener's
+        let listener = Acl::new(Some(&channels), &[]);
+        let client = Acl::new(
+            None,
+            &[AclRuleConfig {
+                permission: Permission::Subscribe,
+                channels: vec![String::from("*")],
+            }],
+        );
+        let acl = listener.overridden_by(&client);
+        assert!(acl.allows(&Permission::Subscribe, &String::from("orders.1")));
+        assert!(!acl.allows(&Permission::Subscribe, &String::from("status")));
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::debug;
+
+use tungstenite::handshake::server::Request;
+use tungstenite::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+
+use common::error::AppError;
+
+use crate::settings::{ClientCertConfig, Permission, TokenConfig};
+use crate::tslm::acl::{Acl, ChannelPattern};
+use crate::tslm::jwt::JwtVerifier;
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Permissions and channel restrictions an endpoint acts with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub permissions: HashSet<Permission>,
+    pub acl: Acl,
+    /// When the credentials expire, the connection is closed at that time.
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Identity {
+    pub fn new(permissions: HashSet<Permission>, acl: Acl) -> Self {
+        Identity {
+            permissions,
+            acl,
+            expires_at: None,
+        }
+    }
+}
+
+impl From<&TokenConfig> for Identity {
+    fn from(config: &TokenConfig) -> Self {
+        let rules = config.acl.as_deref().unwrap_or_default();
+        Identity::new(
+            config.permissions.clone(),
+            Acl::new(config.channels.as_ref(), rules),
+        )
+    }
+}
+
+/// A configured token, kept as its SHA-256 digest and compared in constant time, so how long
+/// a lookup takes tells nothing about how much of a presented token is right.
+struct TokenDigest([u8; 32]);
+
+impl TokenDigest {
+    fn new(token: &str) -> Self {
+        TokenDigest(Sha256::digest(token.as_bytes()).into())
+    }
+
+    fn matches(&self, other: &TokenDigest) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+
+    /// Whether one of the digests matches, every digest is compared.
+    fn contains(digests: &[TokenDigest], token: &TokenDigest) -> bool {
+        digests
+            .iter()
+            .fold(false, |found, digest| digest.matches(token) | found)
+    }
+
+    /// The value of the matching digest, every digest is compared. Like a map built from the
+    /// entries, the last one wins.
+    fn find<'a, T>(entries: &'a [(TokenDigest, T)], token: &TokenDigest) -> Option<&'a T> {
+        let mut found = None;
+        for (digest, value) in entries {
+            if digest.matches(token) {
+                found = Some(value);
+            }
+        }
+        found
+    }
+}
+
+/// The identity of the clients whose certificate has a matching name.
+struct ClientCertIdentity {
+    names: Vec<ChannelPattern>,
+    identity: Identity,
+}
+
+impl ClientCertIdentity {
+    fn new(config: &ClientCertConfig, default_identity: &Identity) -> Self {
+        let rules = config.acl.as_deref().unwrap_or_default();
+        let acl = Acl::new(config.channels.as_ref(), rules);
+        let identity = if config.extend_default_permissions.unwrap_or_default() {
+            Identity::new(
+                default_identity
+                    .permissions
+                    .union(&config.permissions)
+                    .cloned()
+                    .collect(),
+                default_identity.acl.overridden_by(&acl),
+            )
+        } else {
+            Identity::new(config.permissions.clone(), acl)
+        };
+        ClientCertIdentity {
+            names: config.names.iter().map(ChannelPattern::new).collect(),
+            identity,
+        }
+    }
+
+    fn matches(&self, certificate_names: &[String]) -> bool {
+        certificate_names.iter().any(|name| {
+            self.names
+                .iter()
+                .any(|pattern| pattern.matches_labels(name))
+        })
+    }
+}
+
+/// Result of authenticating a websocket upgrade request.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Authenticated {
+    pub identity: Identity,
+    /// The subprotocol that must be echoed back in the handshake response.
+    pub protocol: Option<String>,
+}
+
+/// Resolves the identity of the connections accepted by a listener.
+pub struct Authenticator {
+    default_identity: Identity,
+    auth_tokens: Option<Vec<TokenDigest>>,
+    tokens: Vec<(TokenDigest, Identity)>,
+    jwt: Option<JwtVerifier>,
+    client_certs: Vec<ClientCertIdentity>,
+}
+
+impl Authenticator {
+    pub fn new(
+        default_identity: Identity,
+        auth_tokens: Option<HashSet<String>>,
+        tokens: Vec<TokenConfig>,
+    ) -> Self {
+        let auth_tokens = auth_tokens.map(|auth_tokens| {
+            auth_tokens
+                .iter()
+                .map(|token| TokenDigest::new(token))
+                .collect()
+        });
+        let tokens = tokens
+            .iter()
+            .map(|config| (TokenDigest::new(&config.token), Identity::from(config)))
+            .collect();
+        Authenticator {
+            default_identity,
+            auth_tokens,
+            tokens,
+            jwt: None,
+            client_certs: Vec::new(),
+        }
+    }
+
+    /// Also accept JWTs verified by the given verifier.
+    pub fn with_jwt(mut self, jwt: JwtVerifier) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Also identify clients by their verified TLS certificate, the first matching
+    /// configuration applies.
+    pub fn with_client_certs(mut self, client_certs: &[ClientCertConfig]) -> Self {
+        self.client_certs = client_certs
+            .iter()
+            .map(|config| ClientCertIdentity::new(config, &self.default_identity))
+            .collect();
+        self
+    }
+
+    fn requires_token(&self) -> bool {
+        self.auth_tokens.is_some() || !self.tokens.is_empty() || self.jwt.is_some()
+    }
+
+    /// Identity granted to the given token, if the token is known or a valid JWT.
+    /// Plain `auth_tokens` get the listener's default identity.
+    fn resolve(&self, token: &str) -> Option<Identity> {
+        let digest = TokenDigest::new(token);
+        if let Some(identity) = TokenDigest::find(&self.tokens, &digest) {
+            return Some(identity.clone());
+        }
+        if let Some(ref auth_tokens) = self.auth_tokens
+            && TokenDigest::contains(auth_tokens, &digest)
+        {
+            return Some(self.default_identity.clone());
+        }
+        let jwt = self.jwt.as_ref()?;
+        jwt.verify(token)
+            .inspect_err(|err| debug!("Token rejected: {}", err))
+            .ok()
+    }
+
+    /// Check the upgrade request against the listener's configured tokens.
+    ///
+    /// Tokens are looked up first in the `Sec-WebSocket-Protocol` header (any of the offered
+    /// subprotocols) and then in an `Authorization: Bearer <token>` header. When a subprotocol
+    /// matched it is returned so the handshake can echo it, as required by RFC 6455.
+    /// Listeners without tokens accept every request with the default identity.
+    pub fn authenticate(&self, request: &Request) -> Result<Authenticated, AppError> {
+        if !self.requires_token() {
+            return Ok(Authenticated {
+                identity: self.default_identity.clone(),
+                protocol: None,
+            });
+        }
+
+        for protocol in offered_protocols(request) {
+            if let Some(identity) = self.resolve(&protocol) {
+                return Ok(Authenticated {
+                    identity,
+                    protocol: Some(protocol),
+                });
+            }
+        }
+
+        if let Some(identity) = bearer_token(request).and_then(|token| self.resolve(&token)) {
+            return Ok(Authenticated {
+                identity,
+                protocol: None,
+            });
+        }
+
+        Err(AppError::PermissionDenied(
+            "Missing or invalid authentication token".to_string(),
+        ))
+    }
+
+    /// Like `authenticate`, with the names of the client's verified TLS certificate, if any.
+    /// A certificate matching one of the `client_certs` stands in for a token.
+    pub fn authenticate_peer(
+        &self,
+        request: &Request,
+        certificate_names: &[String],
+    ) -> Result<Authenticated, AppError> {
+        match self
+            .client_certs
+            .iter()
+            .find(|client_cert| client_cert.matches(certificate_names))
+        {
+            Some(client_cert) => Ok(Authenticated {
+                identity: client_cert.identity.clone(),
+                protocol: None,
+            }),
+            None => self.authenticate(request),
+        }
+    }
+}
+
+/// All the subprotocols offered by the client, in order.
+pub(crate) fn offered_protocols(request: &Request) -> Vec<String> {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|protocol| protocol.trim().to_string())
+        .filter(|protocol| !protocol.is_empty())
+        .collect()
+}
+
+fn bearer_token(request: &Request) -> Option<String> {
+    let value = request.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix(BEARER_PREFIX)
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AclRuleConfig;
+
+    fn request_with(header: &str, value: &str) -> Request {
+        Request::builder()
+            .uri("ws://localhost:8080/")
+            .header(header, value)
+            .body(())
+            .unwrap()
+    }
+
+    fn subscriber() -> Identity {
+        Identity::new(HashSet::from([Permission::Subscribe]), Acl::default())
+    }
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(
+            subscriber(),
+            Some(HashSet::from([String::from("secret")])),
+            vec![TokenConfig {
+                token: String::from("publisher"),
+                permissions: HashSet::from([Permission::CreateChannel, Permission::NotifyChannel]),
+                channels: Some(vec![String::from("prices.*")]),
+                acl: None,
+            }],
+        )
+    }
+
+    #[test]
+    fn test_no_tokens_configured() {
+        let authenticator = Authenticator::new(subscriber(), None, vec![]);
+        let request = Request::builder().uri("/").body(()).unwrap();
+        let result = authenticator.authenticate(&request).unwrap();
+        assert_eq!(result.identity, subscriber());
+        assert!(result.protocol.is_none());
+    }
+
+    #[test]
+    fn test_subprotocol_token() {
+        let request = request_with("Sec-WebSocket-Protocol", "other, secret");
+        let result = authenticator().authenticate(&request).unwrap();
+        assert_eq!(result.identity, subscriber());
+        assert_eq!(result.protocol.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let request = request_with("Authorization", "Bearer secret");
+        let result = authenticator().authenticate(&request).unwrap();
+        assert!(result.identity.permissions.contains(&Permission::Subscribe));
+        assert!(result.protocol.is_none());
+    }
+
+    #[test]
+    fn test_token_identity() {
+        let request = request_with("Sec-WebSocket-Protocol", "publisher");
+        let identity = authenticator().authenticate(&request).unwrap().identity;
+        assert!(identity.permissions.contains(&Permission::NotifyChannel));
+        assert!(!identity.permissions.contains(&Permission::Subscribe));
+        assert!(
+            identity
+                .acl
+                .allows(&Permission::NotifyChannel, &String::from("prices.AAPL"))
+        );
+        assert!(
+            !identity
+                .acl
+                .allows(&Permission::NotifyChannel, &String::from("orders.1"))
+        );
+    }
+
+    #[test]
+    fn test_client_certificate_identity() {
+        let client_cert = |extend_default_permissions| ClientCertConfig {
+            names: vec![String::from("publisher-*")],
+            permissions: HashSet::from([Permission::NotifyChannel]),
+            channels: None,
+            acl: Some(vec![AclRuleConfig {
+                permission: Permission::NotifyChannel,
+                channels: vec![String::from("orders.*")],
+            }]),
+            extend_default_permissions: Some(extend_default_permissions),
+        };
+        let names = [String::from("localhost"), String::from("publisher-eu")];
+        let request = Request::builder().uri("/").body(()).unwrap();
+
+        let replacing = authenticator().with_client_certs(&[client_cert(false)]);
+        let authenticated = replacing.authenticate_peer(&request, &names).unwrap();
+        assert_eq!(
+            authenticated.identity.permissions,
+            HashSet::from([Permission::NotifyChannel])
+        );
+        assert!(
+            !authenticated
+                .identity
+                .acl
+                .allows(&Permission::NotifyChannel, &String::from("prices.AAPL"))
+        );
+        // an unknown certificate still needs a token
+        let names = [String::from("subscriber-1")];
+        assert!(replacing.authenticate_peer(&request, &names).is_err());
+        let request = request_with("Sec-WebSocket-Protocol", "secret");
+        assert_eq!(
+            replacing
+                .authenticate_peer(&request, &names)
+                .unwrap()
+                .identity,
+            subscriber()
+        );
+
+        let extending = authenticator().with_client_certs(&[client_cert(true)]);
+        let names = [String::from("publisher-us")];
+        let identity = extending
+            .authenticate_peer(&request, &names)
+            .unwrap()
+            .identity;
+        assert_eq!(
+            identity.permissions,
+            HashSet::from([Permission::Subscribe, Permission::NotifyChannel])
+        );
+        assert!(
+            identity
+                .acl
+                .allows(&Permission::NotifyChannel, &String::from("orders.1"))
+        );
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        // prefixes and extensions of a token are not that token
+        for token in ["wrong", "secre", "secrets", "publishe", ""] {
+            let request = request_with("Authorization", &format!("Bearer {}", token));
+            assert!(authenticator().authenticate(&request).is_err());
+        }
+
+        let request = Request::builder().uri("/").body(()).unwrap();
+        assert!(authenticator().authenticate(&request).is_err());
+    }
+}
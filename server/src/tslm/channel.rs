@@ -1,35 +1,375 @@
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::Duration;
 
 use tracing::error;
 
 use common::error::AppError;
-use common::message::{ChannelMessage, ClientCommand};
+use common::message::{ChannelMessage, ChannelOptions, ClientCommand, SubscribeOptions};
 
 use crate::tslm::endpoint::{Endpoint, EndpointId};
+use crate::tslm::filter::MessageFilter;
+use crate::tslm::history::History;
+use crate::tslm::outgoing::SharedMessage;
+use crate::tslm::projection::Projection;
+use crate::tslm::queue::ConflationKey;
+use crate::tslm::store::MessageStore;
 
 type ChannelId = common::message::ChannelId;
 
+/// Kept messages read at once when a subscriber resumes with `from_seq`.
+const REPLAY_BATCH_SIZE: usize = 256;
+
+/// A message projected once per distinct projection, for the subscribers sharing it.
+type Projected = HashMap<Arc<Projection>, Option<Arc<SharedMessage>>>;
+
+/// How a channel with `conflate_per_second` caps the updates of each subscriber.
+#[derive(Debug, Clone)]
+pub struct Conflation {
+    interval: Duration,
+    /// The `.` separated path of the JSON field keeping a latest message per value.
+    key: Option<Vec<String>>,
+}
+
+impl Conflation {
+    /// `None` when the channel is not conflated, fails with `InvalidMessage` on invalid
+    /// options.
+    pub fn from_options(options: &ChannelOptions) -> Result<Option<Self>, AppError> {
+        let per_second = match (options.conflate_per_second, options.conflate_key.as_ref()) {
+            (None, None) => return Ok(None),
+            (None, Some(_)) => {
+                return Err(AppError::InvalidMessage(String::from(
+                    "conflate_key requires conflate_per_second",
+                )));
+            }
+            (Some(0), _) => {
+                return Err(AppError::InvalidMessage(String::from(
+                    "conflate_per_second must be positive",
+                )));
+            }
+            (Some(per_second), _) => per_second,
+        };
+        let key = match options.conflate_key {
+            Some(ref key) => {
+                let path: Vec<String> = key.split('.').map(String::from).collect();
+                if path.iter().any(String::is_empty) {
+                    return Err(AppError::InvalidMessage(format!(
+                        "Invalid conflate_key '{}'",
+                        key
+                    )));
+                }
+                Some(path)
+            }
+            None => None,
+        };
+        Ok(Some(Conflation {
+            interval: Duration::from_secs(1) / per_second,
+            key,
+        }))
+    }
+
+    /// The key of a published message. Messages without the key field share one key.
+    fn key(&self, channel_id: &ChannelId, message: &ChannelMessage) -> ConflationKey {
+        let key = match (self.key.as_ref(), message) {
+            (Some(path), ChannelMessage::Json(value)) => path
+                .iter()
+                .try_fold(value, |value, field| value.as_object()?.get(field))
+                .map(|field| field.to_string()),
+            _ => None,
+        };
+        ConflationKey {
+            channel_id: channel_id.clone(),
+            key,
+        }
+    }
+}
+
+/// An endpoint subscribed to a channel, with what it asked to receive.
+#[derive(Clone)]
+pub struct Subscriber {
+    pub endpoint: Arc<Endpoint>,
+    filter: Option<Arc<MessageFilter>>,
+    projection: Option<Arc<Projection>>,
+}
+
+impl Subscriber {
+    /// Compile the subscription's filter and projection, failing with `InvalidMessage`
+    /// when one is invalid.
+    pub fn new(endpoint: Arc<Endpoint>, options: &SubscribeOptions) -> Result<Self, AppError> {
+        let filter = match options.filter {
+            Some(ref filter) => Some(Arc::new(MessageFilter::compile(filter)?)),
+            None => None,
+        };
+        let projection = match options.projection {
+            Some(ref fields) => Some(Arc::new(Projection::compile(fields)?)),
+            None => None,
+        };
+        Ok(Subscriber {
+            endpoint,
+            filter,
+            projection,
+        })
+    }
+
+    /// Send the message unless the subscriber filters it out, projected when the
+    /// subscriber has a projection. The filter sees the whole message. A conflated message
+    /// waits for the subscriber's next update of the channel.
+    fn send(
+        &self,
+        message: &Arc<SharedMessage>,
+        projected: &mut Projected,
+        conflated: Option<(&ConflationKey, Duration)>,
+    ) -> Result<(), AppError> {
+        let Some(message) = self.select(message, projected) else {
+            return Ok(());
+        };
+        match conflated {
+            Some((key, interval)) => self.endpoint.send_conflated(key.clone(), interval, message),
+            None => self.endpoint.send_shared(message),
+        }
+    }
+
+    /// Send replayed messages, filtered and projected as by `send`, all of them or none.
+    /// Returns whether they fit in the endpoint's queue, a replay does not go through the
+    /// slow-consumer policy.
+    fn replay(&self, messages: &[Arc<SharedMessage>]) -> Result<bool, AppError> {
+        let selected = messages
+            .iter()
+            .filter_map(|message| self.select(message, &mut Projected::new()))
+            .collect();
+        self.endpoint.send_replayed(selected)
+    }
+
+    /// The message as the subscriber receives it, `None` when filtered out.
+    fn select(
+        &self,
+        message: &Arc<SharedMessage>,
+        projected: &mut Projected,
+    ) -> Option<Arc<SharedMessage>> {
+        if let Some(ref filter) = self.filter
+            && !message.message().is_some_and(|m| filter.matches(m))
+        {
+            return None;
+        }
+        let message = match self.projection {
+            Some(ref projection) => projected
+                .entry(Arc::clone(projection))
+                .or_insert_with(|| projection.apply(message).map(Arc::new))
+                .as_ref()
+                .unwrap_or(message),
+            None => message,
+        };
+        Some(Arc::clone(message))
+    }
+}
+
 pub struct Channel {
     pub channel_id: ChannelId,
-    subscriptions: RwLock<BTreeMap<EndpointId, Arc<Endpoint>>>,
+    /// The endpoint whose disconnection deletes this channel, if any.
+    owner: RwLock<Option<EndpointId>>,
+    /// Implicitly created by a subscriber and not yet created or published to by anyone.
+    pending: AtomicBool,
+    /// Keep the last published message for new subscribers.
+    retain_last: AtomicBool,
+    /// Kept in durable storage, so publishing and replaying do file IO.
+    durable: AtomicBool,
+    /// Cap the updates of each subscriber, when `conflate_per_second` is set.
+    conflation: RwLock<Option<Conflation>>,
+    /// The last published message as a snapshot, when `retain_last` is set.
+    retained: RwLock<Option<Arc<SharedMessage>>>,
+    /// Sequence numbers and kept messages. Held while publishing, so every subscriber gets
+    /// the messages in sequence order.
+    history: Mutex<History>,
+    subscriptions: RwLock<BTreeMap<EndpointId, Subscriber>>,
 }
 
 impl Channel {
     pub fn new(channel_id: ChannelId) -> Self {
         Channel {
             channel_id,
+            owner: RwLock::new(None),
+            pending: AtomicBool::new(false),
+            retain_last: AtomicBool::new(false),
+            durable: AtomicBool::new(false),
+            conflation: RwLock::new(None),
+            retained: RwLock::new(None),
+            history: Mutex::new(History::new(None)),
             subscriptions: RwLock::new(BTreeMap::default()),
         }
     }
 
-    pub fn subscribe(&self, endpoint: Arc<Endpoint>) -> Result<(), AppError> {
+    /// A channel created by a subscriber before any publisher.
+    pub fn new_pending(channel_id: ChannelId) -> Self {
+        let channel = Channel::new(channel_id);
+        channel.pending.store(true, Ordering::SeqCst);
+        channel
+    }
+
+    /// Tie the channel lifetime to the given endpoint.
+    pub fn owned_by(self, owner: EndpointId) -> Self {
+        if let Ok(mut current) = self.owner.write() {
+            *current = Some(owner);
+        }
+        self
+    }
+
+    /// Apply the message retention and conflation options the channel was created with,
+    /// keeping its messages in the given store. The store may already hold messages, e.g. when a
+    /// durable channel is recovered.
+    pub fn with_options(
+        self,
+        options: &ChannelOptions,
+        store: Option<Box<dyn MessageStore>>,
+    ) -> Result<Self, AppError> {
+        self.retain(options, store)?;
+        Ok(self)
+    }
+
+    fn retain(
+        &self,
+        options: &ChannelOptions,
+        store: Option<Box<dyn MessageStore>>,
+    ) -> Result<(), AppError> {
+        *self.conflation.write()? = Conflation::from_options(options)?;
+        self.retain_last
+            .store(options.retain_last, Ordering::SeqCst);
+        self.durable.store(options.durable, Ordering::SeqCst);
+        let mut history = self.history.lock()?;
+        history.set_store(store);
+        if options.retain_last {
+            *self.retained.write()? = history.last()?.and_then(|last| match last.command() {
+                ClientCommand::ChannelMessage(channel_id, message, seq) => {
+                    Some(Arc::new(SharedMessage::new(
+                        ClientCommand::ChannelSnapshot(channel_id.clone(), message.clone(), *seq),
+                    )))
+                }
+                _ => None,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn owner(&self) -> Option<EndpointId> {
+        self.owner.read().ok().and_then(|owner| *owner)
+    }
+
+    pub fn is_durable(&self) -> bool {
+        self.durable.load(Ordering::SeqCst)
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Turn a pending channel into a regular one, returns whether it was pending.
+    /// The store is only opened when the channel was pending. When it cannot be opened the
+    /// channel stays pending, without an owner.
+    pub fn claim(
+        &self,
+        owner: Option<EndpointId>,
+        options: &ChannelOptions,
+        open_store: impl FnOnce() -> Result<Option<Box<dyn MessageStore>>, AppError>,
+    ) -> Result<bool, AppError> {
+        if !self.pending.swap(false, Ordering::SeqCst) {
+            return Ok(false);
+        }
+        if let Err(err) = open_store().and_then(|store| self.retain(options, store)) {
+            self.pending.store(true, Ordering::SeqCst);
+            return Err(err);
+        }
+        if owner.is_some() {
+            *self.owner.write()? = owner;
+        }
+        Ok(true)
+    }
+
+    /// Subscribe the endpoint, first sending it the kept messages from `from_seq` on or,
+    /// without `from_seq`, the retained message if there is one. The subscriber's filter
+    /// applies to those too.
+    ///
+    /// The kept messages are replayed in batches of `REPLAY_BATCH_SIZE`, publishers only wait
+    /// for one batch at a time. When retention evicts the next batch before it is read, or
+    /// when the endpoint's queue has no room for it, the subscription fails with
+    /// `SequenceGap` after the messages already replayed, rather than let the slow-consumer
+    /// policy drop some of them.
+    pub fn subscribe(
+        &self,
+        endpoint: Arc<Endpoint>,
+        options: &SubscribeOptions,
+    ) -> Result<(), AppError> {
+        let subscriber = Subscriber::new(endpoint, options)?;
+        let mut from_seq = options.from_seq;
+        loop {
+            // Holding the history lock keeps publishers out, so the endpoint gets either the
+            // replayed or retained copy of a message or the message itself, never both.
+            let mut history = self.history.lock()?;
+            let batch = match from_seq {
+                Some(seq) => history.replay_from(seq, REPLAY_BATCH_SIZE)?,
+                None => Vec::new(),
+            };
+            let next_seq = batch
+                .last()
+                .and_then(|message| message.sequence())
+                .map(|last_seq| last_seq + 1)
+                .or(from_seq);
+            let live_seq = history.next_seq();
+            let no_room = |requested| AppError::SequenceGap {
+                requested,
+                oldest: live_seq,
+            };
+            if next_seq.is_some_and(|next_seq| next_seq < live_seq) {
+                // More to replay, published meanwhile or beyond this batch.
+                drop(history);
+                if !subscriber.replay(&batch)? {
+                    return Err(no_room(from_seq.unwrap_or(live_seq)));
+                }
+                from_seq = next_seq;
+                continue;
+            }
+
+            let mut subscriptions = self.subscriptions.write()?;
+            if !subscriber.replay(&batch)? {
+                return Err(no_room(from_seq.unwrap_or(live_seq)));
+            }
+            if from_seq.is_none()
+                && let Some(ref snapshot) = *self.retained.read()?
+            {
+                subscriber.send(snapshot, &mut Projected::new(), None)?;
+            }
+            let _ = subscriptions.insert(subscriber.endpoint.id, subscriber);
+            return Ok(());
+        }
+    }
+
+    /// Subscribe the endpoint of a pattern subscription, unless it already is subscribed
+    /// through another one or by channel id. Returns whether it was attached.
+    pub fn attach(&self, subscriber: Subscriber) -> Result<bool, AppError> {
+        let _history = self.history.lock()?;
         let mut subscriptions = self.subscriptions.write()?;
-        let _ = subscriptions.insert(endpoint.id, endpoint);
+        if subscriptions.contains_key(&subscriber.endpoint.id) {
+            return Ok(false);
+        }
+        if let Some(ref snapshot) = *self.retained.read()? {
+            subscriber.send(snapshot, &mut Projected::new(), None)?;
+        }
+        let _ = subscriptions.insert(subscriber.endpoint.id, subscriber);
+        Ok(true)
+    }
+
+    /// Replace the endpoint's subscriber, e.g. by the one of its pattern subscription once
+    /// the subscription by channel id, with its own filter and projection, is gone.
+    pub fn replace_subscriber(&self, subscriber: Subscriber) -> Result<(), AppError> {
+        let _history = self.history.lock()?;
+        let mut subscriptions = self.subscriptions.write()?;
+        let _ = subscriptions.insert(subscriber.endpoint.id, subscriber);
         Ok(())
     }
 
-    #[allow(dead_code)]
+    pub fn subscriber_count(&self) -> usize {
+        self.subscriptions.read().map_or(0, |s| s.len())
+    }
+
     pub fn unsubscribe(&self, endpoint_id: &EndpointId) -> Result<(), AppError> {
         let mut subscriptions = self.subscriptions.write()?;
         self.unsubscribe_guarded(endpoint_id, &mut subscriptions)
@@ -38,31 +378,77 @@ impl Channel {
     fn unsubscribe_guarded(
         &self,
         endpoint_id: &EndpointId,
-        subscriptions: &mut RwLockWriteGuard<BTreeMap<EndpointId, Arc<Endpoint>>>,
+        subscriptions: &mut RwLockWriteGuard<BTreeMap<EndpointId, Subscriber>>,
     ) -> Result<(), AppError> {
         let _ = subscriptions.remove(endpoint_id);
         Ok(())
     }
 
+    /// Drop all the subscribers, notifying them that the channel is gone, and delete the
+    /// kept messages.
+    pub fn close(&self) -> Result<(), AppError> {
+        let mut history = self.history.lock()?;
+        let mut subscriptions = self.subscriptions.write()?;
+        for Subscriber { endpoint, .. } in subscriptions.values() {
+            let _ = endpoint.remove_subscription(&self.channel_id);
+            let _ = endpoint.send(ClientCommand::ChannelClosed(self.channel_id.clone()));
+        }
+        subscriptions.clear();
+        history.remove()
+    }
+
+    /// Flush the kept messages to durable storage.
+    pub fn sync(&self) -> Result<(), AppError> {
+        self.history.lock()?.sync()
+    }
+
     pub fn publish(&self, message: ChannelMessage) -> Result<(), AppError> {
+        // The first publish makes an implicitly created channel a regular one.
+        self.pending.store(false, Ordering::SeqCst);
         let mut prune = Vec::<EndpointId>::default();
 
         // Fan out message to all subscribers
         {
+            let mut history = self.history.lock()?;
+            let seq = history.next_seq();
+            let snapshot = self.retain_last.load(Ordering::SeqCst).then(|| {
+                Arc::new(SharedMessage::new(ClientCommand::ChannelSnapshot(
+                    self.channel_id.clone(),
+                    message.clone(),
+                    seq,
+                )))
+            });
+            let conflated = self.conflation.read()?.as_ref().map(|conflation| {
+                (
+                    conflation.key(&self.channel_id, &message),
+                    conflation.interval,
+                )
+            });
+            // Encoded once per protocol and shared, not once per subscriber.
+            let shared = Arc::new(SharedMessage::new(ClientCommand::ChannelMessage(
+                self.channel_id.clone(),
+                message,
+                seq,
+            )));
+            // A message that cannot be kept is not published.
+            history.push(&shared)?;
+            if snapshot.is_some() {
+                *self.retained.write()? = snapshot;
+            }
+
             let subscriptions = self.subscriptions.read()?;
-            let client_cmd = ClientCommand::ChannelMessage(self.channel_id.clone(), message);
+            let mut projected = Projected::new();
 
-            for (id, endpoint) in subscriptions.iter() {
-                // need to make a copy for each
-                match endpoint.send(client_cmd.clone()) {
+            for (id, subscriber) in subscriptions.iter() {
+                let conflated = conflated.as_ref().map(|(key, interval)| (key, *interval));
+                match subscriber.send(&shared, &mut projected, conflated) {
                     Ok(_) => {
                         // debug!("Sent msg correctly.");
                     }
                     Err(_err) => {
                         // On any send error unsubscribe the endpoint from the channel.
-                        // TODO
-                        //  Design Decision: Should notify hub to unregister the endpoint?
-                        //  Not unregistering the endpoint from the hub for now.
+                        // The connection handler unregisters the endpoint from the directory.
+                        let _ = subscriber.endpoint.remove_subscription(&self.channel_id);
                         prune.push(*id);
                     }
                 }
@@ -91,9 +477,13 @@ impl Channel {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::settings::SlowConsumerPolicy;
+    use crate::tslm::auth::Identity;
     use crate::tslm::directory::Directory;
     use crate::tslm::endpoint::Endpoint;
-    use std::collections::HashSet;
+    use crate::tslm::hub::EndpointFactorySettings;
+    use crate::tslm::outgoing::Outgoing;
+    use crate::tslm::store::MemoryStore;
 
     #[test]
     fn test_channel_creation() {
@@ -105,23 +495,56 @@ mod tests {
     fn test_subscribe_endpoint() {
         let directory = Arc::new(Directory::new());
         let channel = Channel::new(String::from("test_channel"));
-        let permissions = HashSet::new();
+        let identity = Identity::default();
 
-        let (endpoint, _rx) = Endpoint::new(1, directory, permissions);
-        let result = channel.subscribe(endpoint);
+        let (endpoint, _rx) =
+            Endpoint::new(1, directory, identity, &EndpointFactorySettings::default());
+        let result = channel.subscribe(endpoint, &SubscribeOptions::default());
 
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_close_channel() {
+        let directory = Arc::new(Directory::new());
+        let channel = Channel::new(String::from("test_channel"));
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        channel
+            .subscribe(endpoint, &SubscribeOptions::default())
+            .unwrap();
+        channel.close().unwrap();
+
+        assert_eq!(channel.subscriber_count(), 0);
+        match rx.try_recv() {
+            Some(Outgoing::Command(ClientCommand::ChannelClosed(id))) => {
+                assert_eq!(id, "test_channel")
+            }
+            _ => panic!("Expected ChannelClosed"),
+        }
+    }
+
     #[test]
     fn test_publish_message() {
         let directory = Arc::new(Directory::new());
         let channel = Channel::new(String::from("test_channel"));
-        let permissions = HashSet::new();
+        let identity = Identity::default();
 
-        let (endpoint, mut rx) = Endpoint::new(1, Arc::clone(&directory), permissions);
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            identity,
+            &EndpointFactorySettings::default(),
+        );
         directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
-        channel.subscribe(endpoint).unwrap();
+        channel
+            .subscribe(endpoint, &SubscribeOptions::default())
+            .unwrap();
 
         let message = ChannelMessage::Text(String::from("test message"));
         let result = channel.publish(message.clone());
@@ -130,6 +553,370 @@ mod tests {
 
         // Check that message was received
         let received = rx.try_recv();
-        assert!(received.is_ok());
+        assert!(received.is_some());
+    }
+
+    #[test]
+    fn test_publish_filtered() {
+        let directory = Arc::new(Directory::new());
+        let channel = Channel::new(String::from("test_channel"));
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        let options = SubscribeOptions {
+            filter: Some(String::from(r#"symbol == "AAPL" && price > 100"#)),
+            ..SubscribeOptions::default()
+        };
+        channel.subscribe(endpoint, &options).unwrap();
+
+        for (symbol, price) in [("MSFT", 300), ("AAPL", 99), ("AAPL", 101)] {
+            channel
+                .publish(ChannelMessage::Json(
+                    serde_json::json!({"symbol": symbol, "price": price}),
+                ))
+                .unwrap();
+        }
+
+        // only the matching message, with its sequence number in the channel
+        match rx.try_recv() {
+            Some(Outgoing::Shared(shared)) => {
+                assert!(matches!(
+                    shared.command(),
+                    ClientCommand::ChannelMessage(_, _, 3)
+                ));
+            }
+            _ => panic!("Expected the third message"),
+        }
+        assert!(rx.try_recv().is_none());
+
+        // invalid filters are rejected when subscribing
+        let (other, _rx) = Endpoint::new(
+            2,
+            directory,
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        let invalid = SubscribeOptions {
+            filter: Some(String::from("symbol ==")),
+            ..SubscribeOptions::default()
+        };
+        assert!(matches!(
+            channel.subscribe(other, &invalid),
+            Err(AppError::InvalidMessage(_))
+        ));
+        assert_eq!(channel.subscriber_count(), 1);
+    }
+
+    #[test]
+    fn test_publish_projected() {
+        let directory = Arc::new(Directory::new());
+        let channel = Channel::new(String::from("test_channel"));
+        let settings = EndpointFactorySettings::default();
+
+        let (first, mut first_rx) =
+            Endpoint::new(1, Arc::clone(&directory), Identity::default(), &settings);
+        let (second, mut second_rx) =
+            Endpoint::new(2, Arc::clone(&directory), Identity::default(), &settings);
+        let projection = |fields: &[&str]| SubscribeOptions {
+            projection: Some(fields.iter().map(|field| field.to_string()).collect()),
+            ..SubscribeOptions::default()
+        };
+        channel
+            .subscribe(first, &projection(&["symbol", "quote.bid"]))
+            .unwrap();
+        channel
+            .subscribe(second, &projection(&["/quote/bid", "symbol"]))
+            .unwrap();
+
+        channel
+            .publish(ChannelMessage::Json(serde_json::json!({
+                "symbol": "AAPL",
+                "quote": {"bid": 101, "ask": 102},
+            })))
+            .unwrap();
+
+        match (first_rx.try_recv(), second_rx.try_recv()) {
+            (Some(Outgoing::Shared(first)), Some(Outgoing::Shared(second))) => {
+                // identical projections are projected once
+                assert!(Arc::ptr_eq(&first, &second));
+                match first.message() {
+                    Some(ChannelMessage::Json(value)) => assert_eq!(
+                        *value,
+                        serde_json::json!({"symbol": "AAPL", "quote": {"bid": 101}})
+                    ),
+                    _ => panic!("Expected a JSON message"),
+                }
+            }
+            _ => panic!("Expected shared messages"),
+        }
+    }
+
+    #[test]
+    fn test_publish_conflated_by_key() {
+        let directory = Arc::new(Directory::new());
+        let options = ChannelOptions {
+            conflate_per_second: Some(10),
+            conflate_key: Some(String::from("symbol")),
+            ..ChannelOptions::default()
+        };
+        let channel = Channel::new(String::from("test_channel"))
+            .with_options(&options, None)
+            .unwrap();
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            directory,
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        channel
+            .subscribe(endpoint, &SubscribeOptions::default())
+            .unwrap();
+        for (symbol, price) in [("AAPL", 1), ("MSFT", 2), ("AAPL", 3)] {
+            channel
+                .publish(ChannelMessage::Json(
+                    serde_json::json!({"symbol": symbol, "price": price}),
+                ))
+                .unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Some(Outgoing::Shared(shared)) = rx.try_recv() {
+            if let ClientCommand::ChannelMessage(_, _, seq) = shared.command() {
+                received.push(*seq);
+            }
+        }
+        // the latest AAPL and MSFT messages
+        assert_eq!(received, vec![3, 2]);
+
+        let invalid = ChannelOptions {
+            conflate_key: Some(String::from("symbol")),
+            ..ChannelOptions::default()
+        };
+        assert!(Conflation::from_options(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_publish_shares_message() {
+        let directory = Arc::new(Directory::new());
+        let channel = Channel::new(String::from("test_channel"));
+        let settings = EndpointFactorySettings::default();
+
+        let (first, mut first_rx) =
+            Endpoint::new(1, Arc::clone(&directory), Identity::default(), &settings);
+        let (second, mut second_rx) =
+            Endpoint::new(2, Arc::clone(&directory), Identity::default(), &settings);
+        channel
+            .subscribe(first, &SubscribeOptions::default())
+            .unwrap();
+        channel
+            .subscribe(second, &SubscribeOptions::default())
+            .unwrap();
+
+        channel
+            .publish(ChannelMessage::Text(String::from("test message")))
+            .unwrap();
+
+        match (first_rx.try_recv(), second_rx.try_recv()) {
+            (Some(Outgoing::Shared(a)), Some(Outgoing::Shared(b))) => assert!(Arc::ptr_eq(&a, &b)),
+            _ => panic!("Expected a shared message"),
+        }
+    }
+
+    #[test]
+    fn test_retain_last_message() {
+        let directory = Arc::new(Directory::new());
+        let channel = Channel::new(String::from("test_channel"))
+            .with_options(
+                &ChannelOptions {
+                    retain_last: true,
+                    ..ChannelOptions::default()
+                },
+                None,
+            )
+            .unwrap();
+        let settings = EndpointFactorySettings::default();
+
+        channel
+            .publish(ChannelMessage::Text(String::from("first")))
+            .unwrap();
+        channel
+            .publish(ChannelMessage::Text(String::from("last")))
+            .unwrap();
+
+        let (endpoint, mut rx) =
+            Endpoint::new(1, Arc::clone(&directory), Identity::default(), &settings);
+        channel
+            .subscribe(endpoint, &SubscribeOptions::default())
+            .unwrap();
+
+        match rx.try_recv() {
+            Some(Outgoing::Shared(shared)) => match shared.command() {
+                ClientCommand::ChannelSnapshot(id, ChannelMessage::Text(text), seq) => {
+                    assert_eq!(id, "test_channel");
+                    assert_eq!(text, "last");
+                    assert_eq!(*seq, 2);
+                }
+                _ => panic!("Expected a snapshot"),
+            },
+            _ => panic!("Expected a shared message"),
+        }
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_resume_from_sequence() {
+        let directory = Arc::new(Directory::new());
+        let options = ChannelOptions {
+            history_size: Some(2),
+            ..ChannelOptions::default()
+        };
+        let store = MemoryStore::new(&options).map(|store| Box::new(store) as _);
+        let channel = Channel::new(String::from("test_channel"))
+            .with_options(&options, store)
+            .unwrap();
+        let settings = EndpointFactorySettings::default();
+        for text in ["1", "2", "3"] {
+            channel
+                .publish(ChannelMessage::Text(String::from(text)))
+                .unwrap();
+        }
+
+        let (endpoint, mut rx) =
+            Endpoint::new(1, Arc::clone(&directory), Identity::default(), &settings);
+        let from_seq = |seq| SubscribeOptions {
+            from_seq: Some(seq),
+            ..SubscribeOptions::default()
+        };
+        // the first message was evicted
+        assert!(matches!(
+            channel.subscribe(Arc::clone(&endpoint), &from_seq(1)),
+            Err(AppError::SequenceGap { oldest: 2, .. })
+        ));
+        assert_eq!(channel.subscriber_count(), 0);
+
+        channel.subscribe(endpoint, &from_seq(2)).unwrap();
+        channel
+            .publish(ChannelMessage::Text(String::from("4")))
+            .unwrap();
+        for expected in 2..=4 {
+            match rx.try_recv() {
+                Some(Outgoing::Shared(shared)) => match shared.command() {
+                    ClientCommand::ChannelMessage(_, ChannelMessage::Text(text), seq) => {
+                        assert_eq!(*seq, expected);
+                        assert_eq!(*text, expected.to_string());
+                    }
+                    _ => panic!("Expected a channel message"),
+                },
+                _ => panic!("Expected a shared message"),
+            }
+        }
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_replay_larger_than_queue() {
+        let directory = Arc::new(Directory::new());
+        let options = ChannelOptions {
+            history_size: Some(10),
+            ..ChannelOptions::default()
+        };
+        let store = MemoryStore::new(&options).map(|store| Box::new(store) as _);
+        let channel = Channel::new(String::from("test_channel"))
+            .with_options(&options, store)
+            .unwrap();
+        for seq in 1..=5 {
+            channel
+                .publish(ChannelMessage::Text(seq.to_string()))
+                .unwrap();
+        }
+        let from_seq = |seq| SubscribeOptions {
+            from_seq: Some(seq),
+            ..SubscribeOptions::default()
+        };
+
+        for policy in [
+            SlowConsumerPolicy::DropOldest,
+            SlowConsumerPolicy::DropNewest,
+            SlowConsumerPolicy::Disconnect,
+        ] {
+            let settings = EndpointFactorySettings {
+                channel_buffer_size: Some(3),
+                slow_consumer_policy: policy,
+                ..EndpointFactorySettings::default()
+            };
+            let (endpoint, mut rx) =
+                Endpoint::new(1, Arc::clone(&directory), Identity::default(), &settings);
+            // five messages do not fit, none is dropped and the resume fails
+            assert!(matches!(
+                channel.subscribe(Arc::clone(&endpoint), &from_seq(1)),
+                Err(AppError::SequenceGap {
+                    requested: 1,
+                    oldest: 6
+                })
+            ));
+            assert!(rx.try_recv().is_none());
+            assert!(!rx.overflowed());
+            assert_eq!(endpoint.dropped_messages(), 0);
+
+            // three do
+            channel.subscribe(endpoint, &from_seq(3)).unwrap();
+            for expected in 3..=5 {
+                match rx.try_recv() {
+                    Some(Outgoing::Shared(shared)) => {
+                        assert_eq!(shared.sequence(), Some(expected))
+                    }
+                    _ => panic!("Expected a shared message"),
+                }
+            }
+            channel.unsubscribe(&1).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_resume_across_replay_batches() {
+        let directory = Arc::new(Directory::new());
+        let options = ChannelOptions {
+            history_size: Some(1000),
+            ..ChannelOptions::default()
+        };
+        let store = MemoryStore::new(&options).map(|store| Box::new(store) as _);
+        let channel = Channel::new(String::from("test_channel"))
+            .with_options(&options, store)
+            .unwrap();
+        let published = 2 * REPLAY_BATCH_SIZE as u64 + 10;
+        for seq in 1..=published {
+            channel
+                .publish(ChannelMessage::Text(seq.to_string()))
+                .unwrap();
+        }
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        let options = SubscribeOptions {
+            from_seq: Some(1),
+            ..SubscribeOptions::default()
+        };
+        channel.subscribe(endpoint, &options).unwrap();
+        channel
+            .publish(ChannelMessage::Text(String::from("last")))
+            .unwrap();
+        for expected in 1..=published + 1 {
+            match rx.try_recv() {
+                Some(Outgoing::Shared(shared)) => {
+                    assert_eq!(shared.sequence(), Some(expected));
+                }
+                _ => panic!("Expected a shared message"),
+            }
+        }
+        assert!(rx.try_recv().is_none());
     }
 }
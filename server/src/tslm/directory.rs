@@ -1,14 +1,28 @@
-use crate::tslm::channel::Channel;
+use crate::tslm::channel::{Channel, Conflation, Subscriber};
 use common::error::AppError;
-use common::message::ChannelId;
+use common::message::{ChannelId, ChannelOptions, SubscribeOptions, TerminalStreamCommand};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+
+use tracing::{debug, error, info};
 
 use crate::tslm::endpoint::{Endpoint, EndpointId};
+use crate::tslm::pattern::SubjectPattern;
+use crate::tslm::store::{FileStorage, MemoryStore, MessageStore};
+
+type PatternSubscribers = HashMap<SubjectPattern, HashMap<EndpointId, Subscriber>>;
 
 pub struct Directory {
     channels_by_id: RwLock<HashMap<ChannelId, Arc<Channel>>>,
     endpoints_by_id: RwLock<HashMap<EndpointId, Arc<Endpoint>>>,
+    /// Endpoints subscribed to patterns, attached to every matching channel including the
+    /// ones created later. Locked after `channels_by_id`.
+    patterns: RwLock<PatternSubscribers>,
+    /// Where durable channels keep their messages, they cannot be created without it.
+    storage: Option<FileStorage>,
+    /// Held while a durable channel being created opens its log, so racing creates of one
+    /// channel never both write it.
+    creating: Mutex<()>,
 }
 
 impl Directory {
@@ -16,6 +30,60 @@ impl Directory {
         Directory {
             channels_by_id: RwLock::new(HashMap::default()),
             endpoints_by_id: RwLock::new(HashMap::default()),
+            patterns: RwLock::new(HashMap::default()),
+            storage: None,
+            creating: Mutex::new(()),
+        }
+    }
+
+    /// A directory keeping durable channels in the given storage, starting with the
+    /// channels found there.
+    pub fn with_storage(storage: FileStorage) -> Result<Self, AppError> {
+        let mut channels = HashMap::default();
+        for (channel_id, options, store) in storage.recover()? {
+            let channel =
+                Channel::new(channel_id.clone()).with_options(&options, Some(Box::new(store)))?;
+            info!("Recovered durable channel '{}'", channel_id);
+            channels.insert(channel_id, Arc::new(channel));
+        }
+        Ok(Directory {
+            channels_by_id: RwLock::new(channels),
+            endpoints_by_id: RwLock::new(HashMap::default()),
+            patterns: RwLock::new(HashMap::default()),
+            storage: Some(storage),
+            creating: Mutex::new(()),
+        })
+    }
+
+    pub fn storage(&self) -> Option<&FileStorage> {
+        self.storage.as_ref()
+    }
+
+    /// Whether the command may read or write the durable storage. Such commands block on file
+    /// IO, so the connections run them off the async workers.
+    pub fn uses_storage(&self, command: &TerminalStreamCommand) -> bool {
+        if self.storage.is_none() {
+            return false;
+        }
+        match command {
+            TerminalStreamCommand::CreateChannelWithOptions(_, options) => options.durable,
+            command if SubjectPattern::is_pattern(command.channel_id()) => true,
+            command => self
+                .find_channel(command.channel_id())
+                .is_some_and(|channel| channel.is_durable()),
+        }
+    }
+
+    /// Flush the logs of the durable channels to disk.
+    pub fn sync(&self) {
+        let channels: Vec<Arc<Channel>> = match self.channels_by_id.read() {
+            Ok(channels) => channels.values().map(Arc::clone).collect(),
+            Err(_) => return,
+        };
+        for channel in channels {
+            if let Err(err) = channel.sync() {
+                error!("Error syncing channel '{}': {}", channel.channel_id, err);
+            }
         }
     }
 
@@ -32,14 +100,59 @@ impl Directory {
         Ok(())
     }
 
+    /// Unregister the endpoint and remove it from every channel it is subscribed to,
+    /// so quiet channels do not keep disconnected endpoints alive.
     pub fn unregister_endpoint(&self, endpoint_id: &EndpointId) -> Result<(), AppError> {
-        let mut endpoints = self.endpoints_by_id.write()?;
-        let _ = endpoints.remove(endpoint_id);
-        // TODO
-        //  Design Decision: unregistering endpoints do not tell channels to unsubscribe the endpoint.
-        //  The way it is designed now channels will fail to send a message and de-subscribe the
-        //  endpoints on their own.
-        //  Also means that a channel can detect a disconnection earlier than the hub.
+        let endpoint = {
+            let mut endpoints = self.endpoints_by_id.write()?;
+            endpoints.remove(endpoint_id)
+        };
+        let Some(endpoint) = endpoint else {
+            return Ok(());
+        };
+
+        let mut channel_ids = endpoint.take_subscriptions()?;
+        let patterns = endpoint.take_patterns()?;
+        if !patterns.is_empty() {
+            let mut subscribers = self.patterns.write()?;
+            for pattern in &patterns {
+                Self::remove_pattern_subscriber(&mut subscribers, pattern, endpoint_id);
+            }
+            drop(subscribers);
+            let channels = self.channels_by_id.read()?;
+            channel_ids.extend(
+                channels
+                    .keys()
+                    .filter(|channel_id| patterns.iter().any(|pattern| pattern.matches(channel_id)))
+                    .cloned(),
+            );
+        }
+
+        for channel_id in channel_ids {
+            if let Some(channel) = self.find_channel(&channel_id) {
+                channel.unsubscribe(endpoint_id)?;
+                debug!(
+                    "Endpoint {} left channel '{}' ({} subscribers left)",
+                    endpoint_id,
+                    channel_id,
+                    channel.subscriber_count()
+                );
+                self.remove_if_abandoned(&channel_id)?;
+            }
+        }
+
+        for channel_id in endpoint.take_owned_channels()? {
+            // The channel may have been deleted and created again by someone else since.
+            if let Some(channel) =
+                self.remove_channel_if(&channel_id, |c| c.owner() == Some(*endpoint_id))?
+            {
+                debug!(
+                    "Deleting channel '{}' of disconnected endpoint {}",
+                    channel_id, endpoint_id
+                );
+                channel.close()?;
+            }
+        }
         Ok(())
     }
 
@@ -51,19 +164,159 @@ impl Directory {
     /// Create a channel.
     /// Does not subscribe, only creates.
     /// Endpoints do not need to be subscribed to publish messages to the channel.
-    pub fn create_channel(&self, channel_id: ChannelId) -> Result<(), AppError> {
-        let mut channels = self.channels_by_id.write()?;
-        if channels.contains_key(&channel_id) {
-            return Err(AppError::Generic(format!(
-                "Channel '{}' is already registered",
+    /// Fails with `ChannelAlreadyExists` unless `options.if_not_exists` is set, in which
+    /// case the existing channel is left untouched. A pending channel, implicitly created by
+    /// its subscribers, is claimed by the creator instead.
+    pub fn create_channel(
+        &self,
+        channel_id: ChannelId,
+        options: &ChannelOptions,
+        creator: EndpointId,
+    ) -> Result<(), AppError> {
+        if SubjectPattern::is_pattern(&channel_id) {
+            return Err(AppError::InvalidMessage(format!(
+                "channel id '{}' has wildcard tokens",
                 channel_id
             )));
         }
-        let channel = Arc::new(Channel::new(channel_id));
-        channels.insert(channel.channel_id.clone(), channel);
+        // Fail on invalid options before a durable channel's log is created.
+        Conflation::from_options(options)?;
+        let owner = options.delete_on_disconnect.then_some(creator);
+        let _creating = match options.durable {
+            true => Some(self.creating.lock()?),
+            false => None,
+        };
+        if self
+            .find_channel(&channel_id)
+            .is_some_and(|channel| !channel.is_pending())
+        {
+            return Self::already_exists(channel_id, options);
+        }
+        // Opened before taking the channels lock, so lookups and publishes do not wait for
+        // the file IO of a durable channel.
+        let mut store = self.open_store(&channel_id, options)?;
+        let mut channels = self.channels_by_id.write()?;
+        if let Some(channel) = channels.get(&channel_id) {
+            // Claimed meanwhile, or published to and no longer pending.
+            if channel.claim(owner, options, || Ok(store.take()))? {
+                return self.track_owner(&channel_id, owner);
+            }
+            drop(channels);
+            if let Some(mut store) = store
+                && let Err(err) = store.remove()
+            {
+                error!("Error removing the log of '{}': {}", channel_id, err);
+            }
+            return Self::already_exists(channel_id, options);
+        }
+        // The owner only tracks the channel once it can be created.
+        let mut channel = Channel::new(channel_id).with_options(options, store)?;
+        if let Some(owner) = owner {
+            channel = channel.owned_by(owner);
+        }
+        self.attach_pattern_subscribers(&channel)?;
+        self.track_owner(&channel.channel_id, owner)?;
+        channels.insert(channel.channel_id.clone(), Arc::new(channel));
         Ok(())
     }
 
+    fn already_exists(channel_id: ChannelId, options: &ChannelOptions) -> Result<(), AppError> {
+        if options.if_not_exists {
+            Ok(())
+        } else {
+            Err(AppError::ChannelAlreadyExists(channel_id))
+        }
+    }
+
+    /// Subscribe the endpoints of the matching patterns to a new channel. Called with the
+    /// channels lock held, so no pattern subscription can miss the channel.
+    fn attach_pattern_subscribers(&self, channel: &Channel) -> Result<(), AppError> {
+        let patterns = self.patterns.read()?;
+        for (pattern, endpoints) in patterns.iter() {
+            if !pattern.matches(&channel.channel_id) {
+                continue;
+            }
+            for subscriber in endpoints.values() {
+                if subscriber.endpoint.may_subscribe(&channel.channel_id) {
+                    // A failure means the endpoint is disconnecting, it must not fail the
+                    // creation.
+                    let _ = channel.attach(subscriber.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_pattern_subscriber(
+        subscribers: &mut PatternSubscribers,
+        pattern: &SubjectPattern,
+        endpoint_id: &EndpointId,
+    ) {
+        if let Some(endpoints) = subscribers.get_mut(pattern) {
+            endpoints.remove(endpoint_id);
+            if endpoints.is_empty() {
+                subscribers.remove(pattern);
+            }
+        }
+    }
+
+    /// Where a channel created with the given options keeps its messages, if anywhere.
+    fn open_store(
+        &self,
+        channel_id: &ChannelId,
+        options: &ChannelOptions,
+    ) -> Result<Option<Box<dyn MessageStore>>, AppError> {
+        if options.durable {
+            let storage = self.storage.as_ref().ok_or_else(|| {
+                AppError::InvalidConfig("durable channels require the server storage".to_string())
+            })?;
+            return Ok(Some(Box::new(storage.create(channel_id, options)?)));
+        }
+        Ok(MemoryStore::new(options).map(|store| Box::new(store) as _))
+    }
+
+    fn track_owner(
+        &self,
+        channel_id: &ChannelId,
+        owner: Option<EndpointId>,
+    ) -> Result<(), AppError> {
+        if let Some(endpoint) = owner.and_then(|owner| self.find_endpoint(&owner)) {
+            endpoint.add_owned_channel(channel_id)?;
+        }
+        Ok(())
+    }
+
+    /// Remove a pending channel once its last subscriber is gone.
+    fn remove_if_abandoned(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        if self
+            .remove_channel_if(channel_id, |c| c.is_pending() && c.subscriber_count() == 0)?
+            .is_some()
+        {
+            debug!("Removed abandoned pending channel '{}'", channel_id);
+        }
+        Ok(())
+    }
+
+    /// Delete a channel, notifying its subscribers.
+    pub fn delete_channel(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        let channel = self
+            .remove_channel_if(channel_id, |_| true)?
+            .ok_or_else(|| AppError::ChannelNotFound(channel_id.clone()))?;
+        channel.close()
+    }
+
+    fn remove_channel_if(
+        &self,
+        channel_id: &ChannelId,
+        predicate: impl FnOnce(&Channel) -> bool,
+    ) -> Result<Option<Arc<Channel>>, AppError> {
+        let mut channels = self.channels_by_id.write()?;
+        match channels.get(channel_id) {
+            Some(channel) if predicate(channel) => Ok(channels.remove(channel_id)),
+            _ => Ok(None),
+        }
+    }
+
     pub fn find_channel(&self, channel_id: &ChannelId) -> Option<Arc<Channel>> {
         let channels = self.channels_by_id.read().ok()?;
         channels.get(channel_id).map(Arc::clone)
@@ -74,17 +327,197 @@ impl Directory {
         &self,
         channel_id: &ChannelId,
         endpoint: Arc<Endpoint>,
+        options: &SubscribeOptions,
     ) -> Result<(), AppError> {
         let channel = self
             .find_channel(channel_id)
             .ok_or_else(|| AppError::ChannelNotFound(channel_id.clone()))?;
-        channel.subscribe(endpoint)
+        // A failed subscription leaves an existing one in place.
+        let added = endpoint.add_subscription(channel_id)?;
+        channel
+            .subscribe(Arc::clone(&endpoint), options)
+            .inspect_err(|_| {
+                if added {
+                    let _ = endpoint.remove_subscription(channel_id);
+                }
+            })
+    }
+
+    /// Subscribe the endpoint to the given channel id, creating a pending channel when it
+    /// does not exist so the endpoint receives from the first publish on.
+    pub fn subscribe_to_channel_or_create(
+        &self,
+        channel_id: &ChannelId,
+        endpoint: Arc<Endpoint>,
+        options: &SubscribeOptions,
+    ) -> Result<(), AppError> {
+        let mut added = false;
+        loop {
+            // The lock is only held to find or insert the channel, subscribing replays its
+            // history.
+            let channel = {
+                let mut channels = self.channels_by_id.write()?;
+                match channels.get(channel_id) {
+                    Some(channel) => Arc::clone(channel),
+                    None => {
+                        debug!("Creating pending channel '{}'", channel_id);
+                        let channel = Arc::new(Channel::new_pending(channel_id.clone()));
+                        self.attach_pattern_subscribers(&channel)?;
+                        channels.insert(channel_id.clone(), Arc::clone(&channel));
+                        channel
+                    }
+                }
+            };
+            added |= endpoint.add_subscription(channel_id)?;
+            if let Err(err) = channel.subscribe(Arc::clone(&endpoint), options) {
+                if added {
+                    let _ = endpoint.remove_subscription(channel_id);
+                }
+                self.remove_if_abandoned(channel_id)?;
+                return Err(err);
+            }
+            // A pending channel whose other subscribers left, or a deleted channel, may be
+            // gone before the subscription, subscribe again to the one in place.
+            if self
+                .find_channel(channel_id)
+                .is_some_and(|current| Arc::ptr_eq(&current, &channel))
+            {
+                return Ok(());
+            }
+            channel.unsubscribe(&endpoint.id)?;
+        }
+    }
+
+    /// Subscribe the endpoint to every channel matching the pattern, now and as they are
+    /// created. Channels outside the endpoint's ACL are skipped.
+    pub fn subscribe_to_pattern(
+        &self,
+        pattern: SubjectPattern,
+        endpoint: Arc<Endpoint>,
+        options: &SubscribeOptions,
+    ) -> Result<(), AppError> {
+        let subscriber = Subscriber::new(endpoint, options)?;
+        // Hold the channels lock so a channel created meanwhile is attached exactly once.
+        let channels = self.channels_by_id.read()?;
+        subscriber.endpoint.add_pattern(&pattern)?;
+        let previous = self
+            .patterns
+            .write()?
+            .entry(pattern.clone())
+            .or_default()
+            .insert(subscriber.endpoint.id, subscriber.clone());
+        let mut attached = Vec::new();
+        for channel in channels.values() {
+            if !pattern.matches(&channel.channel_id)
+                || !subscriber.endpoint.may_subscribe(&channel.channel_id)
+            {
+                continue;
+            }
+            match channel.attach(subscriber.clone()) {
+                Ok(true) => attached.push(channel),
+                Ok(false) => {}
+                Err(err) => {
+                    // Leave the endpoint subscribed as it was before.
+                    for channel in attached {
+                        let _ = channel.unsubscribe(&subscriber.endpoint.id);
+                    }
+                    let mut patterns = self.patterns.write()?;
+                    match previous {
+                        Some(previous) => {
+                            if let Some(endpoints) = patterns.get_mut(&pattern) {
+                                endpoints.insert(subscriber.endpoint.id, previous);
+                            }
+                        }
+                        None => {
+                            Self::remove_pattern_subscriber(
+                                &mut patterns,
+                                &pattern,
+                                &subscriber.endpoint.id,
+                            );
+                            subscriber.endpoint.remove_pattern(&pattern)?;
+                        }
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The subscriber of a pattern subscription of the endpoint covering the channel.
+    fn pattern_subscriber(
+        &self,
+        channel_id: &ChannelId,
+        endpoint_id: &EndpointId,
+    ) -> Result<Option<Subscriber>, AppError> {
+        let patterns = self.patterns.read()?;
+        Ok(patterns
+            .iter()
+            .filter(|(pattern, _)| pattern.matches(channel_id))
+            .find_map(|(_, endpoints)| endpoints.get(endpoint_id))
+            .filter(|subscriber| subscriber.endpoint.may_subscribe(channel_id))
+            .cloned())
+    }
+
+    /// Remove the pattern subscription, leaving the matching channels the endpoint is not
+    /// otherwise subscribed to.
+    pub fn unsubscribe_from_pattern(
+        &self,
+        pattern: &SubjectPattern,
+        endpoint_id: &EndpointId,
+    ) -> Result<(), AppError> {
+        let mut abandoned = Vec::new();
+        {
+            let channels = self.channels_by_id.read()?;
+            Self::remove_pattern_subscriber(&mut *self.patterns.write()?, pattern, endpoint_id);
+            let Some(endpoint) = self.find_endpoint(endpoint_id) else {
+                return Ok(());
+            };
+            endpoint.remove_pattern(pattern)?;
+            for channel in channels.values() {
+                if pattern.matches(&channel.channel_id) && !endpoint.wants(&channel.channel_id) {
+                    channel.unsubscribe(endpoint_id)?;
+                    abandoned.push(channel.channel_id.clone());
+                }
+            }
+        }
+        for channel_id in abandoned {
+            self.remove_if_abandoned(&channel_id)?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe the endpoint from the given channel id.
+    pub fn unsubscribe_from_channel(
+        &self,
+        channel_id: &ChannelId,
+        endpoint_id: &EndpointId,
+    ) -> Result<(), AppError> {
+        let channel = self
+            .find_channel(channel_id)
+            .ok_or_else(|| AppError::ChannelNotFound(channel_id.clone()))?;
+        if let Some(endpoint) = self.find_endpoint(endpoint_id) {
+            endpoint.remove_subscription(channel_id)?;
+            if let Some(subscriber) = self.pattern_subscriber(channel_id, endpoint_id)? {
+                // Still subscribed through a pattern, with the pattern's filter and
+                // projection from now on.
+                return channel.replace_subscriber(subscriber);
+            }
+        }
+        channel.unsubscribe(endpoint_id)?;
+        self.remove_if_abandoned(channel_id)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::settings::{Permission, StorageConfig};
+    use crate::tslm::acl::Acl;
+    use crate::tslm::auth::Identity;
+    use crate::tslm::hub::EndpointFactorySettings;
+    use crate::tslm::outgoing::Outgoing;
+    use common::message::{ChannelMessage, ClientCommand};
     use std::collections::HashSet;
 
     #[test]
@@ -92,7 +525,7 @@ mod tests {
         let directory = Directory::new();
         let channel_id = String::from("test_channel");
 
-        let result = directory.create_channel(channel_id.clone());
+        let result = directory.create_channel(channel_id.clone(), &ChannelOptions::default(), 1);
         assert!(result.is_ok());
 
         // Channel should now exist
@@ -104,18 +537,209 @@ mod tests {
         let directory = Directory::new();
         let channel_id = String::from("test_channel");
 
-        directory.create_channel(channel_id.clone()).unwrap();
-        let result = directory.create_channel(channel_id);
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        let result = directory.create_channel(channel_id, &ChannelOptions::default(), 1);
 
-        assert!(result.is_err());
+        assert!(matches!(result, Err(AppError::ChannelAlreadyExists(_))));
+    }
+
+    #[test]
+    fn test_create_channel_if_not_exists() {
+        let directory = Directory::new();
+        let channel_id = String::from("test_channel");
+        let options = ChannelOptions {
+            if_not_exists: true,
+            ..ChannelOptions::default()
+        };
+
+        directory
+            .create_channel(channel_id.clone(), &options, 1)
+            .unwrap();
+        assert!(
+            directory
+                .create_channel(channel_id.clone(), &options, 1)
+                .is_ok()
+        );
+        assert!(directory.find_channel(&channel_id).is_some());
+    }
+
+    #[test]
+    fn test_delete_channel() {
+        let directory = Directory::new();
+        let channel_id = String::from("test_channel");
+
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        directory.delete_channel(&channel_id).unwrap();
+        assert!(directory.find_channel(&channel_id).is_none());
+        assert!(directory.delete_channel(&channel_id).is_err());
+
+        // the name can be reused
+        assert!(
+            directory
+                .create_channel(channel_id, &ChannelOptions::default(), 1)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_delete_on_disconnect() {
+        let directory = Arc::new(Directory::new());
+        let channel_id = String::from("test_channel");
+        let options = ChannelOptions {
+            delete_on_disconnect: true,
+            ..ChannelOptions::default()
+        };
+
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        // a channel that cannot be created is not tracked for its owner
+        let durable = ChannelOptions {
+            durable: true,
+            ..options.clone()
+        };
+        assert!(
+            directory
+                .create_channel(channel_id.clone(), &durable, 1)
+                .is_err()
+        );
+        assert!(endpoint.take_owned_channels().unwrap().is_empty());
+
+        directory
+            .create_channel(channel_id.clone(), &options, 1)
+            .unwrap();
+        assert!(directory.find_channel(&channel_id).is_some());
+
+        directory.unregister_endpoint(&1).unwrap();
+        assert!(directory.find_channel(&channel_id).is_none());
+    }
+
+    #[test]
+    fn test_pending_channel() {
+        let directory = Arc::new(Directory::new());
+        let channel_id = String::from("test_channel");
+
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .subscribe_to_channel_or_create(&channel_id, endpoint, &SubscribeOptions::default())
+            .unwrap();
+        assert!(directory.find_channel(&channel_id).unwrap().is_pending());
+
+        // the last subscriber leaving removes the pending channel
+        directory.unsubscribe_from_channel(&channel_id, &1).unwrap();
+        assert!(directory.find_channel(&channel_id).is_none());
+
+        // so does a failed subscription creating it
+        let from_seq = SubscribeOptions {
+            from_seq: Some(5),
+            ..SubscribeOptions::default()
+        };
+        let (endpoint, _rx) = Endpoint::new(
+            2,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        assert!(
+            directory
+                .subscribe_to_channel_or_create(&channel_id, endpoint, &from_seq)
+                .is_err()
+        );
+        assert!(directory.find_channel(&channel_id).is_none());
+    }
+
+    #[test]
+    fn test_claim_pending_channel() {
+        let directory = Arc::new(Directory::new());
+        let channel_id = String::from("test_channel");
+
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .subscribe_to_channel_or_create(&channel_id, endpoint, &SubscribeOptions::default())
+            .unwrap();
+
+        // creating a pending channel claims it rather than failing
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 2)
+            .unwrap();
+        let channel = directory.find_channel(&channel_id).unwrap();
+        assert!(!channel.is_pending());
+        assert_eq!(channel.subscriber_count(), 1);
+
+        directory.unsubscribe_from_channel(&channel_id, &1).unwrap();
+        assert!(directory.find_channel(&channel_id).is_some());
+    }
+
+    #[test]
+    fn test_failed_claim_keeps_channel_pending() {
+        let directory = Arc::new(Directory::new());
+        let channel_id = String::from("test_channel");
+
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .subscribe_to_channel_or_create(&channel_id, endpoint, &SubscribeOptions::default())
+            .unwrap();
+
+        // without storage the durable store cannot be opened
+        let durable = ChannelOptions {
+            durable: true,
+            delete_on_disconnect: true,
+            ..ChannelOptions::default()
+        };
+        assert!(matches!(
+            directory.create_channel(channel_id.clone(), &durable, 2),
+            Err(AppError::InvalidConfig(_))
+        ));
+        let channel = directory.find_channel(&channel_id).unwrap();
+        assert!(channel.is_pending());
+        assert_eq!(channel.owner(), None);
+
+        // so it can still be claimed, and then outlives its subscribers
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 2)
+            .unwrap();
+        assert!(!channel.is_pending());
+        directory.unsubscribe_from_channel(&channel_id, &1).unwrap();
+        assert!(directory.find_channel(&channel_id).is_some());
     }
 
     #[test]
     fn test_register_endpoint() {
         let directory = Arc::new(Directory::new());
-        let permissions = HashSet::new();
+        let identity = Identity::default();
 
-        let (endpoint, _rx) = Endpoint::new(1, Arc::clone(&directory), permissions);
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            identity,
+            &EndpointFactorySettings::default(),
+        );
         let result = directory.register_endpoint(endpoint);
 
         assert!(result.is_ok());
@@ -125,25 +749,478 @@ mod tests {
     #[test]
     fn test_subscribe_to_nonexistent_channel() {
         let directory = Arc::new(Directory::new());
-        let permissions = HashSet::new();
+        let identity = Identity::default();
 
-        let (endpoint, _rx) = Endpoint::new(1, Arc::clone(&directory), permissions);
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            identity,
+            &EndpointFactorySettings::default(),
+        );
         directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
 
-        let result = directory.subscribe_to_channel(&String::from("nonexistent"), endpoint);
+        let result = directory.subscribe_to_channel(
+            &String::from("nonexistent"),
+            endpoint,
+            &SubscribeOptions::default(),
+        );
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_unsubscribe_from_channel() {
+        let directory = Arc::new(Directory::new());
+        let identity = Identity::default();
+        let channel_id = String::from("test_channel");
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            identity,
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        directory
+            .subscribe_to_channel(&channel_id, endpoint, &SubscribeOptions::default())
+            .unwrap();
+        directory.unsubscribe_from_channel(&channel_id, &1).unwrap();
+
+        let channel = directory.find_channel(&channel_id).unwrap();
+        channel
+            .publish(ChannelMessage::Text(String::from("test message")))
+            .unwrap();
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_unregister_endpoint_leaves_channels() {
+        let directory = Arc::new(Directory::new());
+        let channel_id = String::from("test_channel");
+
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        directory
+            .subscribe_to_channel(&channel_id, endpoint, &SubscribeOptions::default())
+            .unwrap();
+
+        let channel = directory.find_channel(&channel_id).unwrap();
+        assert_eq!(channel.subscriber_count(), 1);
+        directory.unregister_endpoint(&1).unwrap();
+        assert_eq!(channel.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn test_pattern_attaches_to_new_channels() {
+        let directory = Arc::new(Directory::new());
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .subscribe_to_pattern(
+                SubjectPattern::new("alerts.*").unwrap(),
+                endpoint,
+                &SubscribeOptions::default(),
+            )
+            .unwrap();
+
+        for channel_id in ["alerts.cpu", "alerts.cpu.high", "orders.eu"] {
+            directory
+                .create_channel(channel_id.to_string(), &ChannelOptions::default(), 2)
+                .unwrap();
+            directory
+                .find_channel(&channel_id.to_string())
+                .unwrap()
+                .publish(ChannelMessage::Text(String::from("hello")))
+                .unwrap();
+        }
+
+        // only the matching channel delivers, with its concrete id
+        match rx.try_recv() {
+            Some(Outgoing::Shared(shared)) => {
+                assert_eq!(shared.channel_id().unwrap(), "alerts.cpu");
+            }
+            _ => panic!("Expected a message from 'alerts.cpu'"),
+        }
+        assert!(rx.try_recv().is_none());
+
+        // patterns cannot be created as channels
+        assert!(
+            directory
+                .create_channel(String::from("alerts.*"), &ChannelOptions::default(), 2)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_pattern_checks_acl_per_channel() {
+        let directory = Arc::new(Directory::new());
+        for channel_id in ["alerts.cpu", "alerts.disk"] {
+            directory
+                .create_channel(channel_id.to_string(), &ChannelOptions::default(), 2)
+                .unwrap();
+        }
+
+        // the ACL glob `*` crosses dots, the pattern's does not
+        let acl = Acl::new(Some(&vec![String::from("alerts.cpu*")]), &[]);
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::new(HashSet::from([Permission::Subscribe]), acl),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .subscribe_to_pattern(
+                SubjectPattern::new("alerts.*").unwrap(),
+                endpoint,
+                &SubscribeOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            directory
+                .find_channel(&String::from("alerts.disk"))
+                .unwrap()
+                .subscriber_count(),
+            0
+        );
+
+        for channel_id in ["alerts.mem", "alerts.cpu.high"] {
+            directory
+                .create_channel(channel_id.to_string(), &ChannelOptions::default(), 2)
+                .unwrap();
+        }
+        for channel_id in ["alerts.cpu", "alerts.disk", "alerts.mem", "alerts.cpu.high"] {
+            directory
+                .find_channel(&channel_id.to_string())
+                .unwrap()
+                .publish(ChannelMessage::Text(String::from("hello")))
+                .unwrap();
+        }
+
+        // only the channel both matched and allowed delivers
+        match rx.try_recv() {
+            Some(Outgoing::Shared(shared)) => {
+                assert_eq!(shared.channel_id().unwrap(), "alerts.cpu");
+            }
+            _ => panic!("Expected a message from 'alerts.cpu'"),
+        }
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_unsubscribe_from_pattern() {
+        let directory = Arc::new(Directory::new());
+        let pattern = SubjectPattern::new("orders.eu.>").unwrap();
+        let channel_id = String::from("orders.eu.1");
+
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        directory
+            .subscribe_to_pattern(
+                pattern.clone(),
+                Arc::clone(&endpoint),
+                &SubscribeOptions::default(),
+            )
+            .unwrap();
+        directory
+            .subscribe_to_channel(&channel_id, endpoint, &SubscribeOptions::default())
+            .unwrap();
+        let channel = directory.find_channel(&channel_id).unwrap();
+        assert_eq!(channel.subscriber_count(), 1);
+
+        // the exact subscription remains
+        directory.unsubscribe_from_pattern(&pattern, &1).unwrap();
+        assert_eq!(channel.subscriber_count(), 1);
+        directory.unsubscribe_from_channel(&channel_id, &1).unwrap();
+        assert_eq!(channel.subscriber_count(), 0);
+
+        // and the pattern subscription is gone for new channels
+        directory
+            .create_channel(String::from("orders.eu.2"), &ChannelOptions::default(), 1)
+            .unwrap();
+        let other = directory
+            .find_channel(&String::from("orders.eu.2"))
+            .unwrap();
+        assert_eq!(other.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn test_unsubscribe_keeps_pattern_subscriber() {
+        let directory = Arc::new(Directory::new());
+        let channel_id = String::from("orders.eu.1");
+
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        directory
+            .create_channel(channel_id.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        directory
+            .subscribe_to_pattern(
+                SubjectPattern::new("orders.eu.>").unwrap(),
+                Arc::clone(&endpoint),
+                &SubscribeOptions::default(),
+            )
+            .unwrap();
+        let filtered = SubscribeOptions {
+            filter: Some(String::from(r#"side == "buy""#)),
+            ..SubscribeOptions::default()
+        };
+        directory
+            .subscribe_to_channel(&channel_id, endpoint, &filtered)
+            .unwrap();
+
+        // the pattern's subscriber, without the filter, replaces the exact one
+        directory.unsubscribe_from_channel(&channel_id, &1).unwrap();
+        let channel = directory.find_channel(&channel_id).unwrap();
+        assert_eq!(channel.subscriber_count(), 1);
+        channel
+            .publish(ChannelMessage::Json(serde_json::json!({"side": "sell"})))
+            .unwrap();
+        assert!(matches!(rx.try_recv(), Some(Outgoing::Shared(_))));
+    }
+
+    #[test]
+    fn test_failed_pattern_subscription_rolls_back() {
+        let directory = Arc::new(Directory::new());
+        let retained = ChannelOptions {
+            retain_last: true,
+            ..ChannelOptions::default()
+        };
+        for (channel_id, options) in [
+            ("alerts.cpu", ChannelOptions::default()),
+            ("alerts.disk", retained.clone()),
+            ("alerts.net", ChannelOptions::default()),
+        ] {
+            directory
+                .create_channel(channel_id.to_string(), &options, 1)
+                .unwrap();
+        }
+        directory
+            .find_channel(&String::from("alerts.disk"))
+            .unwrap()
+            .publish(ChannelMessage::Text(String::from("full")))
+            .unwrap();
+
+        // a closed connection cannot take the retained snapshot
+        let (endpoint, rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        drop(rx);
+        let pattern = SubjectPattern::new("alerts.*").unwrap();
+        assert!(
+            directory
+                .subscribe_to_pattern(pattern, Arc::clone(&endpoint), &SubscribeOptions::default())
+                .is_err()
+        );
+
+        // neither the pattern nor any of its channels keep the endpoint
+        assert!(directory.patterns.read().unwrap().is_empty());
+        assert!(!endpoint.wants(&String::from("alerts.cpu")));
+        for channel_id in ["alerts.cpu", "alerts.disk", "alerts.net"] {
+            let channel = directory.find_channel(&channel_id.to_string()).unwrap();
+            assert_eq!(channel.subscriber_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_failed_resubscription_keeps_subscription() {
+        let directory = Arc::new(Directory::new());
+        let settings = EndpointFactorySettings::default();
+        let (endpoint, mut rx) =
+            Endpoint::new(1, Arc::clone(&directory), Identity::default(), &settings);
+        directory.register_endpoint(Arc::clone(&endpoint)).unwrap();
+        let invalid = SubscribeOptions {
+            filter: Some(String::from("side ==")),
+            ..SubscribeOptions::default()
+        };
+
+        for (channel_id, or_create) in [("orders", false), ("quotes", true)] {
+            let channel_id = channel_id.to_string();
+            directory
+                .create_channel(channel_id.clone(), &ChannelOptions::default(), 2)
+                .unwrap();
+            directory
+                .subscribe_to_channel(
+                    &channel_id,
+                    Arc::clone(&endpoint),
+                    &SubscribeOptions::default(),
+                )
+                .unwrap();
+            let resubscribed = if or_create {
+                directory.subscribe_to_channel_or_create(
+                    &channel_id,
+                    Arc::clone(&endpoint),
+                    &invalid,
+                )
+            } else {
+                directory.subscribe_to_channel(&channel_id, Arc::clone(&endpoint), &invalid)
+            };
+            assert!(matches!(resubscribed, Err(AppError::InvalidMessage(_))));
+
+            // the original subscription still delivers
+            assert!(endpoint.wants(&channel_id));
+            directory
+                .find_channel(&channel_id)
+                .unwrap()
+                .publish(ChannelMessage::Text(String::from("hello")))
+                .unwrap();
+            match rx.try_recv() {
+                Some(Outgoing::Shared(shared)) => {
+                    assert_eq!(shared.channel_id(), Some(&channel_id));
+                }
+                _ => panic!("Expected a message from '{}'", channel_id),
+            }
+        }
+    }
+
     #[test]
     fn test_unregister_endpoint() {
         let directory = Arc::new(Directory::new());
-        let permissions = HashSet::new();
+        let identity = Identity::default();
 
-        let (endpoint, _rx) = Endpoint::new(1, Arc::clone(&directory), permissions);
+        let (endpoint, _rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            identity,
+            &EndpointFactorySettings::default(),
+        );
         directory.register_endpoint(endpoint).unwrap();
 
         assert!(directory.find_endpoint(&1).is_some());
         directory.unregister_endpoint(&1).unwrap();
         assert!(directory.find_endpoint(&1).is_none());
     }
+
+    #[test]
+    fn test_recover_durable_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = || {
+            FileStorage::new(StorageConfig {
+                path: dir.path().to_path_buf(),
+                segment_bytes: None,
+                retention_bytes: None,
+                retention_seconds: None,
+                fsync: None,
+                fsync_interval_ms: None,
+            })
+        };
+        let channel_id = String::from("orders");
+        let options = ChannelOptions {
+            durable: true,
+            ..ChannelOptions::default()
+        };
+
+        let directory = Directory::with_storage(storage()).unwrap();
+        directory
+            .create_channel(channel_id.clone(), &options, 1)
+            .unwrap();
+        let channel = directory.find_channel(&channel_id).unwrap();
+        for text in ["1", "2"] {
+            channel
+                .publish(ChannelMessage::Text(String::from(text)))
+                .unwrap();
+        }
+        drop(channel);
+
+        // creating an existing channel durable opens no log
+        assert!(matches!(
+            directory.create_channel(channel_id.clone(), &options, 1),
+            Err(AppError::ChannelAlreadyExists(_))
+        ));
+        let quotes = String::from("quotes");
+        directory
+            .create_channel(quotes.clone(), &ChannelOptions::default(), 1)
+            .unwrap();
+        let if_not_exists = ChannelOptions {
+            if_not_exists: true,
+            ..options.clone()
+        };
+        directory
+            .create_channel(quotes.clone(), &if_not_exists, 1)
+            .unwrap();
+        drop(directory);
+
+        // after a restart the channel is back, and its messages can be replayed
+        let directory = Arc::new(Directory::with_storage(storage()).unwrap());
+        assert!(directory.find_channel(&quotes).is_none());
+        assert!(directory.uses_storage(&TerminalStreamCommand::Subscribe(channel_id.clone())));
+        assert!(
+            !directory.uses_storage(&TerminalStreamCommand::CreateChannel(String::from(
+                "quotes"
+            )))
+        );
+        let (endpoint, mut rx) = Endpoint::new(
+            1,
+            Arc::clone(&directory),
+            Identity::default(),
+            &EndpointFactorySettings::default(),
+        );
+        let from_seq = SubscribeOptions {
+            from_seq: Some(2),
+            ..SubscribeOptions::default()
+        };
+        directory
+            .subscribe_to_channel(&channel_id, endpoint, &from_seq)
+            .unwrap();
+        match rx.try_recv() {
+            Some(Outgoing::Shared(shared)) => match shared.command() {
+                ClientCommand::ChannelMessage(_, ChannelMessage::Text(text), 2) => {
+                    assert_eq!(text, "2")
+                }
+                _ => panic!("Expected the second message"),
+            },
+            _ => panic!("Expected a shared message"),
+        }
+
+        // numbering continues where it was
+        let channel = directory.find_channel(&channel_id).unwrap();
+        channel
+            .publish(ChannelMessage::Text(String::from("3")))
+            .unwrap();
+        assert!(matches!(
+            rx.try_recv(),
+            Some(Outgoing::Shared(shared))
+                if matches!(shared.command(), ClientCommand::ChannelMessage(_, _, 3))
+        ));
+
+        // without storage durable channels cannot be created
+        assert!(
+            Directory::new()
+                .create_channel(channel_id, &options, 1)
+                .is_err()
+        );
+    }
 }
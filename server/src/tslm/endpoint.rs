@@ -1,97 +1,114 @@
 use std::collections::HashSet;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tracing::warn;
 
 use common::error::AppError;
-use common::message::{ChannelId, ChannelMessage, ClientCommand, TerminalStreamCommand};
+use common::message::{
+    ChannelId, ChannelMessage, ChannelOptions, ClientCommand, CommandEnvelope, CommandError,
+    CommandId, CommandSuccess, SubscribeOptions, TerminalStreamCommand,
+};
 
 use crate::settings::Permission;
+use crate::tslm::acl::Acl;
+use crate::tslm::auth::Identity;
 use crate::tslm::directory::Directory;
+use crate::tslm::hub::EndpointFactorySettings;
+use crate::tslm::outgoing::{Outgoing, SharedMessage};
+use crate::tslm::pattern::SubjectPattern;
+use crate::tslm::queue::{ConflationKey, OutgoingQueue, OutgoingReceiver};
 
 pub type EndpointId = u64;
 
 pub struct Endpoint {
     pub id: EndpointId,
     directory: Arc<Directory>,
-    tx: UnboundedSender<ClientCommand>,
+    queue: Arc<OutgoingQueue>,
     allowed_commands: HashSet<Permission>,
+    acl: Acl,
+    auto_create_channels: bool,
+    /// Channels this endpoint is subscribed to, so they can be left eagerly on unregister.
+    subscriptions: RwLock<HashSet<ChannelId>>,
+    /// Patterns this endpoint is subscribed to.
+    patterns: RwLock<HashSet<SubjectPattern>>,
+    /// Channels created with `delete_on_disconnect` by this endpoint.
+    owned_channels: RwLock<HashSet<ChannelId>>,
 }
 
 impl Endpoint {
+    /// Create an endpoint with an outgoing queue bounded by `channel_buffer_size`, the
+    /// listener's slow-consumer policy applies when it is full.
     pub fn new(
         id: EndpointId,
         directory: Arc<Directory>,
-        allowed_commands: HashSet<Permission>,
-    ) -> (Arc<Endpoint>, UnboundedReceiver<ClientCommand>) {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        identity: Identity,
+        settings: &EndpointFactorySettings,
+    ) -> (Arc<Endpoint>, OutgoingReceiver) {
+        let capacity = settings.channel_buffer_size.filter(|size| *size > 0);
+        let (queue, rx) = OutgoingQueue::new(capacity, settings.slow_consumer_policy);
         let endpoint = Arc::new(Endpoint {
             id,
-            tx,
+            queue,
             directory,
-            allowed_commands,
+            allowed_commands: identity.permissions,
+            acl: identity.acl,
+            auto_create_channels: settings.auto_create_channels,
+            subscriptions: RwLock::new(HashSet::default()),
+            patterns: RwLock::new(HashSet::default()),
+            owned_channels: RwLock::new(HashSet::default()),
         });
         (endpoint, rx)
     }
 
-    /// Create an endpoint with a bounded channel for backpressure control
-    pub fn new_bounded(
-        id: EndpointId,
-        directory: Arc<Directory>,
-        allowed_commands: HashSet<Permission>,
-        buffer_size: usize,
-    ) -> (Arc<Endpoint>, tokio::sync::mpsc::Receiver<ClientCommand>) {
-        let (tx_bounded, rx) = tokio::sync::mpsc::channel(buffer_size);
-
-        // We still use an unbounded sender internally but with async send
-        let (tx_internal, mut rx_internal) = tokio::sync::mpsc::unbounded_channel();
-
-        // Spawn a task to forward messages with backpressure
-        tokio::spawn(async move {
-            while let Some(msg) = rx_internal.recv().await {
-                if tx_bounded.send(msg).await.is_err() {
-                    break;
-                }
-            }
-        });
-
-        let endpoint = Arc::new(Endpoint {
-            id,
-            tx: tx_internal,
-            directory,
-            allowed_commands,
-        });
-        (endpoint, rx)
+    /// Whether the command may block on the durable storage, see `Directory::uses_storage`.
+    pub fn uses_storage(&self, envelope: &CommandEnvelope) -> bool {
+        self.directory.uses_storage(&envelope.command)
     }
 
-    pub fn on_command(&self, cmd: TerminalStreamCommand) -> Result<(), AppError> {
+    /// Execute the command and reply with `Success` or `Error`, carrying the command's id.
+    pub fn on_command(&self, envelope: CommandEnvelope) -> Result<(), AppError> {
+        let CommandEnvelope { id, command: cmd } = envelope;
         let result = match cmd {
             TerminalStreamCommand::CreateChannel(ref channel_id) => {
-                if self.allowed_commands.contains(&Permission::CreateChannel) {
-                    self.directory.create_channel(channel_id.clone())
+                self.create_channel(channel_id, &ChannelOptions::default())
+            }
+            TerminalStreamCommand::CreateChannelWithOptions(ref channel_id, ref options) => {
+                self.create_channel(channel_id, options)
+            }
+            TerminalStreamCommand::DeleteChannel(ref channel_id) => {
+                if self.allowed_commands.contains(&Permission::DeleteChannel) {
+                    self.check_channel(&Permission::DeleteChannel, channel_id)
+                        .and_then(|_| self.directory.delete_channel(channel_id))
                 } else {
                     warn!(
-                        "Endpoint {} attempted to create a channel without permissions.",
+                        "Endpoint {} attempted to delete a channel without permissions.",
                         self.id
                     );
-                    Err(AppError::PermissionDenied("CreateChannel".to_string()))
+                    Err(AppError::PermissionDenied("DeleteChannel".to_string()))
                 }
             }
             TerminalStreamCommand::Subscribe(ref channel_id) => {
-                if self.allowed_commands.contains(&Permission::Subscribe) {
-                    self.subscribe(channel_id)
+                self.subscribe(channel_id, &SubscribeOptions::default())
+            }
+            TerminalStreamCommand::SubscribeWithOptions(ref channel_id, ref options) => {
+                self.subscribe(channel_id, options)
+            }
+            TerminalStreamCommand::Unsubscribe(ref channel_id) => {
+                // Always allowed, an endpoint can only be subscribed where it was permitted.
+                if SubjectPattern::is_pattern(channel_id) {
+                    SubjectPattern::new(channel_id.as_str()).and_then(|pattern| {
+                        self.directory.unsubscribe_from_pattern(&pattern, &self.id)
+                    })
                 } else {
-                    warn!(
-                        "Endpoint {} attempted to subscribe to a channel without permissions.",
-                        self.id
-                    );
-                    Err(AppError::PermissionDenied("Subscribe".to_string()))
+                    self.directory
+                        .unsubscribe_from_channel(channel_id, &self.id)
                 }
             }
             TerminalStreamCommand::NotifyChannel(ref channel_id, ref msg) => {
                 if self.allowed_commands.contains(&Permission::NotifyChannel) {
-                    self.notify_channel(channel_id, msg)
+                    self.check_channel(&Permission::NotifyChannel, channel_id)
+                        .and_then(|_| self.notify_channel(channel_id, msg))
                 } else {
                     warn!(
                         "Endpoint {} attempted to notify a channel without permissions.",
@@ -105,41 +122,220 @@ impl Endpoint {
         // Send response back to client
         match result {
             Ok(_) => {
-                let _ = self.send(ClientCommand::Success(format!(
-                    "Command executed: {:?}",
-                    cmd
-                )));
+                let _ = self.send(ClientCommand::Success(CommandSuccess::new(id, &cmd)));
             }
             Err(ref err) => {
-                let _ = self.send(ClientCommand::Error(err.to_string()));
+                let _ = self.send_error(id, err);
             }
         }
 
         result
     }
 
+    /// Deny the command when the endpoint's ACL does not cover the target channel.
+    fn check_channel(
+        &self,
+        permission: &Permission,
+        channel_id: &ChannelId,
+    ) -> Result<(), AppError> {
+        if self.acl.allows(permission, channel_id) {
+            Ok(())
+        } else {
+            warn!(
+                "Endpoint {} attempted {:?} on channel '{}' outside its ACL.",
+                self.id, permission, channel_id
+            );
+            Err(AppError::PermissionDenied(format!(
+                "{:?} on channel '{}'",
+                permission, channel_id
+            )))
+        }
+    }
+
+    fn create_channel(
+        &self,
+        channel_id: &ChannelId,
+        options: &ChannelOptions,
+    ) -> Result<(), AppError> {
+        if self.allowed_commands.contains(&Permission::CreateChannel) {
+            self.check_channel(&Permission::CreateChannel, channel_id)?;
+            self.directory
+                .create_channel(channel_id.clone(), options, self.id)
+        } else {
+            warn!(
+                "Endpoint {} attempted to create a channel without permissions.",
+                self.id
+            );
+            Err(AppError::PermissionDenied("CreateChannel".to_string()))
+        }
+    }
+
     fn notify_channel(&self, channel_id: &ChannelId, msg: &ChannelMessage) -> Result<(), AppError> {
-        let channel = self
-            .directory
-            .find_channel(channel_id)
-            .ok_or_else(|| AppError::ChannelNotFound(channel_id.clone()))?;
+        let channel = match self.directory.find_channel(channel_id) {
+            Some(channel) => channel,
+            None if self.can_auto_create(channel_id) => {
+                let options = ChannelOptions {
+                    if_not_exists: true,
+                    ..ChannelOptions::default()
+                };
+                self.directory
+                    .create_channel(channel_id.clone(), &options, self.id)?;
+                self.directory
+                    .find_channel(channel_id)
+                    .ok_or_else(|| AppError::ChannelNotFound(channel_id.clone()))?
+            }
+            None => return Err(AppError::ChannelNotFound(channel_id.clone())),
+        };
         channel.publish(msg.clone())
     }
 
-    fn subscribe(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+    /// Publishers may implicitly create channels they would be allowed to create explicitly.
+    fn can_auto_create(&self, channel_id: &ChannelId) -> bool {
+        self.auto_create_channels
+            && self.allowed_commands.contains(&Permission::CreateChannel)
+            && self.acl.allows(&Permission::CreateChannel, channel_id)
+    }
+
+    fn subscribe(
+        &self,
+        channel_id: &ChannelId,
+        options: &SubscribeOptions,
+    ) -> Result<(), AppError> {
+        if !self.allowed_commands.contains(&Permission::Subscribe) {
+            warn!(
+                "Endpoint {} attempted to subscribe to a channel without permissions.",
+                self.id
+            );
+            return Err(AppError::PermissionDenied("Subscribe".to_string()));
+        }
         let self_reference = self
             .directory
             .find_endpoint(&self.id)
             .ok_or_else(|| AppError::EndpointNotFound(self.id.to_string()))?;
-        self.directory
-            .subscribe_to_channel(channel_id, self_reference)
+        if SubjectPattern::is_pattern(channel_id) {
+            // The ACL is checked against each matching channel instead.
+            if options.from_seq.is_some() {
+                return Err(AppError::InvalidMessage(format!(
+                    "from_seq cannot be used with pattern '{}'",
+                    channel_id
+                )));
+            }
+            let pattern = SubjectPattern::new(channel_id.as_str())?;
+            return self
+                .directory
+                .subscribe_to_pattern(pattern, self_reference, options);
+        }
+        self.check_channel(&Permission::Subscribe, channel_id)?;
+        if self.auto_create_channels {
+            self.directory
+                .subscribe_to_channel_or_create(channel_id, self_reference, options)
+        } else {
+            self.directory
+                .subscribe_to_channel(channel_id, self_reference, options)
+        }
     }
 
     // send this command to the client
     pub fn send(&self, msg: ClientCommand) -> Result<(), AppError> {
-        self.tx
-            .send(msg)
-            .map_err(|e| AppError::ChannelSend(e.to_string()))
+        self.queue.try_send(Outgoing::Command(msg))
+    }
+
+    // send a published message, shared with the other subscribers, to the client
+    pub fn send_shared(&self, msg: Arc<SharedMessage>) -> Result<(), AppError> {
+        self.queue.try_send(Outgoing::Shared(msg))
+    }
+
+    // queue replayed messages, all of them or none when they do not fit, returns whether
+    // they were queued
+    pub fn send_replayed(&self, msgs: Vec<Arc<SharedMessage>>) -> Result<bool, AppError> {
+        self.queue.try_send_all(msgs)
+    }
+
+    // queue a message of a conflated channel, replacing the one with the same key that
+    // still waits for the channel's next update
+    pub fn send_conflated(
+        &self,
+        key: ConflationKey,
+        interval: Duration,
+        msg: Arc<SharedMessage>,
+    ) -> Result<(), AppError> {
+        self.queue.try_send_conflated(key, interval, msg)
+    }
+
+    // send the error to the client, as the reply to the given command id
+    pub fn send_error(&self, id: Option<CommandId>, err: &AppError) -> Result<(), AppError> {
+        self.send(ClientCommand::Error(CommandError::new(id, err)))
+    }
+
+    /// Published messages dropped because this endpoint's client did not keep up.
+    pub fn dropped_messages(&self) -> u64 {
+        self.queue.dropped()
+    }
+
+    /// Returns whether the endpoint was not subscribed to the channel yet.
+    pub(crate) fn add_subscription(&self, channel_id: &ChannelId) -> Result<bool, AppError> {
+        let mut subscriptions = self.subscriptions.write()?;
+        Ok(subscriptions.insert(channel_id.clone()))
+    }
+
+    pub(crate) fn remove_subscription(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        let mut subscriptions = self.subscriptions.write()?;
+        subscriptions.remove(channel_id);
+        Ok(())
+    }
+
+    /// Take all the channels this endpoint is subscribed to, leaving it with none.
+    pub(crate) fn take_subscriptions(&self) -> Result<HashSet<ChannelId>, AppError> {
+        let mut subscriptions = self.subscriptions.write()?;
+        Ok(std::mem::take(&mut *subscriptions))
+    }
+
+    pub(crate) fn add_pattern(&self, pattern: &SubjectPattern) -> Result<(), AppError> {
+        let mut patterns = self.patterns.write()?;
+        patterns.insert(pattern.clone());
+        Ok(())
+    }
+
+    pub(crate) fn remove_pattern(&self, pattern: &SubjectPattern) -> Result<(), AppError> {
+        let mut patterns = self.patterns.write()?;
+        patterns.remove(pattern);
+        Ok(())
+    }
+
+    /// Take all the patterns this endpoint is subscribed to, leaving it with none.
+    pub(crate) fn take_patterns(&self) -> Result<HashSet<SubjectPattern>, AppError> {
+        let mut patterns = self.patterns.write()?;
+        Ok(std::mem::take(&mut *patterns))
+    }
+
+    /// Whether the endpoint is subscribed to the channel, by id or through a pattern.
+    pub(crate) fn wants(&self, channel_id: &ChannelId) -> bool {
+        let subscribed = self
+            .subscriptions
+            .read()
+            .is_ok_and(|subscriptions| subscriptions.contains(channel_id));
+        subscribed
+            || self
+                .patterns
+                .read()
+                .is_ok_and(|patterns| patterns.iter().any(|pattern| pattern.matches(channel_id)))
+    }
+
+    /// Whether a pattern subscription may attach this endpoint to the channel.
+    pub(crate) fn may_subscribe(&self, channel_id: &ChannelId) -> bool {
+        self.acl.allows(&Permission::Subscribe, channel_id)
+    }
+
+    pub(crate) fn add_owned_channel(&self, channel_id: &ChannelId) -> Result<(), AppError> {
+        let mut owned_channels = self.owned_channels.write()?;
+        owned_channels.insert(channel_id.clone());
+        Ok(())
+    }
+
+    /// Take all the channels to delete when this endpoint goes away.
+    pub(crate) fn take_owned_channels(&self) -> Result<HashSet<ChannelId>, AppError> {
+        let mut owned_channels = self.owned_channels.write()?;
+        Ok(std::mem::take(&mut *owned_channels))
     }
 
     pub fn unregister(&self) -> Result<(), AppError> {
@@ -0,0 +1,408 @@
+use std::cmp::Ordering;
+
+use serde_json::Value;
+
+use common::error::AppError;
+use common::message::ChannelMessage;
+
+/// A subscriber's filter over the fields of JSON messages, compiled once when subscribing.
+///
+/// Expressions compare a field, named by its `.` separated path, with JSON literals:
+/// `symbol == "AAPL"`, `quote.bid >= 100.5`, `venue in ["XNAS", "XNYS"]`, combined with
+/// `&&`, `||`, `!` and parentheses. Messages that are not JSON never match, and neither
+/// does a comparison with a missing field, except `!=`.
+///
+/// Filters are at most `MAX_FILTER_LEN` bytes long and nest `!` and parentheses at most
+/// `MAX_DEPTH` deep, so parsing, evaluating and dropping them cannot overflow the stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageFilter {
+    expr: Expr,
+}
+
+/// Longest filter expression accepted, in bytes.
+const MAX_FILTER_LEN: usize = 4096;
+
+/// Deepest nesting of `!` and parentheses accepted.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Vec<String>, Op, Value),
+    In(Vec<String>, Vec<Value>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl MessageFilter {
+    pub fn compile(expression: &str) -> Result<Self, AppError> {
+        if expression.len() > MAX_FILTER_LEN {
+            return Err(AppError::InvalidMessage(format!(
+                "Invalid filter: longer than {} bytes",
+                MAX_FILTER_LEN
+            )));
+        }
+        let invalid = |reason: String| {
+            AppError::InvalidMessage(format!("Invalid filter '{}': {}", expression, reason))
+        };
+        let mut parser = Parser {
+            tokens: tokenize(expression).map_err(invalid)?,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.or().map_err(invalid)?;
+        if let Some(token) = parser.peek() {
+            return Err(invalid(format!("unexpected {:?}", token)));
+        }
+        Ok(MessageFilter { expr })
+    }
+
+    pub fn matches(&self, message: &ChannelMessage) -> bool {
+        match message {
+            ChannelMessage::Json(value) => self.expr.eval(value),
+            _ => false,
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, message: &Value) -> bool {
+        match self {
+            Expr::And(left, right) => left.eval(message) && right.eval(message),
+            Expr::Or(left, right) => left.eval(message) || right.eval(message),
+            Expr::Not(expr) => !expr.eval(message),
+            Expr::Compare(path, op, literal) => match lookup(message, path) {
+                Some(field) => match op {
+                    Op::Eq => equals(field, literal),
+                    Op::Ne => !equals(field, literal),
+                    Op::Lt => compare(field, literal) == Some(Ordering::Less),
+                    Op::Le => matches!(
+                        compare(field, literal),
+                        Some(Ordering::Less | Ordering::Equal)
+                    ),
+                    Op::Gt => compare(field, literal) == Some(Ordering::Greater),
+                    Op::Ge => matches!(
+                        compare(field, literal),
+                        Some(Ordering::Greater | Ordering::Equal)
+                    ),
+                },
+                None => *op == Op::Ne,
+            },
+            Expr::In(path, literals) => lookup(message, path)
+                .is_some_and(|field| literals.iter().any(|literal| equals(field, literal))),
+        }
+    }
+}
+
+fn lookup<'a>(message: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(message, |value, field| value.as_object()?.get(field))
+}
+
+/// Numbers are equal by value, so `1` equals `1.0`.
+fn equals(field: &Value, literal: &Value) -> bool {
+    match (field, literal) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        _ => field == literal,
+    }
+}
+
+/// Numbers and strings are ordered, other values are not.
+fn compare(field: &Value, literal: &Value) -> Option<Ordering> {
+    match (field, literal) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Path(Vec<String>),
+    Literal(Value),
+    Op(Op),
+    In,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    OpenList,
+    CloseList,
+    Comma,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let symbols = [
+            ("==", Token::Op(Op::Eq)),
+            ("!=", Token::Op(Op::Ne)),
+            ("<=", Token::Op(Op::Le)),
+            (">=", Token::Op(Op::Ge)),
+            ("<", Token::Op(Op::Lt)),
+            (">", Token::Op(Op::Gt)),
+            ("&&", Token::And),
+            ("||", Token::Or),
+            ("!", Token::Not),
+            ("(", Token::Open),
+            (")", Token::Close),
+            ("[", Token::OpenList),
+            ("]", Token::CloseList),
+            (",", Token::Comma),
+        ];
+        let len = if let Some((symbol, token)) = symbols
+            .into_iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+        {
+            tokens.push(token);
+            symbol.len()
+        } else if c == '"' {
+            let len = string_len(rest).ok_or("unterminated string")?;
+            let literal = serde_json::from_str(&rest[..len]).map_err(|e| e.to_string())?;
+            tokens.push(Token::Literal(literal));
+            len
+        } else if c == '-' || c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+                .unwrap_or(rest.len());
+            let literal = serde_json::from_str(&rest[..len])
+                .map_err(|_| format!("invalid number '{}'", &rest[..len]))?;
+            tokens.push(Token::Literal(literal));
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(match &rest[..len] {
+                "true" => Token::Literal(Value::Bool(true)),
+                "false" => Token::Literal(Value::Bool(false)),
+                "null" => Token::Literal(Value::Null),
+                "in" => Token::In,
+                path => {
+                    if path.split('.').any(str::is_empty) {
+                        return Err(format!("invalid field '{}'", path));
+                    }
+                    Token::Path(path.split('.').map(String::from).collect())
+                }
+            });
+            len
+        } else {
+            return Err(format!("unexpected '{}'", c));
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// The length of the string literal at the start of `input`, quotes included.
+fn string_len(input: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in input.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Recursive descent over `or := and ("||" and)*`, `and := unary ("&&" unary)*`,
+/// `unary := "!" unary | "(" or ")" | path op literal | path "in" "[" literals "]"`.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Current nesting of `!` and parentheses.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("expected {:?}, found {:?}", expected, token)),
+        }
+    }
+
+    /// Parse with one more level of nesting, failing beyond `MAX_DEPTH`.
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(format!("nested deeper than {}", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next()? {
+            Token::Not => Ok(Expr::Not(Box::new(self.nested(Self::unary)?))),
+            Token::Open => {
+                let expr = self.nested(Self::or)?;
+                self.expect(Token::Close)?;
+                Ok(expr)
+            }
+            Token::Path(path) => match self.next()? {
+                Token::Op(op) => Ok(Expr::Compare(path, op, self.literal()?)),
+                Token::In => {
+                    self.expect(Token::OpenList)?;
+                    let mut literals = vec![self.literal()?];
+                    while self.peek() == Some(&Token::Comma) {
+                        self.pos += 1;
+                        literals.push(self.literal()?);
+                    }
+                    self.expect(Token::CloseList)?;
+                    Ok(Expr::In(path, literals))
+                }
+                token => Err(format!("expected a comparison, found {:?}", token)),
+            },
+            token => Err(format!("expected a field, found {:?}", token)),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        match self.next()? {
+            Token::Literal(value) => Ok(value),
+            token => Err(format!("expected a value, found {:?}", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(filter: &str, message: Value) -> bool {
+        MessageFilter::compile(filter)
+            .unwrap()
+            .matches(&ChannelMessage::Json(message))
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let quote = json!({"symbol": "AAPL", "price": 101.5, "size": 100, "quote": {"bid": 101}});
+        assert!(matches(r#"symbol == "AAPL""#, quote.clone()));
+        assert!(!matches(r#"symbol != "AAPL""#, quote.clone()));
+        assert!(matches("price > 100", quote.clone()));
+        assert!(!matches("price <= 100", quote.clone()));
+        assert!(matches("size == 100.0", quote.clone()));
+        assert!(matches("quote.bid >= 101", quote.clone()));
+        assert!(matches(r#"symbol in ["MSFT", "AAPL"]"#, quote.clone()));
+        // a missing field only matches !=
+        assert!(!matches("venue == null", quote.clone()));
+        assert!(matches(r#"venue != "XNAS""#, quote.clone()));
+        // values of different types are not ordered
+        assert!(!matches(r#"price > "100""#, quote));
+    }
+
+    #[test]
+    fn test_combinators() {
+        let quote = json!({"symbol": "AAPL", "price": 99});
+        assert!(matches(
+            r#"symbol == "AAPL" && (price > 100 || price < 100)"#,
+            quote.clone()
+        ));
+        assert!(!matches(
+            r#"!(symbol == "AAPL") || price > 100"#,
+            quote.clone()
+        ));
+        // && binds tighter than ||
+        assert!(matches(
+            r#"symbol == "MSFT" && price > 100 || price == 99"#,
+            quote
+        ));
+
+        let filter = MessageFilter::compile(r#"symbol == "AAPL""#).unwrap();
+        assert!(!filter.matches(&ChannelMessage::Text(String::from("AAPL"))));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        for filter in [
+            "",
+            "symbol",
+            r#"symbol == "AAPL"#,
+            r#"symbol == "AAPL" &&"#,
+            "symbol in []",
+            "(price > 1",
+            "price > 1 price",
+            "price >> 1",
+            "quote..bid == 1",
+            &format!("{}price > 1", "!".repeat(100_000)),
+            &format!("{}price > 1", "(".repeat(100_000)),
+            &format!(
+                "{}price > 1{}",
+                "(".repeat(MAX_DEPTH + 1),
+                ")".repeat(MAX_DEPTH + 1)
+            ),
+            &vec!["price > 1"; 1000].join(" && "),
+        ] {
+            assert!(
+                matches!(
+                    MessageFilter::compile(filter),
+                    Err(AppError::InvalidMessage(_))
+                ),
+                "{:.100}",
+                filter
+            );
+        }
+
+        // as deep as allowed
+        let nested = format!(
+            "{}price > 1{}",
+            "(".repeat(MAX_DEPTH),
+            ")".repeat(MAX_DEPTH)
+        );
+        assert!(matches(&nested, json!({"price": 2})));
+    }
+}
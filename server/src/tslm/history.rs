@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use common::error::AppError;
+use common::message::Sequence;
+
+use crate::tslm::outgoing::SharedMessage;
+use crate::tslm::store::MessageStore;
+
+/// Sequence numbers of a channel, and the store of its last published messages kept for
+/// subscribers resuming with `from_seq`.
+pub struct History {
+    next_seq: Sequence,
+    store: Option<Box<dyn MessageStore>>,
+}
+
+impl History {
+    /// Number the messages after the last one already in the store, from 1 without one.
+    pub fn new(store: Option<Box<dyn MessageStore>>) -> Self {
+        let mut history = History {
+            next_seq: 1,
+            store: None,
+        };
+        history.set_store(store);
+        history
+    }
+
+    /// Keep the next messages in the given store, numbering continues where it was.
+    pub fn set_store(&mut self, store: Option<Box<dyn MessageStore>>) {
+        if let Some(last_seq) = store.as_ref().and_then(|store| store.last_seq()) {
+            self.next_seq = self.next_seq.max(last_seq + 1);
+        }
+        self.store = store;
+    }
+
+    /// The sequence number of the next published message.
+    pub fn next_seq(&self) -> Sequence {
+        self.next_seq
+    }
+
+    /// Keep the message published with the next sequence number, and move on to the
+    /// following one. The number is not used up when the store fails.
+    pub fn push(&mut self, message: &Arc<SharedMessage>) -> Result<(), AppError> {
+        if let Some(ref mut store) = self.store {
+            store.append(self.next_seq, message)?;
+        }
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// The last kept message, if any.
+    pub fn last(&mut self) -> Result<Option<Arc<SharedMessage>>, AppError> {
+        let Some(ref mut store) = self.store else {
+            return Ok(None);
+        };
+        match store.last_seq() {
+            Some(last_seq) => Ok(store.read_from(last_seq, 1)?.pop()),
+            None => Ok(None),
+        }
+    }
+
+    /// Up to `max` of the kept messages from `from_seq` on, oldest first. Fails with
+    /// `SequenceGap` when some of them were already evicted, or when `from_seq` was never
+    /// reached.
+    pub fn replay_from(
+        &mut self,
+        from_seq: Sequence,
+        max: usize,
+    ) -> Result<Vec<Arc<SharedMessage>>, AppError> {
+        let oldest = match self.store {
+            Some(ref mut store) => store.first_seq()?.unwrap_or(self.next_seq),
+            None => self.next_seq,
+        };
+        if from_seq < oldest || from_seq > self.next_seq {
+            return Err(AppError::SequenceGap {
+                requested: from_seq,
+                oldest,
+            });
+        }
+        match self.store {
+            Some(ref mut store) => store.read_from(from_seq, max),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Flush the kept messages to durable storage.
+    pub fn sync(&mut self) -> Result<(), AppError> {
+        match self.store {
+            Some(ref mut store) => store.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Delete the kept messages, the channel is gone.
+    pub fn remove(&mut self) -> Result<(), AppError> {
+        match self.store.take() {
+            Some(mut store) => store.remove(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tslm::store::MemoryStore;
+    use common::message::{ChannelMessage, ChannelOptions, ClientCommand};
+
+    fn publish(history: &mut History) -> Sequence {
+        let seq = history.next_seq();
+        let message = SharedMessage::new(ClientCommand::ChannelMessage(
+            String::from("test_channel"),
+            ChannelMessage::Text(seq.to_string()),
+            seq,
+        ));
+        history.push(&Arc::new(message)).unwrap();
+        seq
+    }
+
+    fn sequences(messages: Vec<Arc<SharedMessage>>) -> Vec<Sequence> {
+        messages
+            .iter()
+            .map(|message| match message.command() {
+                ClientCommand::ChannelMessage(_, _, seq) => *seq,
+                _ => panic!("Expected a channel message"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_last_messages() {
+        let store = MemoryStore::new(&ChannelOptions {
+            history_size: Some(3),
+            ..ChannelOptions::default()
+        });
+        let mut history = History::new(store.map(|store| Box::new(store) as _));
+        for _ in 0..5 {
+            publish(&mut history);
+        }
+
+        assert_eq!(sequences(history.replay_from(4, 10).unwrap()), vec![4, 5]);
+        assert_eq!(
+            sequences(history.replay_from(3, 10).unwrap()),
+            vec![3, 4, 5]
+        );
+        assert_eq!(sequences(history.replay_from(3, 2).unwrap()), vec![3, 4]);
+        // resuming right after the last message replays nothing
+        assert!(history.replay_from(6, 10).unwrap().is_empty());
+
+        match history.replay_from(2, 10) {
+            Err(AppError::SequenceGap { requested, oldest }) => {
+                assert_eq!(requested, 2);
+                assert_eq!(oldest, 3);
+            }
+            _ => panic!("Expected a sequence gap"),
+        }
+        assert!(history.replay_from(7, 10).is_err());
+    }
+
+    #[test]
+    fn test_without_store() {
+        let mut history = History::new(None);
+        assert_eq!(publish(&mut history), 1);
+        assert_eq!(publish(&mut history), 2);
+
+        // sequence numbers are stamped, but nothing can be replayed
+        assert!(history.replay_from(3, 10).unwrap().is_empty());
+        assert!(history.replay_from(2, 10).is_err());
+        assert!(history.last().unwrap().is_none());
+    }
+}
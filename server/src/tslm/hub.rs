@@ -1,20 +1,19 @@
-use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 
-use tokio::sync::mpsc::UnboundedReceiver;
-
 use common::error::AppError;
-use common::message::ClientCommand;
 
-use crate::settings::Permission;
+use crate::settings::SlowConsumerPolicy;
+use crate::tslm::auth::Identity;
 use crate::tslm::directory::Directory;
 use crate::tslm::endpoint::Endpoint;
+use crate::tslm::queue::OutgoingReceiver;
 
 #[derive(Default)]
 pub struct EndpointFactorySettings {
-    pub default_endpoint_permissions: HashSet<Permission>,
     pub channel_buffer_size: Option<usize>,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    pub auto_create_channels: bool,
 }
 
 pub struct Sequence {
@@ -38,8 +37,7 @@ pub struct Hub {
 }
 
 impl Hub {
-    pub fn new() -> Self {
-        let directory = Arc::new(Directory::new());
+    pub fn new(directory: Arc<Directory>) -> Self {
         Hub {
             endpoint_id_seq: Sequence::new(),
             directory,
@@ -49,39 +47,12 @@ impl Hub {
     pub fn create_endpoint(
         &self,
         endpoint_factory_settings: &EndpointFactorySettings,
-    ) -> Result<(Arc<Endpoint>, UnboundedReceiver<ClientCommand>), AppError> {
+        identity: Identity,
+    ) -> Result<(Arc<Endpoint>, OutgoingReceiver), AppError> {
         let directory = Arc::clone(&self.directory);
-        let allowed_commands = endpoint_factory_settings
-            .default_endpoint_permissions
-            .clone();
         let endpoint_id = self.endpoint_id_seq.next();
-
-        let (endpoint, rx) = if let Some(buffer_size) =
-            endpoint_factory_settings.channel_buffer_size
-        {
-            if buffer_size > 0 {
-                // Bounded channel - but we need to convert the receiver type
-                let (ep, bounded_rx) =
-                    Endpoint::new_bounded(endpoint_id, directory, allowed_commands, buffer_size);
-
-                // Convert bounded receiver to unbounded using a forwarding task
-                let (tx, unbounded_rx) = tokio::sync::mpsc::unbounded_channel();
-                tokio::spawn(async move {
-                    let mut bounded = bounded_rx;
-                    while let Some(msg) = bounded.recv().await {
-                        if tx.send(msg).is_err() {
-                            break;
-                        }
-                    }
-                });
-                (ep, unbounded_rx)
-            } else {
-                Endpoint::new(endpoint_id, directory, allowed_commands)
-            }
-        } else {
-            Endpoint::new(endpoint_id, directory, allowed_commands)
-        };
-
+        let (endpoint, rx) =
+            Endpoint::new(endpoint_id, directory, identity, endpoint_factory_settings);
         self.directory.register_endpoint(Arc::clone(&endpoint))?;
         Ok((endpoint, rx))
     }
@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use serde::Deserialize;
+
+use common::error::AppError;
+
+use crate::settings::{AclRuleConfig, JwtAlgorithm, JwtConfig, Permission};
+use crate::tslm::acl::Acl;
+use crate::tslm::auth::Identity;
+
+/// Claims mapped into an endpoint identity. `exp` is mandatory.
+#[derive(Deserialize)]
+struct Claims {
+    exp: u64,
+    #[serde(default)]
+    permissions: HashSet<Permission>,
+    channels: Option<Vec<String>>,
+    acl: Option<Vec<AclRuleConfig>>,
+}
+
+/// Verifies signed JWTs presented during the handshake.
+pub struct JwtVerifier {
+    /// Decoding keys with their optional `kid`.
+    keys: Vec<(Option<String>, DecodingKey)>,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    pub fn from_config(config: &JwtConfig) -> Result<Self, AppError> {
+        let algorithm = match config.algorithm {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::RS256 => Algorithm::RS256,
+            JwtAlgorithm::ES256 => Algorithm::ES256,
+        };
+
+        let keys = match (&config.secret, &config.public_key_file, &config.jwks_file) {
+            (Some(secret), None, None) if algorithm == Algorithm::HS256 => {
+                vec![(None, DecodingKey::from_secret(secret.as_bytes()))]
+            }
+            (None, Some(path), None) if algorithm != Algorithm::HS256 => {
+                let pem = fs::read(path)?;
+                let key = match algorithm {
+                    Algorithm::RS256 => DecodingKey::from_rsa_pem(&pem),
+                    _ => DecodingKey::from_ec_pem(&pem),
+                }
+                .map_err(|e| AppError::InvalidConfig(format!("Invalid JWT public key: {}", e)))?;
+                vec![(None, key)]
+            }
+            (None, None, Some(path)) if algorithm != Algorithm::HS256 => {
+                let jwks: JwkSet = serde_json::from_slice(&fs::read(path)?)?;
+                jwks.keys
+                    .iter()
+                    .map(|jwk| {
+                        DecodingKey::from_jwk(jwk)
+                            .map(|key| (jwk.common.key_id.clone(), key))
+                            .map_err(|e| AppError::InvalidConfig(format!("Invalid JWK: {}", e)))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            _ => {
+                return Err(AppError::InvalidConfig(String::from(
+                    "JWT requires `secret` for HS256, or one of `public_key_file` / `jwks_file` for RS256 and ES256",
+                )));
+            }
+        };
+
+        let mut validation = Validation::new(algorithm);
+        if let Some(ref issuer) = config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(ref audience) = config.audience {
+            validation.set_audience(&[audience]);
+        }
+        if let Some(leeway) = config.leeway_seconds {
+            validation.leeway = leeway;
+        }
+
+        Ok(JwtVerifier { keys, validation })
+    }
+
+    /// Verify the token and map its claims into an identity that expires with the token,
+    /// allowing the same leeway as the verification so an accepted token is not closed at once.
+    pub fn verify(&self, token: &str) -> Result<Identity, AppError> {
+        let header = decode_header(token).map_err(Self::invalid)?;
+        let key = self.find_key(header.kid.as_deref())?;
+        let claims = decode::<Claims>(token, key, &self.validation)
+            .map_err(Self::invalid)?
+            .claims;
+
+        let rules = claims.acl.as_deref().unwrap_or_default();
+        Ok(Identity {
+            permissions: claims.permissions,
+            acl: Acl::new(claims.channels.as_ref(), rules),
+            expires_at: Some(
+                UNIX_EPOCH + Duration::from_secs(claims.exp.saturating_add(self.validation.leeway)),
+            ),
+        })
+    }
+
+    /// A key with a matching `kid`, or the only configured key.
+    fn find_key(&self, kid: Option<&str>) -> Result<&DecodingKey, AppError> {
+        let by_kid = kid.and_then(|kid| {
+            self.keys
+                .iter()
+                .find(|(key_id, _)| key_id.as_deref() == Some(kid))
+        });
+        let found = match (by_kid, self.keys.as_slice()) {
+            (Some(found), _) => Some(found),
+            (None, [single]) => Some(single),
+            _ => None,
+        };
+        found
+            .map(|(_, key)| key)
+            .ok_or_else(|| AppError::PermissionDenied(String::from("Unknown JWT key")))
+    }
+
+    fn invalid(err: jsonwebtoken::errors::Error) -> AppError {
+        AppError::PermissionDenied(format!("Invalid JWT: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode, get_current_timestamp};
+    use serde_json::json;
+
+    const SECRET: &str = "shared-secret";
+
+    fn verifier() -> JwtVerifier {
+        verifier_with_leeway(0)
+    }
+
+    fn verifier_with_leeway(leeway: u64) -> JwtVerifier {
+        JwtVerifier::from_config(&JwtConfig {
+            algorithm: JwtAlgorithm::HS256,
+            secret: Some(String::from(SECRET)),
+            public_key_file: None,
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+            leeway_seconds: Some(leeway),
+        })
+        .unwrap()
+    }
+
+    fn token(claims: serde_json::Value, secret: &str) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let exp = get_current_timestamp() + 60;
+        let token = token(
+            json!({
+                "exp": exp,
+                "permissions": ["Subscribe"],
+                "channels": ["prices.*"],
+            }),
+            SECRET,
+        );
+        let identity = verifier().verify(&token).unwrap();
+        assert!(identity.permissions.contains(&Permission::Subscribe));
+        assert!(
+            identity
+                .acl
+                .allows(&Permission::Subscribe, &String::from("prices.AAPL"))
+        );
+        assert!(
+            !identity
+                .acl
+                .allows(&Permission::Subscribe, &String::from("orders.1"))
+        );
+        assert_eq!(
+            identity.expires_at,
+            Some(UNIX_EPOCH + Duration::from_secs(exp))
+        );
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let token = token(
+            json!({"exp": get_current_timestamp() - 10, "permissions": ["Subscribe"]}),
+            SECRET,
+        );
+        assert!(verifier().verify(&token).is_err());
+    }
+
+    #[test]
+    fn test_expired_token_within_leeway() {
+        let exp = get_current_timestamp() - 10;
+        let token = token(json!({"exp": exp, "permissions": ["Subscribe"]}), SECRET);
+        // accepted, and the connection is not closed before the leeway is over
+        let identity = verifier_with_leeway(60).verify(&token).unwrap();
+        let expires_at = identity.expires_at.unwrap();
+        assert_eq!(expires_at, UNIX_EPOCH + Duration::from_secs(exp + 60));
+        assert!(expires_at > std::time::SystemTime::now());
+    }
+
+    #[test]
+    fn test_wrong_secret() {
+        let token = token(
+            json!({"exp": get_current_timestamp() + 60, "permissions": ["Subscribe"]}),
+            "other-secret",
+        );
+        assert!(verifier().verify(&token).is_err());
+    }
+
+    #[test]
+    fn test_missing_key_config() {
+        let config = JwtConfig {
+            algorithm: JwtAlgorithm::RS256,
+            secret: Some(String::from(SECRET)),
+            public_key_file: None,
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+            leeway_seconds: None,
+        };
+        assert!(JwtVerifier::from_config(&config).is_err());
+    }
+}
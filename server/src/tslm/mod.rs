@@ -1,6 +1,17 @@
+mod acl;
+mod auth;
 mod channel;
 mod directory;
 mod endpoint;
+mod filter;
+mod history;
 mod hub;
+mod jwt;
+mod outgoing;
+mod pattern;
+mod projection;
+mod queue;
 pub mod server;
+mod store;
+mod tls;
 mod websocket;
@@ -0,0 +1,115 @@
+use std::sync::{Arc, OnceLock};
+
+use tungstenite::Message;
+
+use common::error::AppError;
+use common::message::{ChannelId, ChannelMessage, ClientCommand, Frame, Protocol, Sequence};
+
+/// What an endpoint queues for its connection.
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    /// A command for this connection only, encoded by the connection.
+    Command(ClientCommand),
+    /// A published message shared by all the subscribers of a channel.
+    Shared(Arc<SharedMessage>),
+}
+
+/// A published message encoded at most once per protocol, however many subscribers
+/// receive it. The encoded websocket messages are reference counted, so every connection
+/// sends the same bytes without copying them.
+#[derive(Debug)]
+pub struct SharedMessage {
+    command: ClientCommand,
+    legacy: OnceLock<Result<Message, String>>,
+    v2_json: OnceLock<Result<Message, String>>,
+    msgpack: OnceLock<Result<Message, String>>,
+}
+
+impl SharedMessage {
+    pub fn new(command: ClientCommand) -> Self {
+        SharedMessage {
+            command,
+            legacy: OnceLock::new(),
+            v2_json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+        }
+    }
+
+    pub fn command(&self) -> &ClientCommand {
+        &self.command
+    }
+
+    /// The message's sequence number in its channel.
+    pub fn sequence(&self) -> Option<Sequence> {
+        match self.command {
+            ClientCommand::ChannelMessage(_, _, seq)
+            | ClientCommand::ChannelSnapshot(_, _, seq) => Some(seq),
+            _ => None,
+        }
+    }
+
+    /// The channel the message was published to.
+    pub fn channel_id(&self) -> Option<&ChannelId> {
+        match self.command {
+            ClientCommand::ChannelMessage(ref channel_id, _, _)
+            | ClientCommand::ChannelSnapshot(ref channel_id, _, _) => Some(channel_id),
+            _ => None,
+        }
+    }
+
+    /// The published message.
+    pub fn message(&self) -> Option<&ChannelMessage> {
+        match self.command {
+            ClientCommand::ChannelMessage(_, ref message, _)
+            | ClientCommand::ChannelSnapshot(_, ref message, _) => Some(message),
+            _ => None,
+        }
+    }
+
+    /// The message encoded for the given protocol, encoding it on first use.
+    pub fn encoded(&self, protocol: Protocol) -> Result<Message, AppError> {
+        let cell = match protocol {
+            Protocol::Legacy => &self.legacy,
+            Protocol::V2Json => &self.v2_json,
+            Protocol::MessagePack => &self.msgpack,
+        };
+        cell.get_or_init(|| encode(protocol, self.command.clone()).map_err(|e| e.to_string()))
+            .clone()
+            .map_err(AppError::msg)
+    }
+}
+
+/// Encode a command as a websocket message with the connection's protocol.
+pub fn encode(protocol: Protocol, command: ClientCommand) -> Result<Message, AppError> {
+    Ok(match protocol.encode_client_command(command)? {
+        Frame::Text(text) => Message::Text(text.into()),
+        Frame::Binary(data) => Message::Binary(data),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoded_once_per_protocol() {
+        let shared = SharedMessage::new(ClientCommand::ChannelMessage(
+            String::from("test_channel"),
+            ChannelMessage::Text(String::from("hello")),
+            1,
+        ));
+
+        let first = shared.encoded(Protocol::Legacy).unwrap();
+        let second = shared.encoded(Protocol::Legacy).unwrap();
+        // both share the same encoded bytes
+        assert_eq!(
+            first.to_text().unwrap().as_ptr(),
+            second.to_text().unwrap().as_ptr()
+        );
+
+        let v2 = shared.encoded(Protocol::V2Json).unwrap();
+        assert!(v2.to_text().unwrap().starts_with(r#"{"v":2"#));
+        let msgpack = shared.encoded(Protocol::MessagePack).unwrap();
+        assert!(msgpack.is_binary());
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+
+use common::error::AppError;
+use common::message::ChannelId;
+
+/// A NATS style subscription pattern over `.` separated channel ids.
+///
+/// `*` matches exactly one token and a trailing `>` matches one or more tokens, so
+/// `alerts.*` matches `alerts.cpu` but not `alerts.cpu.high`, and `orders.eu.>` matches
+/// both `orders.eu.1` and `orders.eu.1.filled`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SubjectPattern {
+    pattern: String,
+}
+
+const SEPARATOR: char = '.';
+const ANY_TOKEN: &str = "*";
+const ANY_TAIL: &str = ">";
+
+impl SubjectPattern {
+    /// Whether the id has wildcard tokens, such ids name patterns rather than channels.
+    pub fn is_pattern(id: &str) -> bool {
+        id.split(SEPARATOR)
+            .any(|token| token == ANY_TOKEN || token == ANY_TAIL)
+    }
+
+    /// Fails when `>` is not the last token.
+    pub fn new(pattern: impl Into<String>) -> Result<Self, AppError> {
+        let pattern = pattern.into();
+        let mut tokens = pattern.split(SEPARATOR).peekable();
+        while let Some(token) = tokens.next() {
+            if token == ANY_TAIL && tokens.peek().is_some() {
+                return Err(AppError::InvalidMessage(format!(
+                    "'{}' must be the last token of pattern '{}'",
+                    ANY_TAIL, pattern
+                )));
+            }
+        }
+        Ok(SubjectPattern { pattern })
+    }
+
+    pub fn matches(&self, channel_id: &ChannelId) -> bool {
+        let mut candidate = channel_id.split(SEPARATOR);
+        for token in self.pattern.split(SEPARATOR) {
+            match (token, candidate.next()) {
+                (ANY_TAIL, Some(_)) => return true,
+                (ANY_TOKEN, Some(_)) => {}
+                (token, Some(candidate_token)) if token == candidate_token => {}
+                _ => return false,
+            }
+        }
+        candidate.next().is_none()
+    }
+}
+
+impl fmt::Display for SubjectPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, channel_id: &str) -> bool {
+        SubjectPattern::new(pattern)
+            .unwrap()
+            .matches(&channel_id.to_string())
+    }
+
+    #[test]
+    fn test_single_token_wildcard() {
+        assert!(matches("alerts.*", "alerts.cpu"));
+        assert!(!matches("alerts.*", "alerts.cpu.high"));
+        assert!(!matches("alerts.*", "alerts"));
+        assert!(matches("*.eu.*", "orders.eu.1"));
+        assert!(!matches("*.eu.*", "orders.us.1"));
+    }
+
+    #[test]
+    fn test_tail_wildcard() {
+        assert!(matches("orders.eu.>", "orders.eu.1"));
+        assert!(matches("orders.eu.>", "orders.eu.1.filled"));
+        assert!(!matches("orders.eu.>", "orders.eu"));
+        assert!(!matches("orders.eu.>", "orders.us.1"));
+        assert!(matches(">", "anything.at.all"));
+    }
+
+    #[test]
+    fn test_is_pattern() {
+        assert!(SubjectPattern::is_pattern("alerts.*"));
+        assert!(SubjectPattern::is_pattern(">"));
+        // only whole tokens are wildcards
+        assert!(!SubjectPattern::is_pattern("alerts.cpu*"));
+        assert!(!SubjectPattern::is_pattern("prices.AAPL"));
+        assert!(SubjectPattern::new("orders.>.eu").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use serde_json::{Map, Value};
+
+use common::error::AppError;
+use common::message::{ChannelMessage, ClientCommand};
+
+use crate::tslm::outgoing::SharedMessage;
+
+/// The fields of JSON messages a subscriber receives, compiled once when subscribing.
+///
+/// Fields are named by JSON pointers (`/quote/bid`) or `.` separated paths (`quote.bid`)
+/// into nested objects. The projected message keeps their nesting and leaves out the
+/// missing ones. Identical projections compare equal whatever their syntax, so the
+/// fan-out projects a message once for all the subscribers sharing one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Projection {
+    paths: Vec<Vec<String>>,
+}
+
+impl Projection {
+    pub fn compile(fields: &[String]) -> Result<Self, AppError> {
+        if fields.is_empty() {
+            return Err(AppError::InvalidMessage(String::from(
+                "Invalid projection: no fields",
+            )));
+        }
+        let mut paths = fields
+            .iter()
+            .map(|field| parse_path(field))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        paths.dedup();
+        Ok(Projection { paths })
+    }
+
+    /// The message with only the projected fields. Other than JSON messages are kept
+    /// whole, and so is their sequence number.
+    pub fn apply(&self, message: &SharedMessage) -> Option<SharedMessage> {
+        let command = match message.command() {
+            ClientCommand::ChannelMessage(channel_id, ChannelMessage::Json(value), seq) => {
+                ClientCommand::ChannelMessage(
+                    channel_id.clone(),
+                    ChannelMessage::Json(self.project(value)),
+                    *seq,
+                )
+            }
+            ClientCommand::ChannelSnapshot(channel_id, ChannelMessage::Json(value), seq) => {
+                ClientCommand::ChannelSnapshot(
+                    channel_id.clone(),
+                    ChannelMessage::Json(self.project(value)),
+                    *seq,
+                )
+            }
+            _ => return None,
+        };
+        Some(SharedMessage::new(command))
+    }
+
+    fn project(&self, value: &Value) -> Value {
+        let mut projected = Map::new();
+        for path in &self.paths {
+            if let Some(field) = lookup(value, path) {
+                insert(&mut projected, path, field.clone());
+            }
+        }
+        Value::Object(projected)
+    }
+}
+
+fn parse_path(field: &str) -> Result<Vec<String>, AppError> {
+    let path: Vec<String> = match field.strip_prefix('/') {
+        // RFC 6901 escapes
+        Some(pointer) => pointer
+            .split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect(),
+        None => field.split('.').map(String::from).collect(),
+    };
+    if path.iter().any(String::is_empty) {
+        return Err(AppError::InvalidMessage(format!(
+            "Invalid projection field '{}'",
+            field
+        )));
+    }
+    Ok(path)
+}
+
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(value, |value, field| value.as_object()?.get(field))
+}
+
+fn insert(object: &mut Map<String, Value>, path: &[String], value: Value) {
+    match path {
+        [field] => {
+            object.insert(field.clone(), value);
+        }
+        [field, rest @ ..] => {
+            let child = object
+                .entry(field.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+            // A parent and its child both projected, the parent already holds the child.
+            if let Value::Object(child) = child {
+                insert(child, rest, value);
+            }
+        }
+        [] => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn project(fields: &[&str], value: Value) -> Value {
+        let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+        let message = SharedMessage::new(ClientCommand::ChannelMessage(
+            String::from("quotes"),
+            ChannelMessage::Json(value),
+            7,
+        ));
+        match Projection::compile(&fields).unwrap().apply(&message) {
+            Some(projected) => match projected.command() {
+                ClientCommand::ChannelMessage(_, ChannelMessage::Json(value), 7) => value.clone(),
+                _ => panic!("Expected a JSON message with the same sequence number"),
+            },
+            None => panic!("Expected a projected message"),
+        }
+    }
+
+    #[test]
+    fn test_project_fields() {
+        let quote = json!({
+            "symbol": "AAPL",
+            "quote": {"bid": 101, "ask": 102, "venue": {"id": "XNAS", "name": "Nasdaq"}},
+            "a/b": 1,
+        });
+        assert_eq!(
+            project(&["symbol", "/quote/bid", "quote.venue.id"], quote.clone()),
+            json!({"symbol": "AAPL", "quote": {"bid": 101, "venue": {"id": "XNAS"}}})
+        );
+        // missing fields are left out
+        assert_eq!(
+            project(&["symbol", "quote.last"], quote.clone()),
+            json!({"symbol": "AAPL"})
+        );
+        // a parent includes its children
+        assert_eq!(
+            project(&["quote.venue", "/quote/venue/id"], quote.clone()),
+            json!({"quote": {"venue": {"id": "XNAS", "name": "Nasdaq"}}})
+        );
+        assert_eq!(project(&["/a~1b"], quote), json!({"a/b": 1}));
+    }
+
+    #[test]
+    fn test_identical_projections() {
+        let fields = |fields: &[&str]| -> Vec<String> {
+            fields.iter().map(|field| field.to_string()).collect()
+        };
+        assert_eq!(
+            Projection::compile(&fields(&["symbol", "quote.bid"])).unwrap(),
+            Projection::compile(&fields(&["/quote/bid", "symbol", "symbol"])).unwrap()
+        );
+        assert!(Projection::compile(&[]).is_err());
+        assert!(Projection::compile(&fields(&["quote..bid"])).is_err());
+
+        let text = SharedMessage::new(ClientCommand::ChannelMessage(
+            String::from("quotes"),
+            ChannelMessage::Text(String::from("hello")),
+            1,
+        ));
+        assert!(
+            Projection::compile(&fields(&["symbol"]))
+                .unwrap()
+                .apply(&text)
+                .is_none()
+        );
+    }
+}
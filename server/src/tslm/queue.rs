@@ -0,0 +1,596 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use tracing::debug;
+
+use common::error::AppError;
+use common::message::ChannelId;
+
+use crate::settings::SlowConsumerPolicy;
+use crate::tslm::outgoing::{Outgoing, SharedMessage};
+
+/// What a conflated message replaces: the previous message of its channel, or of its
+/// channel with the same key value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConflationKey {
+    pub channel_id: ChannelId,
+    pub key: Option<String>,
+}
+
+/// A published message held back until its channel's next update, replaced by newer
+/// messages with the same key meanwhile.
+struct Conflated {
+    interval: Duration,
+    message: Arc<SharedMessage>,
+}
+
+/// Queue of the messages waiting to be written to a connection.
+///
+/// Only published channel messages count towards the capacity and are subject to the
+/// slow-consumer policy; replies to the endpoint's own commands are queued until
+/// `MAX_QUEUED_COMMANDS` of them wait, then the consumer is disconnected as a slow
+/// consumer whatever the policy.
+/// Messages of conflated channels wait apart, at most one per key, and are released
+/// together at most once per the channel's interval. Each waiting key counts towards the
+/// capacity too, a new key arriving at a full queue is subject to the policy.
+pub struct OutgoingQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: Option<usize>,
+    policy: SlowConsumerPolicy,
+    dropped: AtomicU64,
+}
+
+/// Most replies and notices waiting for a consumer that keeps sending commands but does
+/// not read.
+const MAX_QUEUED_COMMANDS: usize = 1024;
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<Outgoing>,
+    /// Published messages in `items`.
+    shared: usize,
+    /// Replies and notices in `items`.
+    commands: usize,
+    closed: bool,
+    /// Closed because the consumer fell behind, under `SlowConsumerPolicy::Disconnect` or
+    /// with too many replies waiting.
+    overflowed: bool,
+    /// Messages of conflated channels waiting for their channel's next update.
+    conflated: HashMap<ConflationKey, Conflated>,
+    /// The keys of the waiting messages, in the order they started waiting.
+    conflated_order: BTreeMap<u64, ConflationKey>,
+    next_order: u64,
+    /// Number of waiting messages of each conflated channel.
+    waiting: HashMap<ChannelId, usize>,
+    /// When each conflated channel may be updated again.
+    next_update: HashMap<ChannelId, Instant>,
+}
+
+impl QueueState {
+    fn push(&mut self, msg: Outgoing) {
+        match msg {
+            Outgoing::Shared(_) => self.shared += 1,
+            Outgoing::Command(_) => self.commands += 1,
+        }
+        self.items.push_back(msg);
+    }
+
+    fn pop(&mut self) -> Option<Outgoing> {
+        let msg = self.items.pop_front()?;
+        match msg {
+            Outgoing::Shared(_) => self.shared -= 1,
+            Outgoing::Command(_) => self.commands -= 1,
+        }
+        Some(msg)
+    }
+
+    /// Published messages queued or waiting for their conflated channel's next update.
+    fn published(&self) -> usize {
+        self.shared + self.conflated.len()
+    }
+
+    /// Hold back a message of a conflated channel, replacing the waiting one with the same
+    /// key in its place.
+    fn wait_conflated(
+        &mut self,
+        key: ConflationKey,
+        interval: Duration,
+        message: Arc<SharedMessage>,
+    ) {
+        if let Some(waiting) = self.conflated.get_mut(&key) {
+            waiting.message = message;
+            return;
+        }
+        let order = self.next_order;
+        self.next_order += 1;
+        *self.waiting.entry(key.channel_id.clone()).or_default() += 1;
+        self.conflated_order.insert(order, key.clone());
+        self.conflated.insert(key, Conflated { interval, message });
+    }
+
+    /// Remove the waiting message at the given position.
+    fn take_conflated(&mut self, order: u64) -> Option<(ConflationKey, Conflated)> {
+        let key = self.conflated_order.remove(&order)?;
+        let conflated = self.conflated.remove(&key)?;
+        if let Some(count) = self.waiting.get_mut(&key.channel_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.waiting.remove(&key.channel_id);
+            }
+        }
+        Some((key, conflated))
+    }
+
+    /// Queue the waiting messages of the conflated channels that may be updated again,
+    /// all the keys of a channel in the same update.
+    fn release_conflated(&mut self, now: Instant) {
+        self.next_update.retain(|_, next_update| *next_update > now);
+        let ready = self
+            .waiting
+            .keys()
+            .any(|channel_id| !self.next_update.contains_key(channel_id));
+        if !ready {
+            return;
+        }
+        let released: Vec<u64> = self
+            .conflated_order
+            .iter()
+            .filter(|(_, key)| !self.next_update.contains_key(&key.channel_id))
+            .map(|(order, _)| *order)
+            .collect();
+        let mut updated = HashMap::new();
+        for order in released {
+            if let Some((key, conflated)) = self.take_conflated(order) {
+                updated.insert(key.channel_id, now + conflated.interval);
+                self.push(Outgoing::Shared(conflated.message));
+            }
+        }
+        self.next_update.extend(updated);
+    }
+
+    /// When the next waiting conflated message may be released.
+    fn next_release(&self) -> Option<Instant> {
+        self.waiting
+            .keys()
+            .filter_map(|channel_id| self.next_update.get(channel_id))
+            .min()
+            .copied()
+    }
+
+    /// Remove the oldest queued published message or, with none queued, the oldest waiting
+    /// conflated one.
+    fn drop_oldest(&mut self) {
+        let oldest = self
+            .items
+            .iter()
+            .position(|msg| matches!(msg, Outgoing::Shared(_)));
+        if let Some(position) = oldest {
+            self.items.remove(position);
+            self.shared -= 1;
+        } else if let Some(order) = self.conflated_order.keys().next().copied() {
+            self.take_conflated(order);
+        }
+    }
+
+    /// Drop every message, when the queue closes.
+    fn clear(&mut self) {
+        self.items.clear();
+        self.shared = 0;
+        self.commands = 0;
+        self.conflated.clear();
+        self.conflated_order.clear();
+        self.waiting.clear();
+    }
+
+    /// Replace the queued message of the same channel, returns the message back when the
+    /// channel has none queued.
+    fn conflate(&mut self, msg: Outgoing) -> Result<(), Outgoing> {
+        let Outgoing::Shared(ref shared) = msg else {
+            return Err(msg);
+        };
+        let queued = self.items.iter_mut().rev().find(|queued| match queued {
+            Outgoing::Shared(queued) => queued.channel_id() == shared.channel_id(),
+            Outgoing::Command(_) => false,
+        });
+        match queued {
+            Some(queued) => {
+                *queued = msg;
+                Ok(())
+            }
+            None => Err(msg),
+        }
+    }
+}
+
+impl OutgoingQueue {
+    /// A queue holding at most `capacity` published messages, or unbounded when `None`.
+    pub fn new(
+        capacity: Option<usize>,
+        policy: SlowConsumerPolicy,
+    ) -> (Arc<OutgoingQueue>, OutgoingReceiver) {
+        let queue = Arc::new(OutgoingQueue {
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+        });
+        let receiver = OutgoingReceiver {
+            queue: Arc::clone(&queue),
+        };
+        (queue, receiver)
+    }
+
+    /// Queue a message without waiting. Fails when the connection is gone, or when the
+    /// consumer is disconnected for falling behind.
+    pub fn try_send(&self, msg: Outgoing) -> Result<(), AppError> {
+        let mut state = self.state.lock()?;
+        if state.closed {
+            return Err(AppError::ChannelSend("connection closed".to_string()));
+        }
+
+        if matches!(msg, Outgoing::Command(_)) && state.commands >= MAX_QUEUED_COMMANDS {
+            debug!("Outgoing queue holds too many replies, disconnecting");
+            return Err(self.disconnect(&mut state));
+        }
+        if matches!(msg, Outgoing::Shared(_)) && self.is_full(&state) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            let msg = match self.policy {
+                SlowConsumerPolicy::ConflateLatest => match state.conflate(msg) {
+                    Ok(()) => {
+                        debug!("Outgoing queue full, conflated to the latest message");
+                        drop(state);
+                        self.notify.notify_one();
+                        return Ok(());
+                    }
+                    // Nothing queued for this channel, fall back to dropping the oldest.
+                    Err(msg) => msg,
+                },
+                _ => msg,
+            };
+            if !self.make_room(&mut state)? {
+                return Ok(());
+            }
+            state.push(msg);
+        } else {
+            state.push(msg);
+        }
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    fn is_full(&self, state: &QueueState) -> bool {
+        self.capacity
+            .is_some_and(|capacity| state.published() >= capacity)
+    }
+
+    /// Apply the slow-consumer policy to a published message arriving at a full queue,
+    /// returns whether the message may be queued.
+    fn make_room(&self, state: &mut QueueState) -> Result<bool, AppError> {
+        match self.policy {
+            SlowConsumerPolicy::DropNewest => {
+                debug!("Outgoing queue full, dropping the newest message");
+                Ok(false)
+            }
+            SlowConsumerPolicy::DropOldest | SlowConsumerPolicy::ConflateLatest => {
+                debug!("Outgoing queue full, dropping the oldest message");
+                state.drop_oldest();
+                Ok(true)
+            }
+            SlowConsumerPolicy::Disconnect => Err(self.disconnect(state)),
+        }
+    }
+
+    /// Close the queue of a consumer that fell behind, its connection is closed with the
+    /// slow-consumer close code.
+    fn disconnect(&self, state: &mut QueueState) -> AppError {
+        state.closed = true;
+        state.overflowed = true;
+        state.clear();
+        self.notify.notify_one();
+        AppError::ChannelSend("slow consumer".to_string())
+    }
+
+    /// Queue published messages without waiting, all of them when they fit in the capacity
+    /// and none otherwise, bypassing the slow-consumer policy. Returns whether they were
+    /// queued.
+    pub fn try_send_all(&self, messages: Vec<Arc<SharedMessage>>) -> Result<bool, AppError> {
+        let mut state = self.state.lock()?;
+        if state.closed {
+            return Err(AppError::ChannelSend("connection closed".to_string()));
+        }
+        let fits = self
+            .capacity
+            .is_none_or(|capacity| state.published() + messages.len() <= capacity);
+        if !fits {
+            return Ok(false);
+        }
+        for message in messages {
+            state.push(Outgoing::Shared(message));
+        }
+        drop(state);
+        self.notify.notify_one();
+        Ok(true)
+    }
+
+    /// Queue a message of a conflated channel without waiting. It replaces the waiting
+    /// message with the same key, if any, and is released once the channel's last update
+    /// is `interval` old. A new key is subject to the slow-consumer policy when the queue is
+    /// full.
+    pub fn try_send_conflated(
+        &self,
+        key: ConflationKey,
+        interval: Duration,
+        message: Arc<SharedMessage>,
+    ) -> Result<(), AppError> {
+        let mut state = self.state.lock()?;
+        if state.closed {
+            return Err(AppError::ChannelSend("connection closed".to_string()));
+        }
+        if !state.conflated.contains_key(&key) && self.is_full(&state) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            if !self.make_room(&mut state)? {
+                return Ok(());
+            }
+        }
+        state.wait_conflated(key, interval, message);
+        drop(state);
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Number of published messages dropped or conflated because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+            state.clear();
+        }
+        self.notify.notify_one();
+    }
+}
+
+/// The connection side of an `OutgoingQueue`, closing the queue when dropped.
+pub struct OutgoingReceiver {
+    queue: Arc<OutgoingQueue>,
+}
+
+impl OutgoingReceiver {
+    /// Wait for the next message, `None` once the queue is closed.
+    pub async fn recv(&mut self) -> Option<Outgoing> {
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Some(msg);
+            }
+            if self.is_closed() {
+                return None;
+            }
+            // A notification sent while no one waits is kept for the next call.
+            match self.next_release() {
+                Some(next_release) => {
+                    tokio::select! {
+                        _ = self.queue.notify.notified() => {}
+                        _ = tokio::time::sleep_until(next_release.into()) => {}
+                    }
+                }
+                None => self.queue.notify.notified().await,
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Outgoing> {
+        let mut state = self.queue.state.lock().ok()?;
+        state.release_conflated(Instant::now());
+        state.pop()
+    }
+
+    fn next_release(&self) -> Option<Instant> {
+        self.queue.state.lock().ok()?.next_release()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.queue.state.lock().map_or(true, |state| state.closed)
+    }
+
+    /// Whether the queue was closed because the consumer fell behind.
+    pub fn overflowed(&self) -> bool {
+        self.queue.state.lock().is_ok_and(|state| state.overflowed)
+    }
+}
+
+impl Drop for OutgoingReceiver {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tslm::outgoing::SharedMessage;
+    use common::message::{ChannelMessage, ClientCommand};
+
+    fn published(channel_id: &str, text: &str) -> Outgoing {
+        Outgoing::Shared(Arc::new(SharedMessage::new(ClientCommand::ChannelMessage(
+            channel_id.to_string(),
+            ChannelMessage::Text(text.to_string()),
+            1,
+        ))))
+    }
+
+    fn text_of(msg: Option<Outgoing>) -> String {
+        match msg {
+            Some(Outgoing::Shared(shared)) => match shared.command() {
+                ClientCommand::ChannelMessage(_, ChannelMessage::Text(text), _) => text.clone(),
+                _ => panic!("Expected a text message"),
+            },
+            _ => panic!("Expected a published message"),
+        }
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let (queue, mut rx) = OutgoingQueue::new(Some(2), SlowConsumerPolicy::DropOldest);
+        for text in ["1", "2", "3"] {
+            queue.try_send(published("a", text)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(text_of(rx.try_recv()), "2");
+        assert_eq!(text_of(rx.try_recv()), "3");
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (queue, mut rx) = OutgoingQueue::new(Some(2), SlowConsumerPolicy::DropNewest);
+        for text in ["1", "2", "3"] {
+            queue.try_send(published("a", text)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(text_of(rx.try_recv()), "1");
+        assert_eq!(text_of(rx.try_recv()), "2");
+    }
+
+    #[test]
+    fn test_conflate_latest() {
+        let (queue, mut rx) = OutgoingQueue::new(Some(2), SlowConsumerPolicy::ConflateLatest);
+        queue.try_send(published("a", "a1")).unwrap();
+        queue.try_send(published("b", "b1")).unwrap();
+        queue.try_send(published("a", "a2")).unwrap();
+        assert_eq!(queue.dropped(), 1);
+        // the latest value of `a` takes the place of the previous one
+        assert_eq!(text_of(rx.try_recv()), "a2");
+        assert_eq!(text_of(rx.try_recv()), "b1");
+    }
+
+    fn conflate(queue: &OutgoingQueue, key: Option<&str>, text: &str) {
+        let Outgoing::Shared(message) = published("a", text) else {
+            unreachable!()
+        };
+        let key = ConflationKey {
+            channel_id: String::from("a"),
+            key: key.map(String::from),
+        };
+        queue
+            .try_send_conflated(key, Duration::from_millis(50), message)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_conflated_channel() {
+        let (queue, mut rx) = OutgoingQueue::new(None, SlowConsumerPolicy::default());
+        for text in ["1", "2", "3"] {
+            conflate(&queue, None, text);
+        }
+        // only the latest message waiting for the first update
+        assert_eq!(text_of(rx.try_recv()), "3");
+        assert!(rx.try_recv().is_none());
+
+        conflate(&queue, None, "4");
+        conflate(&queue, None, "5");
+        // the next update waits for the interval
+        assert!(rx.try_recv().is_none());
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(text_of(rx.try_recv()), "5");
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_keyed_conflation() {
+        let (queue, mut rx) = OutgoingQueue::new(None, SlowConsumerPolicy::default());
+        conflate(&queue, Some("AAPL"), "AAPL 1");
+        conflate(&queue, Some("MSFT"), "MSFT 1");
+        conflate(&queue, Some("AAPL"), "AAPL 2");
+        // the latest message of every key, in one update
+        assert_eq!(text_of(rx.try_recv()), "AAPL 2");
+        assert_eq!(text_of(rx.try_recv()), "MSFT 1");
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_conflated_keys_count_towards_capacity() {
+        let (queue, mut rx) = OutgoingQueue::new(Some(2), SlowConsumerPolicy::DropNewest);
+        conflate(&queue, Some("AAPL"), "AAPL 1");
+        conflate(&queue, Some("MSFT"), "MSFT 1");
+        conflate(&queue, Some("IBM"), "IBM 1");
+        // replacing a waiting key takes no room
+        conflate(&queue, Some("AAPL"), "AAPL 2");
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(text_of(rx.try_recv()), "AAPL 2");
+        assert_eq!(text_of(rx.try_recv()), "MSFT 1");
+        assert!(rx.try_recv().is_none());
+
+        let (queue, mut rx) = OutgoingQueue::new(Some(2), SlowConsumerPolicy::DropOldest);
+        conflate(&queue, Some("AAPL"), "AAPL 1");
+        conflate(&queue, Some("MSFT"), "MSFT 1");
+        conflate(&queue, Some("IBM"), "IBM 1");
+        assert_eq!(text_of(rx.try_recv()), "MSFT 1");
+        assert_eq!(text_of(rx.try_recv()), "IBM 1");
+
+        let (queue, rx) = OutgoingQueue::new(Some(1), SlowConsumerPolicy::Disconnect);
+        conflate(&queue, Some("AAPL"), "AAPL 1");
+        let Outgoing::Shared(message) = published("a", "MSFT 1") else {
+            unreachable!()
+        };
+        let key = ConflationKey {
+            channel_id: String::from("a"),
+            key: Some(String::from("MSFT")),
+        };
+        assert!(
+            queue
+                .try_send_conflated(key, Duration::from_millis(50), message)
+                .is_err()
+        );
+        assert!(rx.overflowed());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (queue, rx) = OutgoingQueue::new(Some(1), SlowConsumerPolicy::Disconnect);
+        queue.try_send(published("a", "1")).unwrap();
+        assert!(queue.try_send(published("a", "2")).is_err());
+        assert!(rx.overflowed());
+        assert!(queue.try_send(published("a", "3")).is_err());
+    }
+
+    #[test]
+    fn test_commands_bypass_capacity() {
+        let (queue, mut rx) = OutgoingQueue::new(Some(1), SlowConsumerPolicy::DropNewest);
+        queue.try_send(published("a", "1")).unwrap();
+        queue
+            .try_send(Outgoing::Command(ClientCommand::ChannelClosed(
+                String::from("a"),
+            )))
+            .unwrap();
+        assert_eq!(queue.dropped(), 0);
+        assert!(rx.try_recv().is_some());
+        assert!(rx.try_recv().is_some());
+    }
+
+    #[test]
+    fn test_disconnect_on_unread_replies() {
+        let (queue, rx) = OutgoingQueue::new(None, SlowConsumerPolicy::DropNewest);
+        let closed = || Outgoing::Command(ClientCommand::ChannelClosed(String::from("a")));
+        for _ in 0..MAX_QUEUED_COMMANDS {
+            queue.try_send(closed()).unwrap();
+        }
+        assert!(!rx.overflowed());
+        assert!(queue.try_send(closed()).is_err());
+        assert!(rx.overflowed());
+    }
+
+    #[test]
+    fn test_closed_when_receiver_dropped() {
+        let (queue, rx) = OutgoingQueue::new(None, SlowConsumerPolicy::default());
+        drop(rx);
+        assert!(queue.try_send(published("a", "1")).is_err());
+    }
+}
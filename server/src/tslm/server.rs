@@ -1,6 +1,7 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use crossbeam::channel::Sender;
 use tokio::runtime::Builder as TokioRtBuilder;
@@ -9,8 +10,14 @@ use tracing::{error, info};
 
 use common::error::AppError;
 
-use crate::settings::Settings;
+use crate::settings::{FsyncPolicy, Settings};
+use crate::tslm::acl::Acl;
+use crate::tslm::auth::{Authenticator, Identity};
+use crate::tslm::directory::Directory;
 use crate::tslm::hub::{EndpointFactorySettings, Hub};
+use crate::tslm::jwt::JwtVerifier;
+use crate::tslm::store::FileStorage;
+use crate::tslm::tls::ListenerTls;
 use crate::tslm::websocket::{WebSocketServerConfig, WebsocketServer};
 
 pub struct Builder {
@@ -40,7 +47,34 @@ impl LastMileServer {
             .map_err(AppError::from)?;
         let runtime = Arc::new(runtime);
 
-        let hub = Arc::new(Hub::new());
+        let directory = match settings.storage {
+            Some(storage_config) => {
+                info!(
+                    "Keeping durable channels in '{}'",
+                    storage_config.path.display()
+                );
+                Directory::with_storage(FileStorage::new(storage_config))?
+            }
+            None => Directory::new(),
+        };
+        let directory = Arc::new(directory);
+        if let Some(storage) = directory
+            .storage()
+            .filter(|storage| storage.fsync_policy() == FsyncPolicy::Periodic)
+        {
+            let period = Duration::from_millis(storage.fsync_interval_ms().max(1));
+            let directory = Arc::clone(&directory);
+            runtime.spawn(async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    let directory = Arc::clone(&directory);
+                    let _ = tokio::task::spawn_blocking(move || directory.sync()).await;
+                }
+            });
+        }
+
+        let hub = Arc::new(Hub::new(directory));
         let ws_rt = Arc::clone(&runtime);
 
         let mut listeners = Vec::new();
@@ -53,27 +87,48 @@ impl LastMileServer {
             let max_message_size = listener_config.get_max_message_size();
             let max_frame_size = listener_config.get_max_frame_size();
             let channel_buffer_size = listener_config.channel_buffer_size;
+            let slow_consumer_policy = listener_config.slow_consumer_policy.unwrap_or_default();
+            let auto_create_channels = listener_config.auto_create_channels.unwrap_or_default();
             let auth_tokens = listener_config.auth_tokens.clone();
+            let tokens = listener_config.tokens.clone().unwrap_or_default();
+            let jwt_config = listener_config.jwt.clone();
+            let client_certs = listener_config.client_certs.clone().unwrap_or_default();
+            let default_acl = Acl::new(None, listener_config.acl.as_deref().unwrap_or_default());
             let max_connections = listener_config.max_connections;
             let rate_limit_per_second = listener_config.rate_limit_per_second;
+            let tls = ListenerTls::from_config(&listener_config)?.map(Arc::new);
             // Move this last since unwrap_or_default moves the field
             let default_permissions = listener_config
                 .default_endpoint_permissions
                 .unwrap_or_default();
 
-            let endpoint_factory_settings = EndpointFactorySettings {
-                default_endpoint_permissions: default_permissions,
+            let endpoint_factory_settings = Arc::new(EndpointFactorySettings {
                 channel_buffer_size,
-            };
+                slow_consumer_policy,
+                auto_create_channels,
+            });
 
-            let ws_config = WebSocketServerConfig {
+            let mut authenticator = Authenticator::new(
+                Identity::new(default_permissions, default_acl),
                 auth_tokens,
+                tokens,
+            )
+            .with_client_certs(&client_certs);
+            if let Some(ref jwt_config) = jwt_config {
+                authenticator = authenticator.with_jwt(JwtVerifier::from_config(jwt_config)?);
+            }
+            let authenticator = Arc::new(authenticator);
+
+            let ws_config = WebSocketServerConfig {
+                authenticator,
                 max_message_size,
                 max_frame_size,
                 max_connections,
                 rate_limit_per_second,
+                tls,
             };
 
+            let scheme = if ws_config.tls.is_some() { "wss" } else { "ws" };
             let websocket_listener = WebsocketServer::new(
                 listener_rt,
                 address,
@@ -81,7 +136,10 @@ impl LastMileServer {
                 endpoint_factory_settings,
                 ws_config,
             );
-            info!("Running websocket listener '{}' on: {}", name, address);
+            info!(
+                "Running websocket listener '{}' on: {}://{}",
+                name, scheme, address
+            );
             listeners.push(websocket_listener);
         }
 
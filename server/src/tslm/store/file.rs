@@ -0,0 +1,667 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use common::error::AppError;
+use common::message::{ChannelId, ChannelMessage, ChannelOptions, ClientCommand, Sequence};
+
+use crate::settings::{FsyncPolicy, StorageConfig};
+use crate::tslm::outgoing::SharedMessage;
+use crate::tslm::store::MessageStore;
+
+const METADATA_FILE_NAME: &str = "channel.json";
+const SEGMENT_EXTENSION: &str = "log";
+/// Payload length and CRC32, both little endian `u32`.
+const RECORD_HEADER_LEN: u64 = 8;
+/// Read positions kept for replays continuing with their next batch.
+const MAX_READ_POSITIONS: usize = 64;
+
+/// The logs of the durable channels, a directory per channel under `StorageConfig::path`.
+pub struct FileStorage {
+    config: Arc<StorageConfig>,
+}
+
+/// What is needed to recreate a durable channel, kept next to its log segments.
+#[derive(Serialize, Deserialize)]
+struct Metadata {
+    channel_id: ChannelId,
+    options: ChannelOptions,
+}
+
+impl FileStorage {
+    pub fn new(config: StorageConfig) -> Self {
+        FileStorage {
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.config.fsync.unwrap_or_default()
+    }
+
+    pub fn fsync_interval_ms(&self) -> u64 {
+        self.config.get_fsync_interval_ms()
+    }
+
+    /// Open the log of a durable channel being created.
+    pub fn create(
+        &self,
+        channel_id: &ChannelId,
+        options: &ChannelOptions,
+    ) -> Result<FileStore, AppError> {
+        let dir = self.config.path.join(dir_name(channel_id));
+        fs::create_dir_all(&dir)?;
+        let metadata = Metadata {
+            channel_id: channel_id.clone(),
+            options: options.clone(),
+        };
+        // Written aside and renamed, so a crash never leaves a partial file behind.
+        let tmp_path = dir.join(format!("{}.tmp", METADATA_FILE_NAME));
+        fs::write(&tmp_path, serde_json::to_vec(&metadata)?)?;
+        fs::rename(&tmp_path, dir.join(METADATA_FILE_NAME))?;
+        FileStore::open(channel_id.clone(), dir, Arc::clone(&self.config))
+    }
+
+    /// The durable channels found on disk, with their options and logs.
+    ///
+    /// A channel whose metadata or log cannot be read is skipped and left on disk, so one
+    /// corrupt channel does not keep the server from starting. Channels created with
+    /// `delete_on_disconnect` lost their owner with the previous process, their logs are
+    /// removed instead of recovered.
+    pub fn recover(&self) -> Result<Vec<(ChannelId, ChannelOptions, FileStore)>, AppError> {
+        fs::create_dir_all(&self.config.path)?;
+        let mut channels = Vec::new();
+        for entry in fs::read_dir(&self.config.path)? {
+            let dir = entry?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            let data = match fs::read(dir.join(METADATA_FILE_NAME)) {
+                Ok(data) => data,
+                Err(err) => {
+                    warn!("Skipping '{}', not a channel log: {}", dir.display(), err);
+                    continue;
+                }
+            };
+            let metadata: Metadata = match serde_json::from_slice(&data) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    error!(
+                        "Skipping '{}', corrupt channel metadata: {}",
+                        dir.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            if metadata.options.delete_on_disconnect {
+                info!(
+                    "Removing the log of '{}', its owner disconnected",
+                    metadata.channel_id
+                );
+                if let Err(err) = fs::remove_dir_all(&dir) {
+                    error!("Error removing '{}': {}", dir.display(), err);
+                }
+                continue;
+            }
+            match FileStore::open(metadata.channel_id.clone(), dir, Arc::clone(&self.config)) {
+                Ok(store) => channels.push((metadata.channel_id, metadata.options, store)),
+                Err(err) => {
+                    error!("Skipping channel '{}': {}", metadata.channel_id, err);
+                }
+            }
+        }
+        Ok(channels)
+    }
+}
+
+/// A file name for the channel id, escaping anything but ASCII letters, digits, `-`, `_`
+/// and non leading `.` as `%XX`.
+fn dir_name(channel_id: &str) -> String {
+    let mut name = String::with_capacity(channel_id.len());
+    for (i, byte) in channel_id.bytes().enumerate() {
+        let keep =
+            byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' || (byte == b'.' && i > 0);
+        if keep {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if name.is_empty() {
+        // `%` alone cannot be the escaped name of any other id.
+        name.push('%');
+    }
+    name
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record<M> {
+    seq: Sequence,
+    timestamp_ms: u64,
+    message: M,
+}
+
+fn encode_record(seq: Sequence, message: &ChannelMessage) -> Result<(Vec<u8>, u64), AppError> {
+    let timestamp_ms = now_ms();
+    let payload = rmp_serde::to_vec_named(&Record {
+        seq,
+        timestamp_ms,
+        message,
+    })
+    .map_err(|err| AppError::msg(err.to_string()))?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| AppError::msg_str("message too large for the channel log"))?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok((record, timestamp_ms))
+}
+
+/// Reads the records of a segment up to `len` bytes, stopping early at the first
+/// incomplete or corrupt one.
+struct RecordReader {
+    reader: BufReader<File>,
+    len: u64,
+    offset: u64,
+}
+
+impl RecordReader {
+    fn open(path: &Path, len: u64) -> Result<Self, AppError> {
+        RecordReader::open_at(path, len, 0)
+    }
+
+    /// Start reading at `offset`, which must be the start of a record.
+    fn open_at(path: &Path, len: u64, offset: u64) -> Result<Self, AppError> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(RecordReader {
+            reader: BufReader::new(file),
+            len,
+            offset,
+        })
+    }
+
+    fn next_record(&mut self) -> Option<Record<ChannelMessage>> {
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        if self.offset + RECORD_HEADER_LEN > self.len {
+            return None;
+        }
+        self.reader.read_exact(&mut header).ok()?;
+        let [l0, l1, l2, l3, c0, c1, c2, c3] = header;
+        let payload_len = u64::from(u32::from_le_bytes([l0, l1, l2, l3]));
+        let crc = u32::from_le_bytes([c0, c1, c2, c3]);
+        if self.offset + RECORD_HEADER_LEN + payload_len > self.len {
+            return None;
+        }
+        let mut payload = vec![0u8; payload_len as usize];
+        self.reader.read_exact(&mut payload).ok()?;
+        if crc32fast::hash(&payload) != crc {
+            return None;
+        }
+        let record = rmp_serde::from_slice(&payload).ok()?;
+        self.offset += RECORD_HEADER_LEN + payload_len;
+        Some(record)
+    }
+}
+
+struct Segment {
+    path: PathBuf,
+    first_seq: Sequence,
+    /// `None` until the first record is written.
+    last_seq: Option<Sequence>,
+    bytes: u64,
+    last_timestamp_ms: u64,
+}
+
+/// Append-only log of a durable channel, split in segment files named after the sequence
+/// number of their first message. Retention deletes whole segments, never the one being
+/// written.
+pub struct FileStore {
+    channel_id: ChannelId,
+    dir: PathBuf,
+    config: Arc<StorageConfig>,
+    /// Oldest first, the last one is being written.
+    segments: VecDeque<Segment>,
+    active: Option<File>,
+    unsynced: bool,
+    /// Where the record of a sequence number starts, by the `first_seq` of its segment and
+    /// a byte offset, for the replays that stopped before it.
+    read_positions: HashMap<Sequence, (Sequence, u64)>,
+}
+
+impl FileStore {
+    /// Open the log in the given directory, truncating a record left incomplete by a crash.
+    fn open(
+        channel_id: ChannelId,
+        dir: PathBuf,
+        config: Arc<StorageConfig>,
+    ) -> Result<Self, AppError> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+                let first_seq = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<Sequence>().ok());
+                match first_seq {
+                    Some(first_seq) => paths.push((first_seq, path)),
+                    None => warn!("Ignoring unexpected file '{}'", path.display()),
+                }
+            }
+        }
+        paths.sort();
+
+        let mut segments = VecDeque::<Segment>::new();
+        for (first_seq, path) in paths {
+            let file_len = fs::metadata(&path)?.len();
+            let mut reader = RecordReader::open(&path, file_len)?;
+            let mut last_seq = None;
+            let mut last_timestamp_ms = 0;
+            while let Some(record) = reader.next_record() {
+                last_seq = Some(record.seq);
+                last_timestamp_ms = record.timestamp_ms;
+            }
+            if reader.offset < file_len {
+                warn!(
+                    "Truncating '{}' of channel '{}' to {} bytes, dropping an incomplete or corrupt record",
+                    path.display(),
+                    channel_id,
+                    reader.offset
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(reader.offset)?;
+            }
+            if last_seq.is_none() {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let contiguous = segments
+                .back()
+                .and_then(|previous| previous.last_seq)
+                .is_none_or(|previous_last| previous_last + 1 == first_seq);
+            if !contiguous {
+                // Replaying across the hole would silently skip messages, treat the older
+                // segments as evicted instead.
+                warn!(
+                    "Channel '{}' log has a hole before sequence {}, dropping the older segments",
+                    channel_id, first_seq
+                );
+                for segment in segments.drain(..) {
+                    fs::remove_file(&segment.path)?;
+                }
+            }
+            segments.push_back(Segment {
+                path,
+                first_seq,
+                last_seq,
+                bytes: reader.offset,
+                last_timestamp_ms,
+            });
+        }
+
+        let active = match segments.back() {
+            Some(segment) => Some(OpenOptions::new().append(true).open(&segment.path)?),
+            None => None,
+        };
+        Ok(FileStore {
+            channel_id,
+            dir,
+            config,
+            segments,
+            active,
+            unsynced: false,
+            read_positions: HashMap::new(),
+        })
+    }
+
+    /// Start a new segment with the message of the given sequence number.
+    fn roll(&mut self, seq: Sequence) -> Result<(), AppError> {
+        self.sync()?;
+        let path = self.dir.join(format!("{:020}.{}", seq, SEGMENT_EXTENSION));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if self.config.fsync.unwrap_or_default() == FsyncPolicy::Always {
+            File::open(&self.dir)?.sync_all()?;
+        }
+        self.active = Some(file);
+        self.segments.push_back(Segment {
+            path,
+            first_seq: seq,
+            last_seq: None,
+            bytes: 0,
+            last_timestamp_ms: now_ms(),
+        });
+        Ok(())
+    }
+
+    /// Delete the oldest segments beyond `retention_bytes` or `retention_seconds`.
+    fn apply_retention(&mut self) -> Result<(), AppError> {
+        let now = now_ms();
+        let max_age_ms = self.config.retention_seconds.map(|secs| secs * 1000);
+        while self.segments.len() > 1 {
+            let total_bytes: u64 = self.segments.iter().map(|segment| segment.bytes).sum();
+            let too_big = self
+                .config
+                .retention_bytes
+                .is_some_and(|max| total_bytes > max);
+            let too_old = self.segments.front().is_some_and(|oldest| {
+                max_age_ms.is_some_and(|max| now.saturating_sub(oldest.last_timestamp_ms) > max)
+            });
+            if !(too_big || too_old) {
+                break;
+            }
+            if let Some(segment) = self.segments.pop_front() {
+                debug!(
+                    "Deleting segment '{}' of channel '{}'",
+                    segment.path.display(),
+                    self.channel_id
+                );
+                fs::remove_file(&segment.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageStore for FileStore {
+    fn append(&mut self, seq: Sequence, message: &Arc<SharedMessage>) -> Result<(), AppError> {
+        let ClientCommand::ChannelMessage(_, ref channel_message, _) = *message.command() else {
+            return Err(AppError::msg_str("only channel messages can be logged"));
+        };
+        let (record, timestamp_ms) = encode_record(seq, channel_message)?;
+        let segment_bytes = self.config.get_segment_bytes();
+        if self
+            .segments
+            .back()
+            .is_none_or(|segment| segment.bytes >= segment_bytes)
+        {
+            self.roll(seq)?;
+        }
+
+        let (Some(segment), Some(file)) = (self.segments.back_mut(), self.active.as_mut()) else {
+            return Err(AppError::msg_str("channel log has no segment to write to"));
+        };
+        if let Err(err) = file.write_all(&record) {
+            // Leave no partial record behind, later ones would be unreadable.
+            let _ = file.set_len(segment.bytes);
+            return Err(err.into());
+        }
+        segment.bytes += record.len() as u64;
+        segment.last_seq = Some(seq);
+        segment.last_timestamp_ms = timestamp_ms;
+        match self.config.fsync.unwrap_or_default() {
+            FsyncPolicy::Always => file.sync_data()?,
+            FsyncPolicy::Periodic => self.unsynced = true,
+            FsyncPolicy::Never => {}
+        }
+        self.apply_retention()
+    }
+
+    fn first_seq(&mut self) -> Result<Option<Sequence>, AppError> {
+        self.apply_retention()?;
+        Ok(self
+            .segments
+            .iter()
+            .find(|segment| segment.last_seq.is_some())
+            .map(|segment| segment.first_seq))
+    }
+
+    fn last_seq(&self) -> Option<Sequence> {
+        self.segments
+            .iter()
+            .rev()
+            .find_map(|segment| segment.last_seq)
+    }
+
+    fn read_from(
+        &mut self,
+        from_seq: Sequence,
+        max: usize,
+    ) -> Result<Vec<Arc<SharedMessage>>, AppError> {
+        let mut messages = Vec::new();
+        let position = self.read_positions.remove(&from_seq);
+        for segment in &self.segments {
+            if segment.last_seq.is_none_or(|last_seq| last_seq < from_seq) {
+                continue;
+            }
+            // Continue where the previous batch stopped instead of scanning the segment.
+            let offset = match position {
+                Some((first_seq, offset)) if first_seq == segment.first_seq => offset,
+                _ => 0,
+            };
+            let mut reader = RecordReader::open_at(&segment.path, segment.bytes, offset)?;
+            loop {
+                let record_offset = reader.offset;
+                let Some(record) = reader.next_record() else {
+                    break;
+                };
+                if record.seq < from_seq {
+                    continue;
+                }
+                if messages.len() == max {
+                    if self.read_positions.len() >= MAX_READ_POSITIONS {
+                        self.read_positions.clear();
+                    }
+                    self.read_positions
+                        .insert(record.seq, (segment.first_seq, record_offset));
+                    return Ok(messages);
+                }
+                messages.push(Arc::new(SharedMessage::new(ClientCommand::ChannelMessage(
+                    self.channel_id.clone(),
+                    record.message,
+                    record.seq,
+                ))));
+            }
+        }
+        Ok(messages)
+    }
+
+    fn sync(&mut self) -> Result<(), AppError> {
+        if self.unsynced {
+            if let Some(ref file) = self.active {
+                file.sync_data()?;
+            }
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self) -> Result<(), AppError> {
+        self.active = None;
+        self.segments.clear();
+        self.read_positions.clear();
+        match fs::remove_dir_all(&self.dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tungstenite::Bytes;
+
+    fn storage(path: &Path, segment_bytes: u64, retention_bytes: Option<u64>) -> FileStorage {
+        FileStorage::new(StorageConfig {
+            path: path.to_path_buf(),
+            segment_bytes: Some(segment_bytes),
+            retention_bytes,
+            retention_seconds: None,
+            fsync: Some(FsyncPolicy::Always),
+            fsync_interval_ms: None,
+        })
+    }
+
+    fn durable() -> ChannelOptions {
+        ChannelOptions {
+            durable: true,
+            ..ChannelOptions::default()
+        }
+    }
+
+    fn append(store: &mut FileStore, seq: Sequence, message: ChannelMessage) {
+        let shared = SharedMessage::new(ClientCommand::ChannelMessage(
+            String::from("prices/eu"),
+            message,
+            seq,
+        ));
+        store.append(seq, &Arc::new(shared)).unwrap();
+    }
+
+    #[test]
+    fn test_recover_channels() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), 1024 * 1024, None);
+        let channel_id = String::from("prices/eu");
+
+        let mut store = storage.create(&channel_id, &durable()).unwrap();
+        append(&mut store, 1, ChannelMessage::Text(String::from("a")));
+        append(&mut store, 2, ChannelMessage::Json(json!({"price": 1.5})));
+        append(
+            &mut store,
+            3,
+            ChannelMessage::Binary(Bytes::from_static(&[0, 1])),
+        );
+        drop(store);
+
+        let mut channels = storage.recover().unwrap();
+        assert_eq!(channels.len(), 1);
+        let (recovered_id, options, mut store) = channels.remove(0);
+        assert_eq!(recovered_id, channel_id);
+        assert!(options.durable);
+        assert_eq!(store.first_seq().unwrap(), Some(1));
+        assert_eq!(store.last_seq(), Some(3));
+
+        let messages = store.read_from(2, 10).unwrap();
+        assert_eq!(messages.len(), 2);
+        match messages[0].command() {
+            ClientCommand::ChannelMessage(id, ChannelMessage::Json(value), 2) => {
+                assert_eq!(id, "prices/eu");
+                assert_eq!(value["price"], 1.5);
+            }
+            _ => panic!("Expected the JSON message"),
+        }
+
+        store.remove().unwrap();
+        assert!(storage.recover().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recover_skips_corrupt_and_owned_channels() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), 1024 * 1024, None);
+        let mut store = storage.create(&String::from("ticks"), &durable()).unwrap();
+        append(&mut store, 1, ChannelMessage::Text(String::from("tick")));
+        drop(store);
+
+        let corrupt = storage
+            .create(&String::from("corrupt"), &durable())
+            .unwrap();
+        fs::write(corrupt.dir.join(METADATA_FILE_NAME), b"{not json").unwrap();
+        let owned = ChannelOptions {
+            delete_on_disconnect: true,
+            ..durable()
+        };
+        let owned = storage.create(&String::from("owned"), &owned).unwrap();
+
+        let channels = storage.recover().unwrap();
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].0, "ticks");
+        // the corrupt channel is left for inspection, the owned one is gone
+        assert!(corrupt.dir.exists());
+        assert!(!owned.dir.exists());
+    }
+
+    #[test]
+    fn test_segments_and_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let tick = ChannelMessage::Text(String::from("tick"));
+        let record_len = encode_record(1, &tick).unwrap().0.len() as u64;
+        // every record fills a segment, and two of them are kept
+        let storage = storage(dir.path(), 1, Some(2 * record_len));
+        let mut store = storage.create(&String::from("ticks"), &durable()).unwrap();
+        for seq in 1..=5 {
+            append(&mut store, seq, tick.clone());
+        }
+
+        assert_eq!(store.segments.len(), 2);
+        assert_eq!(store.first_seq().unwrap(), Some(4));
+        assert_eq!(store.read_from(4, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_read_in_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let tick = ChannelMessage::Text(String::from("tick"));
+        let record_len = encode_record(1, &tick).unwrap().0.len() as u64;
+        // three records per segment
+        let storage = storage(dir.path(), 3 * record_len, None);
+        let mut store = storage.create(&String::from("ticks"), &durable()).unwrap();
+        for seq in 1..=8 {
+            append(&mut store, seq, tick.clone());
+        }
+
+        let sequences = |messages: Vec<Arc<SharedMessage>>| {
+            messages
+                .iter()
+                .filter_map(|message| message.sequence())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequences(store.read_from(2, 2).unwrap()), vec![2, 3]);
+        // the next batch resumes at the saved position, in the next segment
+        assert_eq!(store.read_positions.get(&4).map(|p| p.0), Some(4));
+        assert_eq!(sequences(store.read_from(4, 2).unwrap()), vec![4, 5]);
+        append(&mut store, 9, tick.clone());
+        assert_eq!(sequences(store.read_from(6, 10).unwrap()), vec![6, 7, 8, 9]);
+        assert!(store.read_positions.is_empty());
+    }
+
+    #[test]
+    fn test_truncate_incomplete_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path(), 1024 * 1024, None);
+        let mut store = storage.create(&String::from("ticks"), &durable()).unwrap();
+        append(
+            &mut store,
+            1,
+            ChannelMessage::Text(String::from("complete")),
+        );
+        let path = store.segments[0].path.clone();
+        drop(store);
+
+        // a crash in the middle of the second record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let (_, _, mut store) = storage.recover().unwrap().remove(0);
+        assert_eq!(store.last_seq(), Some(1));
+        append(&mut store, 2, ChannelMessage::Text(String::from("next")));
+        assert_eq!(store.read_from(1, 10).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_dir_name() {
+        assert_eq!(dir_name("prices.eu-1"), "prices.eu-1");
+        assert_eq!(dir_name("../a b"), "%2E.%2Fa%20b");
+        assert_eq!(dir_name(""), "%");
+    }
+}
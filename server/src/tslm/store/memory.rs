@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use common::error::AppError;
+use common::message::{ChannelOptions, Sequence};
+
+use crate::tslm::outgoing::SharedMessage;
+use crate::tslm::store::MessageStore;
+
+/// Ring buffer of the last `history_size` messages, or of the messages published in the
+/// last `history_seconds`.
+pub struct MemoryStore {
+    max_len: Option<usize>,
+    max_age: Option<Duration>,
+    entries: VecDeque<Entry>,
+}
+
+struct Entry {
+    seq: Sequence,
+    published_at: Instant,
+    message: Arc<SharedMessage>,
+}
+
+impl MemoryStore {
+    /// The store for the channel options, `None` when they keep no history.
+    pub fn new(options: &ChannelOptions) -> Option<Self> {
+        let max_len = options.history_size.filter(|len| *len > 0);
+        let max_age = options.history_seconds.map(Duration::from_secs);
+        if max_len.is_none() && max_age.is_none() {
+            return None;
+        }
+        Some(MemoryStore {
+            max_len,
+            max_age,
+            entries: VecDeque::new(),
+        })
+    }
+
+    fn evict(&mut self) {
+        if let Some(max_len) = self.max_len {
+            while self.entries.len() > max_len {
+                self.entries.pop_front();
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            let now = Instant::now();
+            while self
+                .entries
+                .front()
+                .is_some_and(|entry| now.duration_since(entry.published_at) > max_age)
+            {
+                self.entries.pop_front();
+            }
+        }
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn append(&mut self, seq: Sequence, message: &Arc<SharedMessage>) -> Result<(), AppError> {
+        self.entries.push_back(Entry {
+            seq,
+            published_at: Instant::now(),
+            message: Arc::clone(message),
+        });
+        self.evict();
+        Ok(())
+    }
+
+    fn first_seq(&mut self) -> Result<Option<Sequence>, AppError> {
+        self.evict();
+        Ok(self.entries.front().map(|entry| entry.seq))
+    }
+
+    fn last_seq(&self) -> Option<Sequence> {
+        self.entries.back().map(|entry| entry.seq)
+    }
+
+    fn read_from(
+        &mut self,
+        from_seq: Sequence,
+        max: usize,
+    ) -> Result<Vec<Arc<SharedMessage>>, AppError> {
+        // The sequence numbers are contiguous, the first one wanted is found by its offset.
+        let skip = self
+            .entries
+            .front()
+            .map_or(0, |first| from_seq.saturating_sub(first.seq) as usize);
+        Ok(self
+            .entries
+            .iter()
+            .skip(skip)
+            .take(max)
+            .map(|entry| Arc::clone(&entry.message))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::message::{ChannelMessage, ClientCommand};
+
+    fn message(seq: Sequence) -> Arc<SharedMessage> {
+        Arc::new(SharedMessage::new(ClientCommand::ChannelMessage(
+            String::from("test_channel"),
+            ChannelMessage::Text(seq.to_string()),
+            seq,
+        )))
+    }
+
+    #[test]
+    fn test_keep_last_messages() {
+        let mut store = MemoryStore::new(&ChannelOptions {
+            history_size: Some(3),
+            ..ChannelOptions::default()
+        })
+        .unwrap();
+        for seq in 1..=5 {
+            store.append(seq, &message(seq)).unwrap();
+        }
+        assert_eq!(store.first_seq().unwrap(), Some(3));
+        assert_eq!(store.last_seq(), Some(5));
+        assert_eq!(store.read_from(4, 10).unwrap().len(), 2);
+        assert_eq!(store.read_from(3, 2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_evict_expired_messages() {
+        let mut store = MemoryStore::new(&ChannelOptions {
+            history_seconds: Some(0),
+            ..ChannelOptions::default()
+        })
+        .unwrap();
+        store.append(1, &message(1)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(store.first_seq().unwrap(), None);
+    }
+
+    #[test]
+    fn test_without_history() {
+        assert!(MemoryStore::new(&ChannelOptions::default()).is_none());
+    }
+}
@@ -0,0 +1,45 @@
+//! Storage of the published messages a channel keeps for subscribers resuming with
+//! `from_seq`: in memory for `history_size` / `history_seconds`, on disk for `durable`.
+
+mod file;
+mod memory;
+
+pub use file::FileStorage;
+pub use memory::MemoryStore;
+
+use std::sync::Arc;
+
+use common::error::AppError;
+use common::message::Sequence;
+
+use crate::tslm::outgoing::SharedMessage;
+
+/// Messages kept by a channel, appended in sequence order.
+pub trait MessageStore: Send {
+    /// Keep the message published with the given sequence number.
+    fn append(&mut self, seq: Sequence, message: &Arc<SharedMessage>) -> Result<(), AppError>;
+
+    /// The sequence number of the oldest kept message, once the retention is applied.
+    fn first_seq(&mut self) -> Result<Option<Sequence>, AppError>;
+
+    /// The sequence number of the newest kept message.
+    fn last_seq(&self) -> Option<Sequence>;
+
+    /// Up to `max` of the kept messages from `from_seq` on, oldest first. Replays read
+    /// them in batches, so a long one neither holds the channel nor its memory at once.
+    fn read_from(
+        &mut self,
+        from_seq: Sequence,
+        max: usize,
+    ) -> Result<Vec<Arc<SharedMessage>>, AppError>;
+
+    /// Flush the appended messages to durable storage.
+    fn sync(&mut self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Delete the kept messages, the channel is gone.
+    fn remove(&mut self) -> Result<(), AppError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,472 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+use x509_parser::extensions::GeneralName;
+
+use common::error::AppError;
+
+use crate::settings::{ClientAuth, ListenerConfig};
+
+/// The TLS configuration of a listener, loaded from PEM files and loaded again when they
+/// change, so rotated certificates take effect without a restart. Established connections
+/// keep the configuration they were accepted with.
+pub struct ListenerTls {
+    files: TlsFiles,
+    reload_interval: Duration,
+    handshake_timeout: Duration,
+    loaded: RwLock<Loaded>,
+}
+
+/// The only protocol the WebSocket upgrade runs over.
+const HTTP_1_1: &str = "http/1.1";
+
+struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    alpn: Vec<Vec<u8>>,
+    /// CA bundle verifying client certificates
+    client_ca: Option<(PathBuf, ClientAuth)>,
+}
+
+struct Loaded {
+    config: Arc<ServerConfig>,
+    /// Modification times of the files when they were loaded
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl ListenerTls {
+    /// Fails with `InvalidConfig` when the certificate chain, its key or the CA bundle
+    /// verifying client certificates cannot be used, or when `alpn` offers anything but
+    /// `http/1.1`.
+    pub fn new(
+        cert: PathBuf,
+        key: PathBuf,
+        alpn: Vec<String>,
+        reload_interval: Duration,
+        client_ca: Option<(PathBuf, ClientAuth)>,
+    ) -> Result<Self, AppError> {
+        if let Some(protocol) = alpn.iter().find(|protocol| *protocol != HTTP_1_1) {
+            return Err(AppError::InvalidConfig(format!(
+                "tls_alpn '{}' is not supported, WebSocket connections upgrade from {}",
+                protocol, HTTP_1_1
+            )));
+        }
+        let files = TlsFiles {
+            cert,
+            key,
+            alpn: alpn.into_iter().map(String::into_bytes).collect(),
+            client_ca,
+        };
+        let loaded = files.load()?;
+        Ok(ListenerTls {
+            files,
+            reload_interval,
+            handshake_timeout: Duration::from_secs(10),
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// How long a client has to complete the TLS handshake.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// The listener's TLS when it has `tls_cert` and `tls_key`, which go together. Client
+    /// certificates are only verified by listeners terminating TLS.
+    pub fn from_config(config: &ListenerConfig) -> Result<Option<Self>, AppError> {
+        let client_ca = match (&config.tls_client_ca, &config.tls_client_auth) {
+            (Some(ca), client_auth) => Some((ca.clone(), client_auth.unwrap_or_default())),
+            (None, None) if config.client_certs.is_none() => None,
+            (None, _) => {
+                return Err(AppError::InvalidConfig(String::from(
+                    "tls_client_auth and client_certs require tls_client_ca",
+                )));
+            }
+        };
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => ListenerTls::new(
+                cert.clone(),
+                key.clone(),
+                config.get_tls_alpn(),
+                Duration::from_secs(config.get_tls_reload_interval_secs().max(1)),
+                client_ca,
+            )
+            .map(|tls| {
+                Some(tls.with_handshake_timeout(Duration::from_secs(
+                    config.get_tls_handshake_timeout_secs().max(1),
+                )))
+            }),
+            (None, None) if client_ca.is_none() => Ok(None),
+            (None, None) => Err(AppError::InvalidConfig(String::from(
+                "tls_client_ca requires tls_cert and tls_key",
+            ))),
+            _ => Err(AppError::InvalidConfig(String::from(
+                "tls_cert and tls_key must be set together",
+            ))),
+        }
+    }
+
+    /// An acceptor with the current configuration.
+    pub fn acceptor(&self) -> Result<TlsAcceptor, AppError> {
+        Ok(TlsAcceptor::from(Arc::clone(&self.loaded.read()?.config)))
+    }
+
+    /// Load the files again when one of them changed since they were loaded, returns
+    /// whether they were. The current configuration is kept when they cannot be used.
+    pub fn reload_if_changed(&self) -> Result<bool, AppError> {
+        if self.loaded.read()?.modified == self.files.modified() {
+            return Ok(false);
+        }
+        let loaded = self.files.load()?;
+        *self.loaded.write()? = loaded;
+        Ok(true)
+    }
+
+    /// Check the files for changes every `reload_interval`, for as long as the listener runs.
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.reload_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match self.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificate {}", self.files.cert.display()),
+                Ok(false) => {}
+                Err(err) => warn!("Keeping the current TLS certificate: {}", err),
+            }
+        }
+    }
+}
+
+impl TlsFiles {
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [&self.cert, &self.key]
+            .into_iter()
+            .chain(self.client_ca.as_ref().map(|(ca, _)| ca))
+            .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+            .collect()
+    }
+
+    fn load(&self) -> Result<Loaded, AppError> {
+        // Taken before reading, so a file written meanwhile is loaded again on the next check
+        let modified = self.modified();
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid_file(&self.cert, e))?;
+        if certs.is_empty() {
+            return Err(invalid_file(&self.cert, "no certificate"));
+        }
+        let key =
+            PrivateKeyDer::from_pem_file(&self.key).map_err(|e| invalid_file(&self.key, e))?;
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| AppError::InvalidConfig(e.to_string()))?;
+        let builder = match &self.client_ca {
+            Some((ca, client_auth)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(ca).map_err(|e| invalid_file(ca, e))? {
+                    roots
+                        .add(cert.map_err(|e| invalid_file(ca, e))?)
+                        .map_err(|e| invalid_file(ca, e))?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match client_auth {
+                    ClientAuth::Required => verifier,
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                };
+                builder
+                    .with_client_cert_verifier(verifier.build().map_err(|e| invalid_file(ca, e))?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid_file(&self.key, e))?;
+        config.alpn_protocols = self.alpn.clone();
+        Ok(Loaded {
+            config: Arc::new(config),
+            modified,
+        })
+    }
+}
+
+/// The names of a client certificate verified in the TLS handshake: its subject common
+/// names, then its DNS and URI subject alternative names.
+pub fn certificate_names(cert: &CertificateDer) -> Vec<String> {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(cert) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|common_name| common_name.as_str().ok())
+        .map(String::from)
+        .collect();
+    if let Ok(Some(alt_names)) = cert.subject_alternative_name() {
+        names.extend(
+            alt_names
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) | GeneralName::URI(name) => Some(name.to_string()),
+                    _ => None,
+                }),
+        );
+    }
+    names
+}
+
+fn invalid_file(path: &Path, err: impl std::fmt::Display) -> AppError {
+    AppError::InvalidConfig(format!("TLS file {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    /// Writes a new self-signed certificate for `localhost`, returns its DER.
+    fn write_cert(dir: &Path) -> CertificateDer<'static> {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        cert.cert.der().clone()
+    }
+
+    fn listener_tls(dir: &Path) -> Result<ListenerTls, AppError> {
+        ListenerTls::new(
+            dir.join("cert.pem"),
+            dir.join("key.pem"),
+            vec![String::from("http/1.1")],
+            Duration::from_secs(10),
+            None,
+        )
+    }
+
+    /// Runs a handshake offering `http/1.1`, returns the negotiated protocol.
+    async fn handshake(tls: &ListenerTls, root: CertificateDer<'static>) -> Option<Vec<u8>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let acceptor = tls.acceptor().unwrap();
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await
+            .unwrap();
+        let mut hello = [0; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        server.await.unwrap();
+        stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_alpn() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = write_cert(dir.path());
+        let tls = listener_tls(dir.path()).unwrap();
+        assert_eq!(handshake(&tls, cert).await, Some(b"http/1.1".to_vec()));
+    }
+
+    #[test]
+    fn test_reject_other_alpn() {
+        let dir = tempfile::tempdir().unwrap();
+        write_cert(dir.path());
+        let tls = ListenerTls::new(
+            dir.path().join("cert.pem"),
+            dir.path().join("key.pem"),
+            vec![String::from("h2"), String::from("http/1.1")],
+            Duration::from_secs(10),
+            None,
+        );
+        assert!(matches!(tls, Err(AppError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_reload_changed_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        write_cert(dir.path());
+        let tls = listener_tls(dir.path()).unwrap();
+        assert!(!tls.reload_if_changed().unwrap());
+
+        // modification times may not be finer than a second
+        std::thread::sleep(Duration::from_millis(1100));
+        let rotated = write_cert(dir.path());
+        assert!(tls.reload_if_changed().unwrap());
+        assert!(handshake(&tls, rotated.clone()).await.is_some());
+
+        // an unusable key keeps the current certificate
+        std::thread::sleep(Duration::from_millis(1100));
+        std::fs::write(dir.path().join("key.pem"), "not a key").unwrap();
+        assert!(matches!(
+            tls.reload_if_changed(),
+            Err(AppError::InvalidConfig(_))
+        ));
+        assert!(handshake(&tls, rotated).await.is_some());
+    }
+
+    /// Writes a CA to `ca.pem`, returns a client certificate it issued and its key.
+    fn write_client_ca(dir: &Path) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, SanType};
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![String::from("publisher.internal")]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "publisher-eu");
+        params
+            .subject_alt_names
+            .push(SanType::URI("spiffe://tslm/publisher".try_into().unwrap()));
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+        (cert.der().clone(), key)
+    }
+
+    /// Runs a handshake presenting the client certificate if any, returns the certificate
+    /// names seen by the server.
+    async fn mutual_handshake(
+        tls: &ListenerTls,
+        root: CertificateDer<'static>,
+        client_cert: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+    ) -> Result<Vec<String>, std::io::Error> {
+        let mut roots = RootCertStore::empty();
+        roots.add(root).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((cert, key)) => builder.with_client_auth_cert(vec![cert], key).unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let acceptor = tls.acceptor().unwrap();
+        let server = tokio::spawn(async move {
+            let stream = acceptor.accept(server).await?;
+            let names = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(certificate_names)
+                .unwrap_or_default();
+            Ok(names)
+        });
+        // With TLS 1.3 the server rejects the client certificate after the client is done,
+        // the connection is kept open until then.
+        let _client = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await;
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = write_cert(dir.path());
+        let client_cert = write_client_ca(dir.path());
+        let mutual_tls = |client_auth| {
+            ListenerTls::new(
+                dir.path().join("cert.pem"),
+                dir.path().join("key.pem"),
+                vec![],
+                Duration::from_secs(10),
+                Some((dir.path().join("ca.pem"), client_auth)),
+            )
+            .unwrap()
+        };
+
+        let tls = mutual_tls(ClientAuth::Required);
+        assert_eq!(
+            mutual_handshake(
+                &tls,
+                cert.clone(),
+                Some((client_cert.0.clone(), client_cert.1.clone_key()))
+            )
+            .await
+            .unwrap(),
+            vec![
+                "publisher-eu",
+                "publisher.internal",
+                "spiffe://tslm/publisher"
+            ]
+        );
+        assert!(mutual_handshake(&tls, cert.clone(), None).await.is_err());
+        // a certificate from another CA
+        let dir2 = tempfile::tempdir().unwrap();
+        let other_cert = write_client_ca(dir2.path());
+        assert!(
+            mutual_handshake(&tls, cert.clone(), Some(other_cert))
+                .await
+                .is_err()
+        );
+
+        let tls = mutual_tls(ClientAuth::Optional);
+        assert!(
+            mutual_handshake(&tls, cert.clone(), None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            mutual_handshake(&tls, cert, Some(client_cert))
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            listener_tls(dir.path()),
+            Err(AppError::InvalidConfig(_))
+        ));
+        write_cert(dir.path());
+        std::fs::write(dir.path().join("cert.pem"), "").unwrap();
+        assert!(matches!(
+            listener_tls(dir.path()),
+            Err(AppError::InvalidConfig(_))
+        ));
+    }
+}
@@ -1,38 +1,53 @@
 use std::cell::Cell;
-use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::num::NonZeroU32;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 
-use futures_util::future::select;
+use futures_util::future::{self, select};
 use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt, pin_mut};
 use governor::{Quota, RateLimiter};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
 use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
-use tokio_tungstenite::{WebSocketStream, accept_async_with_config};
+use tokio_tungstenite::{WebSocketStream, accept_hdr_async_with_config};
 use tracing::{debug, error, info, warn};
 use tungstenite::Message;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::protocol::CloseFrame;
+use tungstenite::protocol::frame::coding::CloseCode;
 
 use common::error::AppError;
-use common::message::{ClientCommand, TerminalStreamCommand};
+use common::message::{
+    CommandEnvelope, Protocol, SLOW_CONSUMER_CLOSE_CODE, TOKEN_EXPIRED_CLOSE_CODE,
+};
 
+use crate::tslm::auth::{Authenticator, Identity, offered_protocols};
 use crate::tslm::endpoint::Endpoint;
 use crate::tslm::hub::{EndpointFactorySettings, Hub};
+use crate::tslm::outgoing::{self, Outgoing};
+use crate::tslm::tls::{self, ListenerTls};
+
+/// Commands above 1MB are rejected, in addition to the websocket size limits.
+const MAX_COMMAND_SIZE: usize = 1024 * 1024;
 
 /// Configuration for WebSocket server
 #[derive(Clone)]
 pub struct WebSocketServerConfig {
-    #[allow(dead_code)] // Used for authentication, kept for future implementation
-    pub auth_tokens: Option<HashSet<String>>,
+    pub authenticator: Arc<Authenticator>,
     pub max_message_size: usize,
     pub max_frame_size: usize,
     pub max_connections: Option<usize>,
     pub rate_limit_per_second: Option<u32>,
+    /// Accepted sockets are wrapped in TLS before the websocket handshake when set
+    pub tls: Option<Arc<ListenerTls>>,
 }
 
 pub struct WebsocketServer {
@@ -82,7 +97,7 @@ impl WebsocketServer {
         runtime: Arc<Runtime>,
         addr: SocketAddr,
         hub: Arc<Hub>,
-        settings: EndpointFactorySettings,
+        settings: Arc<EndpointFactorySettings>,
         config: WebSocketServerConfig,
     ) -> Self {
         let handler_rt = Arc::clone(&runtime);
@@ -96,7 +111,7 @@ impl WebsocketServer {
         addr: SocketAddr,
         hub: Arc<Hub>,
         runtime: Arc<Runtime>,
-        settings: EndpointFactorySettings,
+        settings: Arc<EndpointFactorySettings>,
         config: WebSocketServerConfig,
     ) -> Result<(), AppError> {
         let try_socket = TcpListener::bind(&addr).await;
@@ -110,6 +125,10 @@ impl WebsocketServer {
                 .max_connections
                 .map_or("unlimited".to_string(), |m| m.to_string())
         );
+        let tls_watch = config
+            .tls
+            .as_ref()
+            .map(|tls| runtime.spawn(Arc::clone(tls).watch()));
 
         while let Ok((stream, client_addr)) = listener.accept().await {
             // Check connection limit
@@ -125,38 +144,95 @@ impl WebsocketServer {
             );
 
             // Spawn asap so this does not block accepting other incoming conns.
-            match hub.create_endpoint(&settings) {
-                Ok((endpoint, tx)) => {
-                    let cfg = config.clone();
-                    let counter = Arc::clone(&conn_counter);
+            let hub_ref = Arc::clone(&hub);
+            let endpoint_settings = Arc::clone(&settings);
+            let cfg = config.clone();
+            let counter = Arc::clone(&conn_counter);
 
-                    runtime.spawn(async move {
-                        WebsocketServer::connection_handler(stream, tx, endpoint, client_addr, cfg)
+            runtime.spawn(async move {
+                match cfg.tls.clone() {
+                    Some(tls) => {
+                        if let Some(tls_stream) = Self::accept_tls(&tls, stream, client_addr).await
+                        {
+                            let certificate_names = tls_stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(|certs| certs.first())
+                                .map(tls::certificate_names)
+                                .unwrap_or_default();
+                            WebsocketServer::connection_handler(
+                                tls_stream,
+                                certificate_names,
+                                hub_ref,
+                                endpoint_settings,
+                                client_addr,
+                                cfg,
+                            )
                             .await;
-                        counter.decrement();
-                        info!(
-                            "Connection from {} closed (total: {})",
+                        }
+                    }
+                    None => {
+                        WebsocketServer::connection_handler(
+                            stream,
+                            Vec::new(),
+                            hub_ref,
+                            endpoint_settings,
                             client_addr,
-                            counter.count()
-                        );
-                    });
+                            cfg,
+                        )
+                        .await;
+                    }
                 }
-                Err(err) => {
-                    error!("Error creating endpoint: {}", err);
-                    conn_counter.decrement();
+                counter.decrement();
+                info!(
+                    "Connection from {} closed (total: {})",
+                    client_addr,
+                    counter.count()
+                );
+            });
+        }
+        if let Some(tls_watch) = tls_watch {
+            tls_watch.abort();
+        }
+        Ok(())
+    }
+
+    /// The TLS handshake, `None` when it failed or did not complete in time.
+    async fn accept_tls(
+        tls: &ListenerTls,
+        stream: TcpStream,
+        client_addr: SocketAddr,
+    ) -> Option<TlsStream<TcpStream>> {
+        let accepted = match tls.acceptor() {
+            Ok(acceptor) => {
+                match tokio::time::timeout(tls.handshake_timeout(), acceptor.accept(stream)).await {
+                    Ok(accepted) => accepted.map_err(AppError::from),
+                    Err(_) => Err(AppError::msg_str("timed out")),
                 }
             }
+            Err(err) => Err(err),
+        };
+        match accepted {
+            Ok(tls_stream) => Some(tls_stream),
+            Err(err) => {
+                warn!("TLS handshake error from {}: {}", client_addr, err);
+                None
+            }
         }
-        Ok(())
     }
 
-    async fn connection_handler(
-        tcp_stream: TcpStream,
-        mut ts_receiver: UnboundedReceiver<ClientCommand>,
-        endpoint: Arc<Endpoint>,
+    /// `certificate_names` are the names of the client's verified TLS certificate, if any.
+    async fn connection_handler<S>(
+        tcp_stream: S,
+        certificate_names: Vec<String>,
+        hub: Arc<Hub>,
+        settings: Arc<EndpointFactorySettings>,
         client_addr: SocketAddr,
         config: WebSocketServerConfig,
-    ) {
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         // Create rate limiter if configured
         let rate_limiter = config.rate_limit_per_second.and_then(|rate| {
             NonZeroU32::new(rate).map(|r| Arc::new(RateLimiter::direct(Quota::per_second(r))))
@@ -167,7 +243,39 @@ impl WebsocketServer {
         ws_config.max_message_size = Some(config.max_message_size);
         ws_config.max_frame_size = Some(config.max_frame_size);
 
-        let ws_stream = accept_async_with_config(tcp_stream, Some(ws_config))
+        // Resolved by the handshake callback, the endpoint is only created once upgraded.
+        let mut identity: Option<Identity> = None;
+        let mut protocol = Protocol::default();
+        let authenticator = &config.authenticator;
+        // The error response type is dictated by tungstenite's handshake callback.
+        #[allow(clippy::result_large_err)]
+        let handshake = |request: &Request, mut response: Response| match authenticator
+            .authenticate_peer(request, &certificate_names)
+        {
+            Ok(authenticated) => {
+                protocol =
+                    Protocol::negotiate(offered_protocols(request).iter().map(|p| p.as_str()));
+                // Only one subprotocol can be echoed, the encoding takes precedence
+                // over the token.
+                let echoed = protocol
+                    .subprotocol()
+                    .map(String::from)
+                    .or(authenticated.protocol);
+                if let Some(echoed) = echoed
+                    && let Ok(value) = HeaderValue::from_str(&echoed)
+                {
+                    response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, value);
+                }
+                identity = Some(authenticated.identity);
+                Ok(response)
+            }
+            Err(err) => {
+                warn!("Rejected handshake from {}: {}", client_addr, err);
+                Err(Self::unauthorized())
+            }
+        };
+
+        let ws_stream = accept_hdr_async_with_config(tcp_stream, handshake, Some(ws_config))
             .await
             .map_err(AppError::from);
 
@@ -176,6 +284,15 @@ impl WebsocketServer {
                 warn!("WebSocket handshake error from {}: {}", client_addr, err);
             }
             Ok(tcp_stream) => {
+                let identity = identity.unwrap_or_default();
+                let expires_at = identity.expires_at;
+                let (endpoint, mut ts_receiver) = match hub.create_endpoint(&settings, identity) {
+                    Ok(created) => created,
+                    Err(err) => {
+                        error!("Error creating endpoint: {}", err);
+                        return;
+                    }
+                };
                 info!("Client connected from: {}", client_addr);
                 let (mut tx, mut rx) = tcp_stream.split();
                 let in_ref = Arc::clone(&endpoint);
@@ -188,17 +305,35 @@ impl WebsocketServer {
                             && limiter.check().is_err()
                         {
                             warn!("Rate limit exceeded for connection {}", client_addr);
-                            let _ = in_ref
-                                .send(ClientCommand::Error("Rate limit exceeded".to_string()));
+                            let err = AppError::RateLimitExceeded(client_addr.to_string());
+                            let id = protocol.peek_command_id(&msg.clone().into_data());
+                            let _ = in_ref.send_error(id, &err);
                             continue;
                         }
-                        Self::handle_incoming_message(&in_ref, msg);
+                        Self::handle_incoming_message(&in_ref, protocol, msg).await;
                     }
                 };
 
                 let outgoing = async move {
-                    while let Some(msg) = ts_receiver.recv().await {
-                        match Self::handle_outgoing_message(&mut tx, msg).await {
+                    let expiry = Self::expiry(expires_at);
+                    pin_mut!(expiry);
+                    loop {
+                        let msg = tokio::select! {
+                            msg = ts_receiver.recv() => msg,
+                            _ = &mut expiry => {
+                                info!("Credentials of connection {} expired", client_addr);
+                                let _ = tx.send(Self::token_expired()).await;
+                                break;
+                            }
+                        };
+                        let Some(msg) = msg else {
+                            if ts_receiver.overflowed() {
+                                warn!("Disconnecting slow consumer {}", client_addr);
+                                let _ = tx.send(Self::slow_consumer()).await;
+                            }
+                            break;
+                        };
+                        match Self::handle_outgoing_message(&mut tx, protocol, msg).await {
                             Ok(_) => {
                                 // nothing for now.
                             }
@@ -213,56 +348,114 @@ impl WebsocketServer {
                 // either a msg incoming from tcp/websocket or from client channel going to the socket
                 pin_mut!(incoming, outgoing);
                 select(incoming, outgoing).await;
-                // either sending or receiving stopped so unregister the endpoint
-                let _ = endpoint.unregister();
+                // either sending or receiving stopped so unregister the endpoint, deleting
+                // its channels may remove their durable logs
+                let unregistering = Arc::clone(&endpoint);
+                let _ = tokio::task::spawn_blocking(move || unregistering.unregister()).await;
+                let dropped = endpoint.dropped_messages();
+                if dropped > 0 {
+                    warn!(
+                        "Dropped {} messages for slow connection {}",
+                        dropped, client_addr
+                    );
+                }
             }
         };
     }
 
-    async fn handle_outgoing_message(
-        tx: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
-        cmd: ClientCommand,
-    ) -> Result<(), AppError> {
-        let json_string_command = serde_json::to_string(&cmd).map_err(AppError::from)?;
-        tx.send(Message::Text(json_string_command.into()))
-            .await
-            .map_err(AppError::from)
+    fn unauthorized() -> ErrorResponse {
+        let mut response = ErrorResponse::new(Some(String::from("Unauthorized")));
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        response
+    }
+
+    /// Resolves when the credentials expire, never for credentials without expiration.
+    async fn expiry(expires_at: Option<SystemTime>) {
+        match expires_at {
+            Some(at) => {
+                let remaining = at.duration_since(SystemTime::now()).unwrap_or_default();
+                tokio::time::sleep(remaining).await;
+            }
+            None => future::pending().await,
+        }
+    }
+
+    fn token_expired() -> Message {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::from(TOKEN_EXPIRED_CLOSE_CODE),
+            reason: "Token expired".into(),
+        }))
     }
 
-    fn handle_incoming_message(endpoint: &Endpoint, msg: Message) {
+    fn slow_consumer() -> Message {
+        Message::Close(Some(CloseFrame {
+            code: CloseCode::from(SLOW_CONSUMER_CLOSE_CODE),
+            reason: "Slow consumer".into(),
+        }))
+    }
+
+    async fn handle_outgoing_message<S>(
+        tx: &mut SplitSink<WebSocketStream<S>, Message>,
+        protocol: Protocol,
+        msg: Outgoing,
+    ) -> Result<(), AppError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let message = match msg {
+            Outgoing::Command(cmd) => outgoing::encode(protocol, cmd)?,
+            Outgoing::Shared(shared) => shared.encoded(protocol)?,
+        };
+        tx.send(message).await.map_err(AppError::from)
+    }
+
+    /// Decode a command frame, replying with the error when it is not a valid command.
+    fn decode_command(
+        endpoint: &Endpoint,
+        protocol: Protocol,
+        data: &[u8],
+    ) -> Option<CommandEnvelope> {
+        // Validate message size (additional check beyond WebSocket config)
+        if data.len() > MAX_COMMAND_SIZE {
+            warn!("Message too large: {} bytes", data.len());
+            let err = AppError::MessageTooLarge {
+                size: data.len(),
+                max: MAX_COMMAND_SIZE,
+            };
+            let _ = endpoint.send_error(None, &err);
+            return None;
+        }
+
+        match protocol.decode_command(data) {
+            Ok(envelope) => Some(envelope),
+            Err(err) => {
+                debug!("Invalid ts message: {}", err);
+                // Still correlate the error when the id itself was readable.
+                let _ = endpoint.send_error(protocol.peek_command_id(data), &err);
+                None
+            }
+        }
+    }
+
+    async fn handle_incoming_message(endpoint: &Arc<Endpoint>, protocol: Protocol, msg: Message) {
         let ts_msg = match msg {
             Message::Ping(_) | Message::Pong(_) => {
                 // Tungstenite takes care of pings, we just get notified, nothing to do.
                 None
             }
-            Message::Text(txt) => {
-                // Validate message size (additional check beyond WebSocket config)
-                if txt.len() > 1024 * 1024 {
-                    // 1MB text message limit
-                    warn!("Message too large: {} bytes", txt.len());
-                    let _ = endpoint.send(ClientCommand::Error(String::from("Message too large")));
-                    return;
-                }
-
-                // parse into ts command
-                match serde_json::from_str::<TerminalStreamCommand>(txt.as_str()) {
-                    Ok(ts_cmd) => Some(ts_cmd),
-                    Err(err) => {
-                        debug!("Invalid ts message: {}", err);
-                        let _ = endpoint.send(ClientCommand::Error(format!(
-                            "Invalid message format: {}",
-                            err
-                        )));
-                        None
-                    }
-                }
+            Message::Text(txt) if !protocol.is_binary() => {
+                Self::decode_command(endpoint, protocol, txt.as_bytes())
             }
-            Message::Binary(_) => {
-                // We have nothing to do with binary msgs for now.
-                debug!("Binary messages not supported");
-                let _ = endpoint.send(ClientCommand::Error(String::from(
-                    "Binary messages not supported",
-                )));
+            Message::Binary(data) if protocol.is_binary() => {
+                Self::decode_command(endpoint, protocol, &data)
+            }
+            Message::Text(_) | Message::Binary(_) => {
+                debug!("Frame type does not match the {:?} protocol", protocol);
+                let err = AppError::InvalidMessage(format!(
+                    "Frame type not supported by the {:?} protocol",
+                    protocol
+                ));
+                let _ = endpoint.send_error(None, &err);
                 None
             }
             Message::Frame(_) => {
@@ -281,7 +474,17 @@ impl WebsocketServer {
 
         match ts_msg {
             Some(msg) => {
-                match endpoint.on_command(msg) {
+                // Durable channels append, fsync and replay from disk, keep that IO off the
+                // async workers.
+                let result = if endpoint.uses_storage(&msg) {
+                    let endpoint = Arc::clone(endpoint);
+                    tokio::task::spawn_blocking(move || endpoint.on_command(msg))
+                        .await
+                        .unwrap_or_else(|err| Err(AppError::msg(err.to_string())))
+                } else {
+                    endpoint.on_command(msg)
+                };
+                match result {
                     Ok(_) => {
                         // send ack to the client?
                     }